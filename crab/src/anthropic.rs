@@ -0,0 +1,454 @@
+use crate::llm::{Message, ToolDefinition};
+use crate::sampling::Sampling;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Sent as the first user turn when the history would otherwise open with the assistant.
+const CONVERSATION_START: &str = "(conversation start)";
+
+#[derive(Debug, Serialize)]
+pub struct AnthropicRequest {
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<AnthropicContent>,
+    pub messages: Vec<AnthropicMessage>,
+    pub max_tokens: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AnthropicMessage {
+    pub role: &'static str,
+    pub content: AnthropicContent,
+}
+
+/// Plain string content is kept as-is; it only becomes a block list when a turn
+/// carries tool blocks or has to be merged with a neighbour.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum AnthropicContent {
+    Text(String),
+    Blocks(Vec<Value>),
+}
+
+impl AnthropicContent {
+    fn into_blocks(self) -> Vec<Value> {
+        match self {
+            AnthropicContent::Text(text) => vec![json!({ "type": "text", "text": text })],
+            AnthropicContent::Blocks(blocks) => blocks,
+        }
+    }
+}
+
+/// Builds a Messages API request from the crate's chat-style history. System messages
+/// are lifted into the top-level `system` field, consecutive turns from the same role
+/// are merged, and the conversation always opens with a user turn. The Messages API
+/// has no seed or penalties, so those sampling settings are not sent. Extended
+/// thinking counts toward `max_tokens`, so its budget is added on top, and it does
+/// not allow a custom temperature or top_p.
+///
+/// With `prompt_cache`, the system prompt is sent as one block per system message and
+/// cache breakpoints are set after the first (the static prompt, shared by every run)
+/// and after the last (which also covers the tools and stays the same for the rest of
+/// the run).
+pub fn build_request(
+    model: &str,
+    messages: &[Message],
+    tools: &[ToolDefinition],
+    max_tokens: u32,
+    stream: bool,
+    sampling: &Sampling,
+    prompt_cache: bool,
+) -> AnthropicRequest {
+    let system: Vec<&str> = messages
+        .iter()
+        .filter(|m| m.role == "system" && !m.content.trim().is_empty())
+        .map(|m| m.content.as_str())
+        .collect();
+
+    let mut turns: Vec<AnthropicMessage> = Vec::new();
+    for message in messages.iter().filter(|m| m.role != "system") {
+        let Some(turn) = convert_message(message) else {
+            continue;
+        };
+
+        match turns.last_mut() {
+            Some(last) if last.role == turn.role => {
+                let mut blocks =
+                    std::mem::replace(&mut last.content, AnthropicContent::Text(String::new()))
+                        .into_blocks();
+                blocks.extend(turn.content.into_blocks());
+                last.content = AnthropicContent::Blocks(blocks);
+            }
+            _ => turns.push(turn),
+        }
+    }
+
+    if turns.first().map(|t| t.role) != Some("user") {
+        turns.insert(
+            0,
+            AnthropicMessage {
+                role: "user",
+                content: AnthropicContent::Text(CONVERSATION_START.to_string()),
+            },
+        );
+    }
+
+    let budget = sampling.thinking_budget();
+
+    AnthropicRequest {
+        model: model.to_string(),
+        system: match system.len() {
+            0 => None,
+            _ if prompt_cache => Some(cached_system_blocks(&system)),
+            _ => Some(AnthropicContent::Text(system.join("\n\n"))),
+        },
+        messages: turns,
+        max_tokens: max_tokens + budget.unwrap_or(0),
+        tools: tools
+            .iter()
+            .map(|t| {
+                json!({
+                    "name": t.name,
+                    "description": t.description,
+                    "input_schema": t.parameters,
+                })
+            })
+            .collect(),
+        stream,
+        temperature: sampling.temperature.filter(|_| budget.is_none()),
+        top_p: sampling.top_p.filter(|_| budget.is_none()),
+        stop_sequences: sampling.stop.clone(),
+        thinking: budget.map(|budget| json!({ "type": "enabled", "budget_tokens": budget })),
+    }
+}
+
+fn cached_system_blocks(system: &[&str]) -> AnthropicContent {
+    let last = system.len() - 1;
+    AnthropicContent::Blocks(
+        system
+            .iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let mut block = json!({ "type": "text", "text": text });
+                if i == 0 || i == last {
+                    block["cache_control"] = json!({ "type": "ephemeral" });
+                }
+                block
+            })
+            .collect(),
+    )
+}
+
+fn convert_message(message: &Message) -> Option<AnthropicMessage> {
+    if message.role == "tool" {
+        return Some(AnthropicMessage {
+            role: "user",
+            content: AnthropicContent::Blocks(vec![json!({
+                "type": "tool_result",
+                "tool_use_id": message.tool_call_id.clone().unwrap_or_default(),
+                "content": message.content,
+            })]),
+        });
+    }
+
+    let role = if message.role == "assistant" {
+        "assistant"
+    } else {
+        "user"
+    };
+
+    if message.tool_calls.is_empty() && message.images.is_empty() {
+        // The API rejects empty text content, so blank turns are dropped entirely.
+        if message.content.trim().is_empty() {
+            return None;
+        }
+        return Some(AnthropicMessage {
+            role,
+            content: AnthropicContent::Text(message.content.clone()),
+        });
+    }
+
+    // Thinking has to lead the turn that made the tool calls. Images go next;
+    // Anthropic reads them best ahead of the question about them.
+    let mut blocks: Vec<Value> = message.thinking.clone();
+    blocks.extend(message.images.iter().map(|image| {
+        json!({
+            "type": "image",
+            "source": { "type": "base64", "media_type": image.media_type, "data": image.data },
+        })
+    }));
+    if !message.content.trim().is_empty() {
+        blocks.push(json!({ "type": "text", "text": message.content }));
+    }
+    for call in &message.tool_calls {
+        blocks.push(json!({
+            "type": "tool_use",
+            "id": call.id,
+            "name": call.name,
+            "input": call.arguments,
+        }));
+    }
+
+    Some(AnthropicMessage {
+        role,
+        content: AnthropicContent::Blocks(blocks),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{ImagePart, ToolCall};
+
+    fn body(messages: &[Message]) -> Value {
+        serde_json::to_value(build_request(
+            "claude-test",
+            messages,
+            &[],
+            512,
+            false,
+            &Sampling::default(),
+            false,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn lifts_all_system_messages_into_top_level_field() {
+        let messages = vec![
+            Message::new("system", "You are Crab."),
+            Message::new("system", "Relevant past memories:\n- likes tea"),
+            Message::new("user", "hello"),
+        ];
+
+        assert_eq!(
+            body(&messages),
+            json!({
+                "model": "claude-test",
+                "system": "You are Crab.\n\nRelevant past memories:\n- likes tea",
+                "messages": [{ "role": "user", "content": "hello" }],
+                "max_tokens": 512,
+            })
+        );
+    }
+
+    #[test]
+    fn marks_the_static_prompt_and_the_whole_system_prompt_as_cacheable() {
+        let messages = vec![
+            Message::new("system", "You are Crab."),
+            Message::new("system", "Relevant past memories:\n- likes tea"),
+            Message::new("system", "Active meeting context:\nstandup"),
+            Message::new("user", "hello"),
+        ];
+        let request = build_request(
+            "claude-test",
+            &messages,
+            &[],
+            512,
+            false,
+            &Sampling::default(),
+            true,
+        );
+
+        let cached = json!({ "type": "ephemeral" });
+        assert_eq!(
+            serde_json::to_value(request).unwrap()["system"],
+            json!([
+                { "type": "text", "text": "You are Crab.", "cache_control": cached },
+                { "type": "text", "text": "Relevant past memories:\n- likes tea" },
+                { "type": "text", "text": "Active meeting context:\nstandup", "cache_control": cached },
+            ])
+        );
+    }
+
+    #[test]
+    fn merges_consecutive_user_turns_after_command_output() {
+        let messages = vec![
+            Message::new("system", "sys"),
+            Message::new("user", "list files"),
+            Message::new("assistant", "{\"terminal\":\"ls\"}"),
+            Message::new("user", "COMMAND_OUTPUT:\na.txt"),
+            Message::new("user", "thanks"),
+        ];
+
+        assert_eq!(
+            body(&messages)["messages"],
+            json!([
+                { "role": "user", "content": "list files" },
+                { "role": "assistant", "content": "{\"terminal\":\"ls\"}" },
+                {
+                    "role": "user",
+                    "content": [
+                        { "type": "text", "text": "COMMAND_OUTPUT:\na.txt" },
+                        { "type": "text", "text": "thanks" },
+                    ],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn starts_with_user_turn_when_history_opens_with_assistant() {
+        let messages = vec![
+            Message::new("system", "sys"),
+            Message::new("assistant", "Welcome back!"),
+            Message::new("user", "hi"),
+        ];
+
+        assert_eq!(
+            body(&messages)["messages"],
+            json!([
+                { "role": "user", "content": "(conversation start)" },
+                { "role": "assistant", "content": "Welcome back!" },
+                { "role": "user", "content": "hi" },
+            ])
+        );
+    }
+
+    #[test]
+    fn maps_tool_calls_and_merges_results_with_following_user_text() {
+        let call = ToolCall {
+            id: "toolu_1".to_string(),
+            name: "run_terminal".to_string(),
+            arguments: json!({ "command": "ls" }),
+        };
+        let messages = vec![
+            Message::new("user", "list files"),
+            Message {
+                role: "assistant".to_string(),
+                tool_calls: vec![call.clone()],
+                ..Default::default()
+            },
+            Message::tool_result(&call, "COMMAND_OUTPUT:\na.txt"),
+            Message::new("user", "ERROR: Command denied by user"),
+        ];
+
+        assert_eq!(
+            body(&messages)["messages"],
+            json!([
+                { "role": "user", "content": "list files" },
+                {
+                    "role": "assistant",
+                    "content": [{
+                        "type": "tool_use",
+                        "id": "toolu_1",
+                        "name": "run_terminal",
+                        "input": { "command": "ls" },
+                    }],
+                },
+                {
+                    "role": "user",
+                    "content": [
+                        {
+                            "type": "tool_result",
+                            "tool_use_id": "toolu_1",
+                            "content": "COMMAND_OUTPUT:\na.txt",
+                        },
+                        { "type": "text", "text": "ERROR: Command denied by user" },
+                    ],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn sends_supported_sampling_settings_only() {
+        let sampling = Sampling {
+            temperature: Some(0.0),
+            seed: Some(42),
+            stop: vec!["</answer>".to_string()],
+            presence_penalty: Some(0.3),
+            ..Sampling::default()
+        };
+        let request = build_request(
+            "claude-test",
+            &[Message::new("user", "hi")],
+            &[],
+            512,
+            false,
+            &sampling,
+            false,
+        );
+        let body = serde_json::to_value(request).unwrap();
+
+        assert_eq!(body["temperature"], 0.0);
+        assert_eq!(body["stop_sequences"], json!(["</answer>"]));
+        assert!(body.get("seed").is_none() && body.get("presence_penalty").is_none());
+    }
+
+    #[test]
+    fn enables_thinking_and_replays_it_ahead_of_the_tool_calls() {
+        let call = ToolCall {
+            id: "toolu_1".to_string(),
+            name: "run_terminal".to_string(),
+            arguments: json!({ "command": "ls" }),
+        };
+        let thinking = json!({ "type": "thinking", "thinking": "List them.", "signature": "sig" });
+        let messages = vec![
+            Message::new("user", "list files"),
+            Message {
+                role: "assistant".to_string(),
+                tool_calls: vec![call.clone()],
+                thinking: vec![thinking.clone()],
+                ..Default::default()
+            },
+            Message::tool_result(&call, "a.txt"),
+        ];
+        let sampling = Sampling {
+            temperature: Some(0.2),
+            reasoning_effort: Some("medium".to_string()),
+            ..Sampling::default()
+        };
+        let body = serde_json::to_value(build_request(
+            "claude-test",
+            &messages,
+            &[],
+            512,
+            false,
+            &sampling,
+            false,
+        ))
+        .unwrap();
+
+        assert_eq!(
+            body["thinking"],
+            json!({ "type": "enabled", "budget_tokens": 4096 })
+        );
+        assert_eq!(body["max_tokens"], 512 + 4096);
+        assert!(body.get("temperature").is_none());
+        assert_eq!(body["messages"][1]["content"][0], thinking);
+        assert_eq!(body["messages"][1]["content"][1]["type"], "tool_use");
+    }
+
+    #[test]
+    fn sends_images_as_base64_blocks_before_the_text() {
+        let image = ImagePart {
+            media_type: "image/png".to_string(),
+            data: "iVBORw0KGgo=".to_string(),
+        };
+        let messages = vec![Message::with_images("user", "what is this?", vec![image])];
+
+        assert_eq!(
+            body(&messages)["messages"][0]["content"],
+            json!([
+                {
+                    "type": "image",
+                    "source": { "type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo=" },
+                },
+                { "type": "text", "text": "what is this?" },
+            ])
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Cooperative cancellation for a run. The agent loop races every completion, approval
+//! wait and command against a `CancelToken`, so a stop request ends the run between
+//! steps with a proper reply instead of killing it mid-request.
+
+use crate::error::CrabError;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
+
+/// Created by the orchestrator's `/stop` command, like the HITL approval files.
+const DEFAULT_STOP_FILE: &str = "/tmp/hermit_stop.lock";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Resolves once the run is asked to stop. Clones share the same state.
+#[derive(Clone)]
+pub struct CancelToken {
+    reason: watch::Receiver<Option<String>>,
+}
+
+impl CancelToken {
+    fn new() -> (watch::Sender<Option<String>>, Self) {
+        let (sender, reason) = watch::channel(None);
+        (sender, Self { reason })
+    }
+
+    /// Why the run was stopped, once it has been.
+    pub async fn cancelled(&self) -> String {
+        let mut receiver = self.reason.clone();
+        let reason = match receiver.wait_for(Option::is_some).await {
+            Ok(reason) => reason.clone(),
+            Err(_) => None,
+        };
+        match reason {
+            Some(reason) => reason,
+            // Nothing is watching any more, so the run can no longer be stopped.
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Runs `work` to completion unless the run is stopped first, in which case
+    /// `work` is dropped and `CrabError::Cancelled` returned.
+    pub async fn guard<T>(&self, work: impl Future<Output = T>) -> Result<T, CrabError> {
+        tokio::select! {
+            biased;
+            reason = self.cancelled() => Err(CrabError::Cancelled(reason)),
+            value = work => Ok(value),
+        }
+    }
+}
+
+/// The stop file, `STOP_FILE` or `/tmp/hermit_stop.lock`.
+pub fn stop_file() -> PathBuf {
+    PathBuf::from(
+        std::env::var("STOP_FILE")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_STOP_FILE.to_string()),
+    )
+}
+
+/// Starts watching for SIGTERM (container teardown), SIGINT and the operator's
+/// `stop_file`. A stop file left over from an earlier run is removed first, so it
+/// cannot end this one as soon as it starts.
+pub fn listen(stop_file: PathBuf) -> CancelToken {
+    let (sender, token) = CancelToken::new();
+    let _ = std::fs::remove_file(&stop_file);
+
+    tokio::spawn(async move {
+        let mut terminate = signal(SignalKind::terminate()).ok();
+        let mut interrupt = signal(SignalKind::interrupt()).ok();
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+
+        let reason = loop {
+            tokio::select! {
+                Some(()) = received(&mut terminate) => break "received SIGTERM",
+                Some(()) = received(&mut interrupt) => break "received SIGINT",
+                _ = poll.tick() => {
+                    if std::fs::remove_file(&stop_file).is_ok() {
+                        break "stopped by the operator";
+                    }
+                }
+            }
+        };
+        let _ = sender.send(Some(reason.to_string()));
+        // Keep the channel open so late callers still see the reason.
+        std::future::pending::<()>().await;
+    });
+
+    token
+}
+
+async fn received(signal: &mut Option<Signal>) -> Option<()> {
+    match signal {
+        Some(signal) => signal.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stop_file_cancels_guarded_work_but_a_stale_one_does_not() {
+        let stop_file = std::env::temp_dir().join(format!("crab-stop-{}", std::process::id()));
+        std::fs::write(&stop_file, "").unwrap();
+
+        let token = listen(stop_file.clone());
+        assert!(!stop_file.exists());
+        assert_eq!(token.guard(async { 7 }).await, Ok(7));
+
+        let slow = tokio::time::sleep(Duration::from_secs(30));
+        let stopper = async {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            std::fs::write(&stop_file, "").unwrap();
+        };
+        let (result, ()) = tokio::join!(token.guard(slow), stopper);
+
+        assert_eq!(
+            result,
+            Err(CrabError::Cancelled("stopped by the operator".to_string()))
+        );
+        assert!(!stop_file.exists());
+        assert!(token.guard(async {}).await.is_err());
+    }
+}
@@ -0,0 +1,400 @@
+//! Record-and-replay cassettes for LLM traffic.
+//!
+//! With `LLM_CASSETTE_MODE=record`, every completion request and its outcome is
+//! appended to the cassette (`LLM_CASSETTE`, one JSON object per line) with API keys
+//! and tokens redacted. With `LLM_CASSETTE_MODE=replay`, the recorded outcomes are
+//! served back in order without touching the network, and `main` runs the agent's
+//! commands in a scratch directory instead of the workspace.
+
+use crate::error::CrabError;
+use crate::llm::{Completion, Message, ToolCall, ToolDefinition, Usage};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_DIR: &str = "/app/workspace/data/cassettes";
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CassetteMode {
+    Record,
+    Replay,
+}
+
+/// One request/response pair as written to the cassette.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Interaction {
+    request: RecordedRequest,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response: Option<RecordedResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RecordedError>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecordedRequest {
+    messages: Vec<Message>,
+    tools: Vec<String>,
+    max_tokens: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecordedResponse {
+    provider: String,
+    model: String,
+    content: String,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+    #[serde(default)]
+    cached_tokens: u32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    reasoning: String,
+    #[serde(default)]
+    reasoning_tokens: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedError {
+    kind: String,
+    message: String,
+}
+
+impl RecordedError {
+    fn of(error: &CrabError) -> Self {
+        Self {
+            kind: error.kind().to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    /// Rebuilds errors whose message is shown as is; the rest come back as a
+    /// provider error carrying the recorded kind as its code.
+    fn to_error(&self) -> CrabError {
+        let message = self.message.clone();
+        match self.kind.as_str() {
+            "timeout" => CrabError::Timeout(message),
+            "network" => CrabError::Network(message),
+            "invalid_response" => CrabError::InvalidResponse(message),
+            "config" => CrabError::Config(message),
+            _ => CrabError::Http {
+                status: None,
+                code: Some(self.kind.clone()),
+                message,
+            },
+        }
+    }
+}
+
+/// Replaces API keys and tokens with `[REDACTED]`: the values of every key and token
+/// variable in the environment, plus anything shaped like a well-known key format.
+struct Redactor {
+    secrets: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    fn from_env() -> Self {
+        let mut secrets: Vec<String> = env::vars()
+            .filter(|(name, _)| {
+                name.ends_with("_API_KEY") || name.ends_with("_TOKEN") || name == "LLM_HEADERS"
+            })
+            .flat_map(|(name, value)| {
+                if name == "LLM_HEADERS" {
+                    crate::llm::parse_extra_headers(&value)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(_, v)| v)
+                        .collect()
+                } else {
+                    vec![value]
+                }
+            })
+            .filter(|value| value.len() >= 8)
+            .collect();
+        // Longest first, so a key that contains another is replaced whole.
+        secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+
+        let patterns = [
+            r"sk-[A-Za-z0-9_\-]{16,}",
+            r"AIza[0-9A-Za-z_\-]{30,}",
+            r"gsk_[A-Za-z0-9]{20,}",
+            r"xai-[A-Za-z0-9]{20,}",
+            r"(?i)bearer\s+[A-Za-z0-9._\-]{16,}",
+        ]
+        .iter()
+        .map(|p| Regex::new(p).expect("valid redaction pattern"))
+        .collect();
+
+        Self { secrets, patterns }
+    }
+
+    fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for secret in &self.secrets {
+            text = text.replace(secret.as_str(), REDACTED);
+        }
+        for pattern in &self.patterns {
+            text = pattern.replace_all(&text, REDACTED).into_owned();
+        }
+        text
+    }
+}
+
+#[derive(Debug, Default)]
+struct ReplayState {
+    interactions: Vec<Interaction>,
+    position: usize,
+}
+
+pub struct Cassette {
+    mode: CassetteMode,
+    path: PathBuf,
+    redactor: Redactor,
+    replay: Mutex<ReplayState>,
+}
+
+impl Cassette {
+    /// `None` unless `LLM_CASSETTE_MODE` is `record` or `replay`. Recording without
+    /// `LLM_CASSETTE` writes a new timestamped file under the workspace data folder.
+    pub fn from_env() -> Result<Option<Self>, CrabError> {
+        let mode = match env::var("LLM_CASSETTE_MODE").unwrap_or_default().as_str() {
+            "record" => CassetteMode::Record,
+            "replay" => CassetteMode::Replay,
+            "" | "off" => return Ok(None),
+            other => {
+                return Err(CrabError::Config(format!(
+                    "Unknown LLM_CASSETTE_MODE '{}': use record or replay",
+                    other
+                )))
+            }
+        };
+
+        let path = match env::var("LLM_CASSETTE").ok().filter(|p| !p.is_empty()) {
+            Some(path) => PathBuf::from(path),
+            None if mode == CassetteMode::Record => {
+                let stamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                Path::new(DEFAULT_DIR).join(format!("cassette-{}.jsonl", stamp))
+            }
+            None => {
+                return Err(CrabError::Config(
+                    "LLM_CASSETTE must name the cassette to replay".to_string(),
+                ))
+            }
+        };
+
+        Self::open(mode, path).map(Some)
+    }
+
+    pub fn open(mode: CassetteMode, path: PathBuf) -> Result<Self, CrabError> {
+        let mut state = ReplayState::default();
+
+        if mode == CassetteMode::Replay {
+            let contents = fs::read_to_string(&path).map_err(|e| {
+                CrabError::Config(format!("Could not read cassette {}: {}", path.display(), e))
+            })?;
+            for (i, line) in contents.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let interaction = serde_json::from_str(line).map_err(|e| {
+                    CrabError::Config(format!(
+                        "Invalid cassette {} line {}: {}",
+                        path.display(),
+                        i + 1,
+                        e
+                    ))
+                })?;
+                state.interactions.push(interaction);
+            }
+        } else if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+
+        Ok(Self {
+            mode,
+            path,
+            redactor: Redactor::from_env(),
+            replay: Mutex::new(state),
+        })
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends one interaction. Failing to record never fails the run.
+    pub fn record(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        max_tokens: u32,
+        result: &Result<Completion, CrabError>,
+    ) {
+        let interaction = Interaction {
+            request: RecordedRequest {
+                messages: messages.to_vec(),
+                tools: tools.iter().map(|t| t.name.to_string()).collect(),
+                max_tokens,
+            },
+            response: result.as_ref().ok().map(|c| RecordedResponse {
+                provider: c.provider.clone(),
+                model: c.model.clone(),
+                content: c.content.clone(),
+                tool_calls: c.tool_calls.clone(),
+                input_tokens: c.usage.input_tokens,
+                output_tokens: c.usage.output_tokens,
+                cached_tokens: c.usage.cached_tokens,
+                reasoning: c.reasoning.clone(),
+                reasoning_tokens: c.usage.reasoning_tokens,
+            }),
+            error: result.as_ref().err().map(RecordedError::of),
+        };
+
+        let line = self
+            .redactor
+            .redact(&serde_json::to_string(&interaction).unwrap_or_default());
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(e) = written {
+            eprintln!("Warning: Could not write cassette: {}", e);
+        }
+    }
+
+    /// Serves the next recorded outcome. A request that differs from the recorded one
+    /// is still answered, with a warning, so a replay shows where a run diverged.
+    pub fn replay(&self, messages: &[Message]) -> Result<Completion, CrabError> {
+        let mut state = self.replay.lock().unwrap_or_else(|e| e.into_inner());
+        let index = state.position;
+        let interaction = state.interactions.get(index).cloned().ok_or_else(|| {
+            CrabError::InvalidResponse(format!(
+                "Cassette {} has no interaction {}",
+                self.path.display(),
+                index + 1
+            ))
+        })?;
+        state.position += 1;
+
+        let recorded = interaction.request.messages.last().map(|m| &m.content);
+        let current = messages.last().map(|m| self.redactor.redact(&m.content));
+        if recorded != current.as_ref() {
+            eprintln!(
+                "[Replay] Request {} differs from the cassette in its last message",
+                index + 1
+            );
+        }
+
+        if let Some(error) = interaction.error {
+            return Err(error.to_error());
+        }
+        let response = interaction.response.ok_or_else(|| {
+            CrabError::InvalidResponse(format!("Cassette interaction {} is empty", index + 1))
+        })?;
+
+        Ok(Completion {
+            content: response.content,
+            tool_calls: response.tool_calls,
+            reasoning: response.reasoning,
+            usage: Usage {
+                input_tokens: response.input_tokens,
+                output_tokens: response.output_tokens,
+                cached_tokens: response.cached_tokens,
+                reasoning_tokens: response.reasoning_tokens,
+            },
+            provider: response.provider,
+            model: response.model,
+            ..Default::default()
+        })
+    }
+}
+
+/// Where replayed commands run, so they cannot change the real workspace.
+pub fn scratch_dir() -> PathBuf {
+    env::var("LLM_CASSETTE_SCRATCH")
+        .ok()
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| env::temp_dir().join(format!("crab-replay-{}", std::process::id())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_redacted_interactions_and_replays_them_in_order() {
+        let path = env::temp_dir().join(format!("crab-cassette-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let recorder = Cassette::open(CassetteMode::Record, path.clone()).unwrap();
+        let messages = vec![Message::new(
+            "user",
+            "my key is sk-abcdefghijklmnopqrstuvwx, list files",
+        )];
+        let first = Completion {
+            content: String::new(),
+            tool_calls: vec![ToolCall {
+                id: "call_1".to_string(),
+                name: "run_terminal".to_string(),
+                arguments: serde_json::json!({ "command": "ls" }),
+            }],
+            usage: Usage {
+                input_tokens: 10,
+                output_tokens: 2,
+                cached_tokens: 0,
+                reasoning_tokens: 0,
+            },
+            provider: "openai".to_string(),
+            model: "gpt-4o".to_string(),
+            ..Default::default()
+        };
+        recorder.record(&messages, &[], 100, &Ok(first.clone()));
+        recorder.record(
+            &messages,
+            &[],
+            100,
+            &Err(CrabError::Timeout("Request timed out".to_string())),
+        );
+
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(!written.contains("sk-abcdefghijklmnopqrstuvwx"));
+        assert!(written.contains("my key is [REDACTED], list files"));
+
+        let player = Cassette::open(CassetteMode::Replay, path.clone()).unwrap();
+        let replayed = player.replay(&messages).unwrap();
+        assert_eq!(replayed.tool_calls, first.tool_calls);
+        assert_eq!(replayed.usage, first.usage);
+        assert_eq!(replayed.model, "gpt-4o");
+        assert!(matches!(
+            player.replay(&messages),
+            Err(CrabError::Timeout(_))
+        ));
+        assert!(matches!(
+            player.replay(&messages),
+            Err(CrabError::InvalidResponse(_))
+        ));
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,274 @@
+use crate::context::{message_tokens, ModelFamily};
+use crate::error::CrabError;
+use crate::llm::{Completion, LLMClient, Message};
+use std::env;
+use std::fs;
+
+/// Marker prefix for the compacted history when it cannot be written back to a file.
+/// The payload is the base64-encoded JSON history, like the `HISTORY` input.
+pub const HISTORY_MARKER: &str = "[HISTORY]";
+
+/// Opens the rolling summary message so the next compaction can find and extend it.
+pub const SUMMARY_PREFIX: &str = "Summary of earlier conversation:";
+
+const DEFAULT_SUMMARY_PROMPT: &str = "You maintain the long-term memory of an AI agent. \
+Summarize the conversation below into a concise briefing for the agent's future self. \
+Keep facts about the user, their preferences, decisions made, open tasks, file names, \
+commands that worked and anything the user asked to remember. Drop greetings and \
+small talk. If a previous summary is given, merge it with the new turns. \
+Reply with the summary only, as short bullet points.";
+
+#[derive(Debug, Clone)]
+pub struct CompactionConfig {
+    pub enabled: bool,
+    /// Compact once the conversation history is estimated above this many tokens.
+    pub threshold_tokens: u32,
+    /// Most recent messages kept verbatim after the summary.
+    pub keep_recent: usize,
+    pub max_summary_tokens: u32,
+    pub prompt: String,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold_tokens: 6_000,
+            keep_recent: 6,
+            max_summary_tokens: 800,
+            prompt: DEFAULT_SUMMARY_PROMPT.to_string(),
+        }
+    }
+}
+
+impl CompactionConfig {
+    /// `HISTORY_SUMMARY_PROMPT` takes the prompt text directly, or `@path` to read it
+    /// from a file.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let number = |key: &str| env::var(key).ok().and_then(|v| v.parse::<u64>().ok());
+
+        let prompt = match env::var("HISTORY_SUMMARY_PROMPT") {
+            Ok(value) if value.starts_with('@') => {
+                fs::read_to_string(&value[1..]).unwrap_or_else(|e| {
+                    eprintln!(
+                        "Warning: Could not read summary prompt {}: {}",
+                        &value[1..],
+                        e
+                    );
+                    defaults.prompt.clone()
+                })
+            }
+            Ok(value) if !value.trim().is_empty() => value,
+            _ => defaults.prompt.clone(),
+        };
+
+        Self {
+            enabled: env::var("HISTORY_COMPACT").unwrap_or_else(|_| "true".to_string()) != "false",
+            threshold_tokens: number("HISTORY_COMPACT_TOKENS")
+                .map(|n| n as u32)
+                .unwrap_or(defaults.threshold_tokens),
+            keep_recent: number("HISTORY_KEEP_RECENT")
+                .map(|n| n as usize)
+                .unwrap_or(defaults.keep_recent),
+            max_summary_tokens: number("HISTORY_SUMMARY_TOKENS")
+                .map(|n| n as u32)
+                .unwrap_or(defaults.max_summary_tokens),
+            prompt,
+        }
+    }
+}
+
+/// Whether `history` is big enough to be worth a summarization call. Injected system
+/// messages (memories) are not part of the conversation and are not counted.
+pub fn needs_compaction(
+    history: &[Message],
+    family: ModelFamily,
+    config: &CompactionConfig,
+) -> bool {
+    if !config.enabled {
+        return false;
+    }
+
+    let conversation: Vec<&Message> = history.iter().filter(|m| m.role != "system").collect();
+    conversation.len() > config.keep_recent
+        && conversation
+            .iter()
+            .map(|m| message_tokens(m, family))
+            .sum::<u32>()
+            > config.threshold_tokens
+}
+
+/// Folds everything but the last `keep_recent` messages into one summary system
+/// message, merging any earlier summary. Returns the history to persist — the
+/// summary followed by the recent messages — and the summarization completion so
+/// its usage can be reported. Other system messages are left out; the orchestrator
+/// injects them fresh on every run.
+pub async fn compact(
+    client: &LLMClient,
+    history: &[Message],
+    config: &CompactionConfig,
+) -> Result<(Vec<Message>, Completion), CrabError> {
+    let previous_summary = history
+        .iter()
+        .find(|m| m.role == "system" && m.content.starts_with(SUMMARY_PREFIX))
+        .map(|m| m.content[SUMMARY_PREFIX.len()..].trim().to_string());
+
+    let conversation: Vec<&Message> = history.iter().filter(|m| m.role != "system").collect();
+    let mut split = conversation.len().saturating_sub(config.keep_recent);
+    // Keep a tool call and its results on the same side of the split.
+    // With `keep_recent` at 0 the split is past the end and everything is folded.
+    while split > 0 && split < conversation.len() && conversation[split].role == "tool" {
+        split -= 1;
+    }
+    let (older, recent) = conversation.split_at(split);
+
+    let mut transcript = String::new();
+    if let Some(summary) = &previous_summary {
+        transcript.push_str(&format!("Previous summary:\n{}\n\n", summary));
+    }
+    transcript.push_str("Conversation:\n");
+    for message in older {
+        transcript.push_str(&render(message));
+        transcript.push('\n');
+    }
+
+    let request = vec![
+        Message::new("system", config.prompt.clone()),
+        Message::new("user", transcript),
+    ];
+    let completion = client
+        .complete(&request, &[], config.max_summary_tokens)
+        .await?;
+
+    let summary = completion.content.trim();
+    if summary.is_empty() {
+        return Err(CrabError::InvalidResponse(
+            "Summarization returned an empty summary".to_string(),
+        ));
+    }
+
+    let mut compacted = vec![Message::new(
+        "system",
+        format!("{}\n{}", SUMMARY_PREFIX, summary),
+    )];
+    compacted.extend(recent.iter().map(|m| (*m).clone()));
+    Ok((compacted, completion))
+}
+
+fn render(message: &Message) -> String {
+    let mut line = format!("{}: {}", message.role, message.content.trim());
+    for call in &message.tool_calls {
+        line.push_str(&format!(
+            "\n  [called {} with {}]",
+            call.name, call.arguments
+        ));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ProviderTarget;
+    use crate::test_server::{StubResponse, StubServer};
+    use serde_json::json;
+
+    fn history(turns: usize) -> Vec<Message> {
+        let mut messages = vec![
+            Message::new("system", "Relevant memories for this context:\n- likes tea"),
+            Message::new(
+                "system",
+                format!("{}\n- user is called Sam", SUMMARY_PREFIX),
+            ),
+        ];
+        for i in 0..turns {
+            let role = if i % 2 == 0 { "user" } else { "assistant" };
+            messages.push(Message::new(role, format!("turn {}", i)));
+        }
+        messages
+    }
+
+    #[test]
+    fn compaction_threshold_ignores_system_messages() {
+        let config = CompactionConfig {
+            threshold_tokens: 50,
+            keep_recent: 2,
+            ..CompactionConfig::default()
+        };
+
+        assert!(!needs_compaction(&history(4), ModelFamily::Gpt, &config));
+        assert!(needs_compaction(&history(20), ModelFamily::Gpt, &config));
+        assert!(!needs_compaction(
+            &history(20),
+            ModelFamily::Gpt,
+            &CompactionConfig {
+                enabled: false,
+                ..config
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn folds_older_turns_and_previous_summary_into_a_new_summary() {
+        let server = StubServer::start(vec![StubResponse::json(
+            200,
+            json!({
+                "choices": [{ "message": { "role": "assistant", "content": "- user is Sam\n- asked about turn 0" } }],
+                "usage": { "total_tokens": 42 },
+            }),
+        )]);
+        let client = LLMClient::stub(vec![ProviderTarget::stub(
+            "openai",
+            "gpt-4o-mini",
+            &server.url,
+        )]);
+        let config = CompactionConfig {
+            keep_recent: 2,
+            prompt: "Summarize.".to_string(),
+            ..CompactionConfig::default()
+        };
+
+        let (compacted, completion) = compact(&client, &history(6), &config).await.unwrap();
+
+        assert_eq!(completion.usage.total(), 42);
+        let contents: Vec<&str> = compacted.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec![
+                "Summary of earlier conversation:\n- user is Sam\n- asked about turn 0",
+                "turn 4",
+                "turn 5",
+            ]
+        );
+
+        let body = server.requests()[0].json();
+        assert_eq!(body["messages"][0]["content"], "Summarize.");
+        assert_eq!(
+            body["messages"][1]["content"],
+            "Previous summary:\n- user is called Sam\n\nConversation:\nuser: turn 0\nassistant: turn 1\nuser: turn 2\nassistant: turn 3\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeping_no_recent_messages_folds_the_whole_conversation() {
+        let server = StubServer::start(vec![StubResponse::ok_chat("- all of it")]);
+        let client = LLMClient::stub(vec![ProviderTarget::stub(
+            "openai",
+            "gpt-4o-mini",
+            &server.url,
+        )]);
+        let config = CompactionConfig {
+            keep_recent: 0,
+            ..CompactionConfig::default()
+        };
+
+        let (compacted, _) = compact(&client, &history(3), &config).await.unwrap();
+
+        assert_eq!(compacted.len(), 1);
+        assert!(server.requests()[0].json()["messages"][1]["content"]
+            .as_str()
+            .unwrap()
+            .ends_with("user: turn 2\n"));
+    }
+}
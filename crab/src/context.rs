@@ -0,0 +1,342 @@
+use crate::llm::Message;
+use crate::models;
+use std::env;
+
+/// Tokens added per message for role markers and separators.
+const MESSAGE_OVERHEAD: u32 = 4;
+
+/// Vision models bill an image by its pixels; a full-size one costs about this much.
+const IMAGE_TOKENS: u32 = 1_600;
+
+/// Used when the model registry has no context length and `LLM_CONTEXT_LENGTH` is
+/// not set.
+const DEFAULT_CONTEXT_LENGTH: u32 = 32_768;
+
+/// Memory and meeting sections never take more than this many tokens each.
+const MAX_SECTION_TOKENS: u32 = 2_000;
+
+const TRUNCATED: &str = "\n[...truncated]";
+
+/// Tokenizer families differ enough in how many characters fit in a token that a
+/// single ratio either wastes context on GPT or overflows it on Mistral.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModelFamily {
+    Gpt,
+    Claude,
+    Gemini,
+    Llama,
+    Mistral,
+    DeepSeek,
+    Other,
+}
+
+impl ModelFamily {
+    pub fn of(model: &str) -> Self {
+        let name = base_name(model);
+        let starts = |prefixes: &[&str]| prefixes.iter().any(|p| name.starts_with(p));
+
+        if starts(&["gpt-", "chatgpt", "o1", "o3", "o4"]) {
+            ModelFamily::Gpt
+        } else if starts(&["claude"]) {
+            ModelFamily::Claude
+        } else if starts(&["gemini", "gemma"]) {
+            ModelFamily::Gemini
+        } else if name.contains("llama") {
+            ModelFamily::Llama
+        } else if starts(&["mistral", "mixtral", "codestral", "ministral"]) {
+            ModelFamily::Mistral
+        } else if starts(&["deepseek"]) {
+            ModelFamily::DeepSeek
+        } else {
+            ModelFamily::Other
+        }
+    }
+
+    /// Average characters of English text or code per token.
+    fn chars_per_token(self) -> f64 {
+        match self {
+            ModelFamily::Gpt | ModelFamily::Gemini => 4.0,
+            ModelFamily::Llama | ModelFamily::DeepSeek => 3.8,
+            ModelFamily::Claude => 3.5,
+            ModelFamily::Mistral => 3.3,
+            ModelFamily::Other => 3.2,
+        }
+    }
+}
+
+/// Model id without an OpenRouter-style `vendor/` prefix, lowercased.
+fn base_name(model: &str) -> String {
+    model
+        .rsplit('/')
+        .next()
+        .unwrap_or(model)
+        .to_ascii_lowercase()
+}
+
+/// Estimates the token count of `text`. ASCII is divided by the family's ratio;
+/// every other character is counted as a full token, which over-counts accented
+/// Latin text but keeps CJK and emoji from blowing the budget.
+pub fn estimate_tokens(text: &str, family: ModelFamily) -> u32 {
+    let (ascii, other) = text.chars().fold((0u32, 0u32), |(ascii, other), c| {
+        if c.is_ascii() {
+            (ascii + 1, other)
+        } else {
+            (ascii, other + 1)
+        }
+    });
+    (ascii as f64 / family.chars_per_token()).ceil() as u32 + other
+}
+
+pub fn message_tokens(message: &Message, family: ModelFamily) -> u32 {
+    let calls: u32 = message
+        .tool_calls
+        .iter()
+        .map(|call| {
+            estimate_tokens(&call.name, family)
+                + estimate_tokens(&call.arguments.to_string(), family)
+        })
+        .sum();
+    estimate_tokens(&message.content, family)
+        + calls
+        + message.images.len() as u32 * IMAGE_TOKENS
+        + MESSAGE_OVERHEAD
+}
+
+/// Context window in tokens, from the model registry.
+pub fn context_length(provider: &str, model: &str) -> u32 {
+    models::lookup(provider, model)
+        .context_length
+        .unwrap_or(DEFAULT_CONTEXT_LENGTH)
+}
+
+/// What `ContextWindow::fit` left out, for the log line.
+#[derive(Debug, Default, PartialEq)]
+pub struct TrimReport {
+    pub dropped_messages: usize,
+    pub truncated_sections: usize,
+    pub estimated_tokens: u32,
+}
+
+/// Keeps each request inside the model's context window. The conversation is
+/// assumed to be laid out as `main` builds it: leading system messages (the system
+/// prompt, then memory and meeting sections), past history, and the current turn.
+#[derive(Debug, Clone)]
+pub struct ContextWindow {
+    family: ModelFamily,
+    /// Tokens available for the prompt once the reply is reserved.
+    budget: u32,
+    max_history_messages: usize,
+}
+
+impl ContextWindow {
+    /// `LLM_CONTEXT_LENGTH` overrides the registry for models it does not know.
+    pub fn for_model(
+        provider: &str,
+        model: &str,
+        max_output_tokens: u32,
+        max_history_messages: usize,
+    ) -> Self {
+        let length = env::var("LLM_CONTEXT_LENGTH")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| context_length(provider, model));
+        Self::new(
+            ModelFamily::of(model),
+            length,
+            max_output_tokens,
+            max_history_messages,
+        )
+    }
+
+    pub fn new(
+        family: ModelFamily,
+        context_length: u32,
+        max_output_tokens: u32,
+        max_history_messages: usize,
+    ) -> Self {
+        // Estimates are rough, so keep a tenth of the window spare.
+        let usable = context_length - context_length / 10;
+        Self {
+            family,
+            budget: usable.saturating_sub(max_output_tokens),
+            max_history_messages,
+        }
+    }
+
+    /// Returns the messages to send. The first system message and everything from
+    /// `turn_start` (the latest user message and any tool exchange after it) are
+    /// always kept. Memory and meeting sections are capped, history is limited to
+    /// `max_history_messages`, and the oldest history is dropped until it fits.
+    pub fn fit(&self, messages: &[Message], turn_start: usize) -> (Vec<Message>, TrimReport) {
+        let turn_start = turn_start.min(messages.len());
+        let head_end = messages[..turn_start]
+            .iter()
+            .position(|m| m.role != "system")
+            .unwrap_or(turn_start);
+
+        let mut report = TrimReport::default();
+        let section_cap = MAX_SECTION_TOKENS.min(self.budget / 8);
+
+        let mut head: Vec<Message> = messages[..head_end].to_vec();
+        for section in head.iter_mut().skip(1) {
+            if estimate_tokens(&section.content, self.family) > section_cap {
+                section.content = self.truncate(&section.content, section_cap);
+                report.truncated_sections += 1;
+            }
+        }
+
+        let history = &messages[head_end..turn_start];
+        let mut start = history.len().saturating_sub(self.max_history_messages);
+        report.dropped_messages = start;
+
+        let tokens =
+            |list: &[Message]| -> u32 { list.iter().map(|m| message_tokens(m, self.family)).sum() };
+        let fixed = tokens(&head) + tokens(&messages[turn_start..]);
+        let mut history_tokens = tokens(&history[start..]);
+
+        while start < history.len() && fixed + history_tokens > self.budget {
+            history_tokens -= message_tokens(&history[start], self.family);
+            start += 1;
+            report.dropped_messages += 1;
+        }
+
+        // A tool result is meaningless once the call that produced it is gone.
+        while start < history.len() && history[start].role == "tool" {
+            history_tokens -= message_tokens(&history[start], self.family);
+            start += 1;
+            report.dropped_messages += 1;
+        }
+
+        report.estimated_tokens = fixed + history_tokens;
+
+        let mut window = head;
+        window.extend_from_slice(&history[start..]);
+        window.extend_from_slice(&messages[turn_start..]);
+        (window, report)
+    }
+
+    fn truncate(&self, text: &str, max_tokens: u32) -> String {
+        let marker = estimate_tokens(TRUNCATED, self.family);
+        let mut used = 0.0;
+        let mut end = 0;
+
+        for (i, c) in text.char_indices() {
+            used += if c.is_ascii() {
+                1.0 / self.family.chars_per_token()
+            } else {
+                1.0
+            };
+            if used.ceil() as u32 + marker > max_tokens {
+                break;
+            }
+            end = i + c.len_utf8();
+        }
+
+        format!("{}{}", &text[..end], TRUNCATED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ToolCall;
+    use serde_json::json;
+
+    #[test]
+    fn estimates_differ_by_family_and_count_non_ascii_heavily() {
+        let text = "a".repeat(400);
+        assert_eq!(estimate_tokens(&text, ModelFamily::Gpt), 100);
+        assert_eq!(estimate_tokens(&text, ModelFamily::Claude), 115);
+        assert_eq!(estimate_tokens("日本語", ModelFamily::Gpt), 3);
+
+        assert_eq!(
+            ModelFamily::of("anthropic/claude-3.5-sonnet"),
+            ModelFamily::Claude
+        );
+        assert_eq!(
+            ModelFamily::of("llama-3.3-70b-versatile"),
+            ModelFamily::Llama
+        );
+        assert_eq!(ModelFamily::of("gpt-4o-mini"), ModelFamily::Gpt);
+    }
+
+    #[test]
+    fn looks_up_context_length_by_model_prefix() {
+        assert_eq!(context_length("openai", "gpt-4o-mini"), 128_000);
+        assert_eq!(context_length("openai", "gpt-4"), 8_192);
+        assert_eq!(
+            context_length("openrouter", "meta-llama/llama-3.1-70b-instruct"),
+            131_072
+        );
+        assert_eq!(context_length("ollama", "llama3:8b"), 8_192);
+        assert_eq!(
+            context_length("openai-compatible", "something-new"),
+            DEFAULT_CONTEXT_LENGTH
+        );
+    }
+
+    fn conversation(history: usize) -> Vec<Message> {
+        let mut messages = vec![
+            Message::new("system", "You are Crab."),
+            Message::new(
+                "system",
+                format!("Relevant past memories:\n{}", "m".repeat(40_000)),
+            ),
+        ];
+        for i in 0..history {
+            let role = if i % 2 == 0 { "user" } else { "assistant" };
+            messages.push(Message::new(
+                role,
+                format!("turn {} {}", i, "x".repeat(400)),
+            ));
+        }
+        messages.push(Message::new("user", "latest question"));
+        messages
+    }
+
+    #[test]
+    fn keeps_system_prompt_and_latest_turn_while_dropping_oldest_history() {
+        let messages = conversation(40);
+        let turn_start = messages.len() - 1;
+        let window = ContextWindow::new(ModelFamily::Gpt, 4_000, 1_000, 100);
+
+        let (fitted, report) = window.fit(&messages, turn_start);
+
+        assert_eq!(fitted[0].content, "You are Crab.");
+        assert_eq!(fitted.last().unwrap().content, "latest question");
+        assert!(fitted[1].content.ends_with(TRUNCATED));
+        assert_eq!(report.truncated_sections, 1);
+        assert!(report.dropped_messages > 0);
+        assert!(report.estimated_tokens <= 4_000 - 400 - 1_000);
+        // What survives is the most recent history, in order.
+        assert!(fitted[fitted.len() - 2].content.starts_with("turn 39 "));
+    }
+
+    #[test]
+    fn respects_max_history_messages_and_drops_orphaned_tool_results() {
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            name: "run_terminal".to_string(),
+            arguments: json!({ "command": "ls" }),
+        };
+        let messages = vec![
+            Message::new("system", "sys"),
+            Message::new("user", "old"),
+            Message {
+                role: "assistant".to_string(),
+                tool_calls: vec![call.clone()],
+                ..Default::default()
+            },
+            Message::tool_result(&call, "a.txt"),
+            Message::new("assistant", "done"),
+            Message::new("user", "now"),
+        ];
+        let window = ContextWindow::new(ModelFamily::Gpt, 100_000, 1_000, 2);
+
+        let (fitted, report) = window.fit(&messages, 5);
+
+        let roles: Vec<&str> = fitted.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec!["system", "assistant", "user"]);
+        assert_eq!(report.dropped_messages, 3);
+    }
+}
@@ -0,0 +1,138 @@
+//! The response contract from `build_system_prompt`, as a JSON Schema for providers
+//! with structured output and as a validator for everything the model sends back.
+
+use serde_json::{json, Value};
+use std::env;
+
+const STRING_FIELDS: &[&str] = &["userId", "message", "action", "terminal"];
+
+/// How many times a broken final answer is sent back for repair before crab gives
+/// up and wraps the text itself.
+pub fn max_repairs() -> usize {
+    env::var("RESPONSE_REPAIR_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2)
+}
+
+/// Written to satisfy OpenAI's strict mode: every property required and no extras.
+pub fn schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "userId": { "type": "string", "description": "Telegram user id" },
+            "message": { "type": "string", "description": "Short plain text for the Telegram bubble" },
+            "action": { "type": "string", "description": "Empty, or FILE:<filename.ext> to send a file from /app/workspace/out/" },
+            "terminal": { "type": "string", "description": "Empty, or a single shell command to run in the container" },
+            "panelActions": {
+                "type": "array",
+                "items": { "type": "string" },
+                "description": "Dashboard actions such as CALENDAR_CREATE:title|prompt|start_time|end_time|color|symbol",
+            },
+        },
+        "required": ["userId", "message", "action", "terminal", "panelActions"],
+        "additionalProperties": false,
+    })
+}
+
+/// Checks `response` against the contract. A surrounding markdown code fence is
+/// tolerated; everything else is reported, one problem per entry.
+pub fn validate(response: &str) -> Result<Value, Vec<String>> {
+    let text = strip_code_fence(response.trim());
+    let value: Value =
+        serde_json::from_str(text).map_err(|e| vec![format!("not valid JSON ({})", e)])?;
+    let Some(object) = value.as_object() else {
+        return Err(vec!["the reply must be a JSON object".to_string()]);
+    };
+
+    let mut errors = Vec::new();
+    for field in STRING_FIELDS {
+        match object.get(*field) {
+            None => errors.push(format!("missing field \"{}\"", field)),
+            Some(Value::String(_)) => {}
+            Some(_) => errors.push(format!("\"{}\" must be a string", field)),
+        }
+    }
+
+    match object.get("panelActions") {
+        None => errors.push("missing field \"panelActions\"".to_string()),
+        Some(Value::Array(items)) if items.iter().all(Value::is_string) => {}
+        Some(_) => errors.push("\"panelActions\" must be an array of strings".to_string()),
+    }
+
+    if let Some(action) = object.get("action").and_then(Value::as_str) {
+        if !action.is_empty() && !action.starts_with("FILE:") {
+            errors.push("\"action\" must be empty or start with FILE:".to_string());
+        }
+    }
+
+    for key in object.keys() {
+        if !STRING_FIELDS.contains(&key.as_str()) && key != "panelActions" {
+            errors.push(format!("unexpected field \"{}\"", key));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(value)
+    } else {
+        Err(errors)
+    }
+}
+
+fn strip_code_fence(text: &str) -> &str {
+    let Some(inner) = text.strip_prefix("```") else {
+        return text;
+    };
+    let inner = inner.strip_prefix("json").unwrap_or(inner);
+    inner.strip_suffix("```").unwrap_or(inner).trim()
+}
+
+/// The user turn that asks the model to fix its last answer.
+pub fn repair_prompt(errors: &[String]) -> String {
+    format!(
+        "Your last reply broke the RESPONSE CONTRACT: {}. Reply again with ONLY the JSON object \
+         (userId, message, action, terminal, panelActions) and nothing else.",
+        errors.join("; ")
+    )
+}
+
+/// Wraps an answer that never became valid, so the user still gets its text.
+pub fn fallback(response: &str) -> Value {
+    json!({
+        "userId": "",
+        "message": response.trim(),
+        "action": "",
+        "terminal": "",
+        "panelActions": [],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_contract_and_lists_every_violation() {
+        let valid = r#"```json
+{"userId": "42", "message": "Done", "action": "FILE:report.pdf", "terminal": "", "panelActions": []}
+```"#;
+        assert_eq!(validate(valid).unwrap()["action"], "FILE:report.pdf");
+
+        let errors =
+            validate(r#"{"message": 5, "action": "SEND", "terminal": "", "panelActions": [1], "mood": "ok"}"#)
+                .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "missing field \"userId\"",
+                "\"message\" must be a string",
+                "\"panelActions\" must be an array of strings",
+                "\"action\" must be empty or start with FILE:",
+                "unexpected field \"mood\"",
+            ]
+        );
+
+        assert!(validate("Sure! Here is the answer.").unwrap_err()[0].starts_with("not valid JSON"));
+        assert!(validate(&fallback("Sure!").to_string()).is_ok());
+    }
+}
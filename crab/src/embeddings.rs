@@ -0,0 +1,90 @@
+//! Text embeddings for ranking memories. Providers with an embeddings endpoint are
+//! used through `LLMClient::embed`; everywhere else (the proxy, mocks, providers
+//! without one, or when the call fails) a local embedding built from hashed word and
+//! character n-grams stands in. It needs no network and is stable across runs, so
+//! vectors saved with a memory stay comparable.
+
+/// Name recorded with vectors from `local_embedding`. Bump the suffix when the
+/// features change so stored vectors get recomputed.
+pub const LOCAL_MODEL: &str = "local-ngram-v1";
+
+const LOCAL_DIMENSIONS: usize = 512;
+
+/// The embedding model used for a provider when `LLM_EMBEDDING_MODEL` is not set.
+/// `None` means the provider has no embeddings endpoint crab knows about.
+pub fn default_model(provider: &str) -> Option<&'static str> {
+    match provider {
+        "openai" | "azure" => Some("text-embedding-3-small"),
+        "google" => Some("text-embedding-004"),
+        "mistral" => Some("mistral-embed"),
+        "ollama" => Some("nomic-embed-text"),
+        _ => None,
+    }
+}
+
+/// FNV-1a, chosen over `DefaultHasher` because its output is fixed forever.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn add_feature(vector: &mut [f32], feature: &str, weight: f32) {
+    let hash = fnv1a(feature.as_bytes());
+    let index = (hash % LOCAL_DIMENSIONS as u64) as usize;
+    // A hash-derived sign keeps colliding features from always adding up.
+    let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+    vector[index] += sign * weight;
+}
+
+/// A unit vector of hashed words and character trigrams. Trigrams let related word
+/// forms ("deploy", "deployment") match.
+pub fn local_embedding(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; LOCAL_DIMENSIONS];
+    let lowered = text.to_lowercase();
+    for word in lowered
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 2)
+    {
+        add_feature(&mut vector, word, 1.0);
+        let padded: Vec<char> = format!(" {} ", word).chars().collect();
+        for trigram in padded.windows(3) {
+            add_feature(&mut vector, &trigram.iter().collect::<String>(), 0.5);
+        }
+    }
+    normalize(vector)
+}
+
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    vector
+}
+
+/// Cosine similarity; 0 for empty or mismatched vectors.
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.is_empty() || a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norms =
+        a.iter().map(|v| v * v).sum::<f32>().sqrt() * b.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+/// Indices of the `k` vectors most similar to `query`, best first.
+pub fn top_k(query: &[f32], vectors: &[Vec<f32>], k: usize) -> Vec<usize> {
+    let mut scored: Vec<(usize, f32)> = vectors
+        .iter()
+        .enumerate()
+        .map(|(i, vector)| (i, cosine(query, vector)))
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.into_iter().take(k).map(|(i, _)| i).collect()
+}
@@ -0,0 +1,425 @@
+//! Structured errors for LLM calls and command execution, so callers can tell an auth
+//! failure from a context overflow or a failed command, and `main` can report each
+//! one with its own exit code.
+
+use crate::gemini::GeminiError;
+use crate::retry;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::Duration;
+
+/// Marker prefix for the machine-readable error line printed before exiting.
+pub const ERROR_MARKER: &str = "[ERROR]";
+
+/// What the provider told us about its rate limit when it turned us away.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimit {
+    pub retry_after: Option<Duration>,
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let number = |names: &[&str]| {
+            names.iter().find_map(|name| {
+                headers
+                    .get(*name)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse().ok())
+            })
+        };
+
+        Self {
+            retry_after: retry::retry_after(headers).or_else(|| retry::rate_limit_reset(headers)),
+            remaining_requests: number(&[
+                "x-ratelimit-remaining-requests",
+                "anthropic-ratelimit-requests-remaining",
+            ]),
+            remaining_tokens: number(&[
+                "x-ratelimit-remaining-tokens",
+                "anthropic-ratelimit-tokens-remaining",
+            ]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CrabError {
+    /// Missing key, unknown provider or bad setting; nothing was sent.
+    Config(String),
+    /// The provider rejected our credentials (401/403).
+    Auth {
+        status: u16,
+        message: String,
+    },
+    RateLimited {
+        status: u16,
+        code: Option<String>,
+        message: String,
+        limit: RateLimit,
+    },
+    /// The prompt does not fit the model's context window.
+    ContextOverflow {
+        code: Option<String>,
+        message: String,
+    },
+    /// A safety or moderation filter refused the prompt or the answer.
+    ContentFilter {
+        message: String,
+    },
+    Timeout(String),
+    /// DNS, connect or TLS failure, or the connection dropped mid-response.
+    Network(String),
+    /// Any other error the provider reported. `status` is `None` for errors that
+    /// arrive inside a stream or are relayed by the orchestrator proxy.
+    Http {
+        status: Option<u16>,
+        code: Option<String>,
+        message: String,
+    },
+    /// A success response we could not make sense of.
+    InvalidResponse(String),
+    /// The shell could not be started for a command.
+    Spawn {
+        command: String,
+        message: String,
+    },
+    /// The command ran and exited non-zero (`code` is `None` if killed by a signal).
+    CommandFailed {
+        command: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+    /// The next call would go over `MAX_COST_USD` or the daily token limit.
+    BudgetExceeded(String),
+    /// The run was stopped by a signal or the operator's stop file.
+    Cancelled(String),
+}
+
+impl CrabError {
+    /// Classifies a provider error from its status and body. OpenAI, Anthropic and
+    /// Gemini all nest the details under `error`, with the code in `code`, `type`
+    /// or `status` respectively.
+    pub fn from_api_error(status: Option<u16>, body: &str, headers: Option<&HeaderMap>) -> Self {
+        let parsed: Value = serde_json::from_str(body).unwrap_or(Value::Null);
+        let error = parsed.get("error").unwrap_or(&parsed);
+        let message = error
+            .get("message")
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .unwrap_or_else(|| match error {
+                Value::String(s) => s.clone(),
+                Value::Null => body.trim().to_string(),
+                other => other.to_string(),
+            });
+        let code = ["code", "type", "status"]
+            .iter()
+            .find_map(|key| error.get(*key).and_then(|v| v.as_str()))
+            .map(str::to_string);
+
+        let code_is =
+            |candidates: &[&str]| code.as_deref().is_some_and(|c| candidates.contains(&c));
+        let lower = message.to_lowercase();
+        let mentions = |needles: &[&str]| needles.iter().any(|n| lower.contains(n));
+
+        if code_is(&["context_length_exceeded", "string_above_max_length"])
+            || status == Some(413)
+            || mentions(&[
+                "context length",
+                "context window",
+                "maximum context",
+                "prompt is too long",
+                "too many tokens",
+                "exceeds the maximum number of tokens",
+            ])
+        {
+            return CrabError::ContextOverflow { code, message };
+        }
+
+        if code_is(&["content_filter", "content_policy_violation"])
+            || mentions(&["content management policy", "content policy"])
+        {
+            return CrabError::ContentFilter { message };
+        }
+
+        match status {
+            Some(status @ (401 | 403)) => CrabError::Auth { status, message },
+            Some(429) => CrabError::RateLimited {
+                status: 429,
+                code,
+                message,
+                limit: headers.map(RateLimit::from_headers).unwrap_or_default(),
+            },
+            _ if code_is(&[
+                "rate_limit_exceeded",
+                "rate_limit_error",
+                "RESOURCE_EXHAUSTED",
+            ]) =>
+            {
+                CrabError::RateLimited {
+                    status: status.unwrap_or(429),
+                    code,
+                    message,
+                    limit: headers.map(RateLimit::from_headers).unwrap_or_default(),
+                }
+            }
+            _ => CrabError::Http {
+                status,
+                code,
+                message,
+            },
+        }
+    }
+
+    pub fn from_reqwest(error: &reqwest::Error) -> Self {
+        let message = format!("Request failed: {}", error);
+        if error.is_timeout() {
+            CrabError::Timeout(message)
+        } else if error.is_connect() || error.is_request() || error.is_body() {
+            CrabError::Network(message)
+        } else if error.is_decode() {
+            CrabError::InvalidResponse(message)
+        } else {
+            CrabError::Http {
+                status: error.status().map(|s| s.as_u16()),
+                code: None,
+                message,
+            }
+        }
+    }
+
+    /// Whether another attempt, or another provider, might succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            CrabError::RateLimited { .. } | CrabError::Timeout(_) | CrabError::Network(_) => true,
+            CrabError::Http {
+                status: Some(status),
+                ..
+            } => StatusCode::from_u16(*status).is_ok_and(retry::is_retryable_status),
+            CrabError::Http {
+                code: Some(code), ..
+            } => matches!(
+                code.as_str(),
+                "overloaded_error" | "api_error" | "UNAVAILABLE"
+            ),
+            _ => false,
+        }
+    }
+
+    /// Stable identifier for the orchestrator.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CrabError::Config(_) => "config",
+            CrabError::Auth { .. } => "auth",
+            CrabError::RateLimited { .. } => "rate_limited",
+            CrabError::ContextOverflow { .. } => "context_overflow",
+            CrabError::ContentFilter { .. } => "content_filter",
+            CrabError::Timeout(_) => "timeout",
+            CrabError::Network(_) => "network",
+            CrabError::Http { .. } => "http",
+            CrabError::InvalidResponse(_) => "invalid_response",
+            CrabError::Spawn { .. } => "spawn_failed",
+            CrabError::CommandFailed { .. } => "command_failed",
+            CrabError::BudgetExceeded(_) => "budget_exceeded",
+            CrabError::Cancelled(_) => "cancelled",
+        }
+    }
+
+    /// Process exit code for `main`. 1 stays the generic failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CrabError::Config(_) => 2,
+            CrabError::Auth { .. } => 3,
+            CrabError::RateLimited { .. } => 4,
+            CrabError::ContextOverflow { .. } => 5,
+            CrabError::ContentFilter { .. } => 6,
+            CrabError::Timeout(_) => 7,
+            CrabError::Network(_) => 8,
+            CrabError::Http { .. } => 9,
+            CrabError::InvalidResponse(_) => 10,
+            CrabError::Spawn { .. } => 11,
+            CrabError::CommandFailed { .. } => 12,
+            CrabError::BudgetExceeded(_) => 13,
+            CrabError::Cancelled(_) => 14,
+        }
+    }
+
+    /// The `[ERROR]` line `main` prints before exiting.
+    pub fn report(&self) -> String {
+        let mut report = json!({
+            "kind": self.kind(),
+            "exitCode": self.exit_code(),
+            "message": self.to_string(),
+        });
+
+        match self {
+            CrabError::Auth { status, .. } => report["status"] = json!(status),
+            CrabError::RateLimited {
+                status,
+                code,
+                limit,
+                ..
+            } => {
+                report["status"] = json!(status);
+                report["code"] = json!(code);
+                report["retryAfterSecs"] = json!(limit.retry_after.map(|d| d.as_secs_f64()));
+            }
+            CrabError::ContextOverflow { code, .. } => report["code"] = json!(code),
+            CrabError::Http { status, code, .. } => {
+                report["status"] = json!(status);
+                report["code"] = json!(code);
+            }
+            CrabError::CommandFailed { code, .. } => report["code"] = json!(code),
+            _ => {}
+        }
+
+        format!("{} {}", ERROR_MARKER, report)
+    }
+}
+
+impl fmt::Display for CrabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrabError::Config(message)
+            | CrabError::Timeout(message)
+            | CrabError::Network(message)
+            | CrabError::InvalidResponse(message) => write!(f, "{}", message),
+            CrabError::BudgetExceeded(message) => write!(f, "Budget exceeded: {}", message),
+            CrabError::Cancelled(reason) => write!(f, "Run cancelled: {}", reason),
+            CrabError::Auth { status, message } => {
+                write!(f, "Authentication failed ({}): {}", status, message)
+            }
+            CrabError::RateLimited { message, limit, .. } => match limit.retry_after {
+                Some(wait) => write!(
+                    f,
+                    "Rate limited (retry after {:.0}s): {}",
+                    wait.as_secs_f64(),
+                    message
+                ),
+                None => write!(f, "Rate limited: {}", message),
+            },
+            CrabError::ContextOverflow { message, .. } => {
+                write!(f, "Context length exceeded: {}", message)
+            }
+            CrabError::ContentFilter { message } => {
+                write!(f, "Blocked by content filter: {}", message)
+            }
+            CrabError::Http {
+                status: Some(status),
+                message,
+                ..
+            } => write!(f, "API error ({}): {}", status, message),
+            CrabError::Http {
+                status: None,
+                message,
+                ..
+            } => write!(f, "API error: {}", message),
+            CrabError::Spawn { command, message } => {
+                write!(f, "Failed to execute '{}': {}", command, message)
+            }
+            CrabError::CommandFailed {
+                code: Some(code),
+                stderr,
+                ..
+            } => write!(f, "Command exited with status {}: {}", code, stderr),
+            CrabError::CommandFailed {
+                code: None, stderr, ..
+            } => write!(f, "Command was terminated: {}", stderr),
+        }
+    }
+}
+
+impl std::error::Error for CrabError {}
+
+impl From<GeminiError> for CrabError {
+    fn from(error: GeminiError) -> Self {
+        match error {
+            GeminiError::PromptBlocked { .. } | GeminiError::ContentBlocked { .. } => {
+                CrabError::ContentFilter {
+                    message: error.to_string(),
+                }
+            }
+            GeminiError::Finished { .. } | GeminiError::Empty => {
+                CrabError::InvalidResponse(error.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_provider_error_bodies() {
+        let openai = r#"{"error":{"message":"This model's maximum context length is 8192 tokens.","type":"invalid_request_error","code":"context_length_exceeded"}}"#;
+        assert!(matches!(
+            CrabError::from_api_error(Some(400), openai, None),
+            CrabError::ContextOverflow { code: Some(c), .. } if c == "context_length_exceeded"
+        ));
+
+        let anthropic = r#"{"type":"error","error":{"type":"authentication_error","message":"invalid x-api-key"}}"#;
+        assert_eq!(
+            CrabError::from_api_error(Some(401), anthropic, None),
+            CrabError::Auth {
+                status: 401,
+                message: "invalid x-api-key".to_string()
+            }
+        );
+
+        let gemini =
+            r#"{"error":{"code":429,"message":"Quota exceeded","status":"RESOURCE_EXHAUSTED"}}"#;
+        let limited = CrabError::from_api_error(Some(429), gemini, None);
+        assert_eq!(limited.kind(), "rate_limited");
+        assert!(limited.is_retryable());
+
+        let overloaded = r#"{"type":"overloaded_error","message":"Overloaded"}"#;
+        let streamed = CrabError::from_api_error(None, overloaded, None);
+        assert_eq!(streamed.to_string(), "API error: Overloaded");
+        assert!(streamed.is_retryable());
+
+        let plain = CrabError::from_api_error(Some(404), "not found", None);
+        assert_eq!(plain.to_string(), "API error (404): not found");
+        assert!(!plain.is_retryable());
+    }
+
+    #[test]
+    fn each_kind_has_its_own_exit_code() {
+        let errors = [
+            CrabError::Config(String::new()),
+            CrabError::Auth {
+                status: 401,
+                message: String::new(),
+            },
+            CrabError::from_api_error(Some(429), "", None),
+            CrabError::from_api_error(Some(413), "", None),
+            CrabError::ContentFilter {
+                message: String::new(),
+            },
+            CrabError::Timeout(String::new()),
+            CrabError::Network(String::new()),
+            CrabError::from_api_error(Some(500), "", None),
+            CrabError::InvalidResponse(String::new()),
+            CrabError::Spawn {
+                command: String::new(),
+                message: String::new(),
+            },
+            CrabError::CommandFailed {
+                command: String::new(),
+                code: Some(1),
+                stderr: String::new(),
+            },
+            CrabError::BudgetExceeded(String::new()),
+            CrabError::Cancelled(String::new()),
+        ];
+
+        let mut codes: Vec<i32> = errors.iter().map(CrabError::exit_code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&0) && !codes.contains(&1));
+    }
+}
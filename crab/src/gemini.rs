@@ -0,0 +1,589 @@
+use crate::llm::{Completion, Message, ToolCall, ToolDefinition, Usage};
+use crate::sampling::Sampling;
+use crate::stream::ContentStream;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt;
+
+/// Sent as the first user turn when the history would otherwise open with the model.
+const CONVERSATION_START: &str = "(conversation start)";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<Value>,
+    pub contents: Vec<GeminiContent>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<Value>,
+    pub generation_config: GenerationConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached_content: Option<String>,
+}
+
+impl GeminiRequest {
+    /// The system instruction and tools, which are the same on every call of a run and
+    /// can be moved into a `cachedContents` resource.
+    pub fn cacheable_prefix(&self) -> Option<Value> {
+        let system_instruction = self.system_instruction.as_ref()?;
+        let mut prefix = json!({ "systemInstruction": system_instruction });
+        if !self.tools.is_empty() {
+            prefix["tools"] = json!(self.tools);
+        }
+        Some(prefix)
+    }
+
+    /// Refers to the cache holding `cacheable_prefix` instead of sending it again;
+    /// Gemini rejects requests that repeat what the cache holds.
+    pub fn use_cached_content(&mut self, name: String) {
+        self.system_instruction = None;
+        self.tools.clear();
+        self.cached_content = Some(name);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GeminiContent {
+    pub role: &'static str,
+    pub parts: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationConfig {
+    pub max_output_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_mime_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking_config: Option<Value>,
+}
+
+/// Reasons Gemini can refuse or cut short a generation without an HTTP error.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeminiError {
+    /// The prompt itself was blocked (`promptFeedback.blockReason`).
+    PromptBlocked {
+        reason: String,
+    },
+    /// The candidate was stopped by a safety or policy filter.
+    ContentBlocked {
+        finish_reason: String,
+        categories: Vec<String>,
+    },
+    /// Any other finish reason that leaves no usable answer.
+    Finished {
+        finish_reason: String,
+    },
+    Empty,
+}
+
+impl fmt::Display for GeminiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeminiError::PromptBlocked { reason } => {
+                write!(f, "Gemini blocked the prompt ({})", reason)
+            }
+            GeminiError::ContentBlocked {
+                finish_reason,
+                categories,
+            } if categories.is_empty() => {
+                write!(f, "Gemini blocked the response ({})", finish_reason)
+            }
+            GeminiError::ContentBlocked {
+                finish_reason,
+                categories,
+            } => write!(
+                f,
+                "Gemini blocked the response ({}: {})",
+                finish_reason,
+                categories.join(", ")
+            ),
+            GeminiError::Finished { finish_reason } => {
+                write!(f, "Gemini stopped without an answer ({})", finish_reason)
+            }
+            GeminiError::Empty => write!(f, "No response from API"),
+        }
+    }
+}
+
+/// Builds a `generateContent` request. System messages become `systemInstruction`,
+/// assistant turns use the `model` role, and consecutive same-role turns are merged
+/// because Gemini expects user and model turns to alternate. `response_schema` is
+/// only applied without tools, since Gemini rejects JSON output with function calling.
+pub fn build_request(
+    messages: &[Message],
+    tools: &[ToolDefinition],
+    max_tokens: u32,
+    response_schema: Option<&Value>,
+    sampling: &Sampling,
+) -> GeminiRequest {
+    let system: Vec<Value> = messages
+        .iter()
+        .filter(|m| m.role == "system" && !m.content.trim().is_empty())
+        .map(|m| json!({ "text": m.content }))
+        .collect();
+
+    let mut contents: Vec<GeminiContent> = Vec::new();
+    for message in messages.iter().filter(|m| m.role != "system") {
+        let Some(content) = convert_message(message) else {
+            continue;
+        };
+
+        match contents.last_mut() {
+            Some(last) if last.role == content.role => last.parts.extend(content.parts),
+            _ => contents.push(content),
+        }
+    }
+
+    if contents.first().map(|c| c.role) != Some("user") {
+        contents.insert(
+            0,
+            GeminiContent {
+                role: "user",
+                parts: vec![json!({ "text": CONVERSATION_START })],
+            },
+        );
+    }
+
+    let function_declarations: Vec<Value> = tools
+        .iter()
+        .map(|t| {
+            json!({
+                "name": t.name,
+                "description": t.description,
+                "parameters": t.parameters,
+            })
+        })
+        .collect();
+
+    let structured = response_schema.is_some() && tools.is_empty();
+
+    GeminiRequest {
+        system_instruction: (!system.is_empty()).then(|| json!({ "parts": system })),
+        contents,
+        tools: if function_declarations.is_empty() {
+            Vec::new()
+        } else {
+            vec![json!({ "functionDeclarations": function_declarations })]
+        },
+        generation_config: GenerationConfig {
+            max_output_tokens: max_tokens,
+            response_mime_type: structured.then_some("application/json"),
+            response_schema: response_schema.filter(|_| structured).map(openapi_schema),
+            temperature: sampling.temperature,
+            top_p: sampling.top_p,
+            seed: sampling.seed,
+            stop_sequences: sampling.stop.clone(),
+            presence_penalty: sampling.presence_penalty,
+            frequency_penalty: sampling.frequency_penalty,
+            thinking_config: sampling
+                .thinking_budget()
+                .map(|budget| json!({ "thinkingBudget": budget, "includeThoughts": true })),
+        },
+        cached_content: None,
+    }
+}
+
+/// Gemini takes an OpenAPI-style subset of JSON Schema: upper-case type names and no
+/// `additionalProperties`.
+fn openapi_schema(schema: &Value) -> Value {
+    match schema {
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .filter(|(key, _)| key.as_str() != "additionalProperties")
+                .map(|(key, value)| match (key.as_str(), value) {
+                    ("type", Value::String(t)) => (key.clone(), json!(t.to_uppercase())),
+                    ("properties", Value::Object(properties)) => (
+                        key.clone(),
+                        Value::Object(
+                            properties
+                                .iter()
+                                .map(|(name, s)| (name.clone(), openapi_schema(s)))
+                                .collect(),
+                        ),
+                    ),
+                    _ => (key.clone(), openapi_schema(value)),
+                })
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+fn convert_message(message: &Message) -> Option<GeminiContent> {
+    if message.role == "tool" {
+        return Some(GeminiContent {
+            role: "user",
+            parts: vec![json!({
+                "functionResponse": {
+                    "name": message.name.clone().unwrap_or_default(),
+                    "response": { "content": message.content },
+                },
+            })],
+        });
+    }
+
+    let role = if message.role == "assistant" {
+        "model"
+    } else {
+        "user"
+    };
+
+    let mut parts = Vec::new();
+    if !message.content.trim().is_empty() {
+        parts.push(json!({ "text": message.content }));
+    }
+    for image in &message.images {
+        parts.push(json!({
+            "inlineData": { "mimeType": image.media_type, "data": image.data },
+        }));
+    }
+    for call in &message.tool_calls {
+        parts.push(json!({
+            "functionCall": { "name": call.name, "args": call.arguments },
+        }));
+    }
+
+    (!parts.is_empty()).then_some(GeminiContent { role, parts })
+}
+
+/// Folds one `GenerateContentResponse` (a full response or a single stream chunk)
+/// into `completion`, surfacing prompt blocks and abnormal finish reasons as errors.
+/// When streaming, new text is shown through `shown`.
+pub fn apply_response(
+    response: &Value,
+    completion: &mut Completion,
+    shown: Option<&mut ContentStream>,
+) -> Result<(), GeminiError> {
+    if let Some(reason) = response
+        .pointer("/promptFeedback/blockReason")
+        .and_then(|v| v.as_str())
+    {
+        return Err(GeminiError::PromptBlocked {
+            reason: reason.to_string(),
+        });
+    }
+
+    if let Some(metadata) = response.get("usageMetadata") {
+        completion.usage = usage_from_metadata(metadata);
+    }
+
+    let Some(candidate) = response.pointer("/candidates/0") else {
+        return Ok(());
+    };
+
+    if let Some(parts) = candidate
+        .pointer("/content/parts")
+        .and_then(|v| v.as_array())
+    {
+        collect_parts(parts, completion, shown);
+    }
+
+    match candidate.get("finishReason").and_then(|v| v.as_str()) {
+        None | Some("STOP") | Some("FINISH_REASON_UNSPECIFIED") => Ok(()),
+        // A truncated answer is still an answer; only fail if nothing came back.
+        Some("MAX_TOKENS") if !completion.content.is_empty() => Ok(()),
+        Some(
+            reason @ ("SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII"
+            | "IMAGE_SAFETY"),
+        ) => Err(GeminiError::ContentBlocked {
+            finish_reason: reason.to_string(),
+            categories: blocked_categories(candidate),
+        }),
+        Some(reason) => Err(GeminiError::Finished {
+            finish_reason: reason.to_string(),
+        }),
+    }
+}
+
+/// `promptTokenCount` includes cached tokens; thinking tokens are billed as output.
+fn usage_from_metadata(metadata: &Value) -> Usage {
+    let count = |key: &str| metadata.get(key).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+    let cached = count("cachedContentTokenCount");
+    Usage {
+        input_tokens: count("promptTokenCount").saturating_sub(cached),
+        output_tokens: count("candidatesTokenCount") + count("thoughtsTokenCount"),
+        cached_tokens: cached,
+        reasoning_tokens: count("thoughtsTokenCount"),
+    }
+}
+
+fn blocked_categories(candidate: &Value) -> Vec<String> {
+    candidate
+        .get("safetyRatings")
+        .and_then(|v| v.as_array())
+        .map(|ratings| {
+            ratings
+                .iter()
+                .filter(|r| r.get("blocked").and_then(|v| v.as_bool()) == Some(true))
+                .filter_map(|r| r.get("category")?.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Appends text and function calls from Gemini `parts` to `completion`. Gemini does
+/// not assign call ids, so they are numbered in the order they appear. Thought
+/// summaries are marked `thought` and go to the reasoning instead.
+fn collect_parts(
+    parts: &[Value],
+    completion: &mut Completion,
+    mut shown: Option<&mut ContentStream>,
+) {
+    for part in parts {
+        let text = part.get("text").and_then(|v| v.as_str());
+        if part.get("thought").and_then(|v| v.as_bool()) == Some(true) {
+            completion.reasoning.push_str(text.unwrap_or_default());
+            continue;
+        }
+
+        if let Some(text) = text {
+            completion.content.push_str(text);
+            if let Some(shown) = shown.as_deref_mut() {
+                shown.update(&completion.content);
+            }
+        }
+
+        if let Some(call) = part.get("functionCall") {
+            completion.tool_calls.push(ToolCall {
+                id: format!("call_{}", completion.tool_calls.len()),
+                name: call
+                    .get("name")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                arguments: call.get("args").cloned().unwrap_or_else(|| json!({})),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_roles_and_system_instruction() {
+        let messages = vec![
+            Message::new("system", "You are Crab."),
+            Message::new("system", "Active meeting context:\nnone"),
+            Message::new("user", "hi"),
+            Message::new("assistant", "hello"),
+            Message::new("user", "COMMAND_OUTPUT:\nok"),
+            Message::new("user", "next"),
+        ];
+
+        let body = serde_json::to_value(build_request(
+            &messages,
+            &[],
+            256,
+            None,
+            &Sampling::default(),
+        ))
+        .unwrap();
+
+        assert_eq!(
+            body,
+            json!({
+                "systemInstruction": {
+                    "parts": [
+                        { "text": "You are Crab." },
+                        { "text": "Active meeting context:\nnone" },
+                    ],
+                },
+                "contents": [
+                    { "role": "user", "parts": [{ "text": "hi" }] },
+                    { "role": "model", "parts": [{ "text": "hello" }] },
+                    {
+                        "role": "user",
+                        "parts": [{ "text": "COMMAND_OUTPUT:\nok" }, { "text": "next" }],
+                    },
+                ],
+                "generationConfig": { "maxOutputTokens": 256 },
+            })
+        );
+    }
+
+    #[test]
+    fn requests_json_with_an_openapi_schema_only_without_tools() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "message": { "type": "string" } },
+            "required": ["message"],
+            "additionalProperties": false,
+        });
+        let messages = vec![Message::new("user", "hi")];
+
+        let body = serde_json::to_value(build_request(
+            &messages,
+            &[],
+            256,
+            Some(&schema),
+            &Sampling::default(),
+        ))
+        .unwrap();
+        assert_eq!(
+            body["generationConfig"],
+            json!({
+                "maxOutputTokens": 256,
+                "responseMimeType": "application/json",
+                "responseSchema": {
+                    "type": "OBJECT",
+                    "properties": { "message": { "type": "STRING" } },
+                    "required": ["message"],
+                },
+            })
+        );
+
+        let tool = ToolDefinition {
+            name: "run_terminal",
+            description: "Run a command",
+            parameters: json!({ "type": "object" }),
+        };
+        let body = serde_json::to_value(build_request(
+            &messages,
+            &[tool],
+            256,
+            Some(&schema),
+            &Sampling::default(),
+        ))
+        .unwrap();
+        assert_eq!(body["generationConfig"], json!({ "maxOutputTokens": 256 }));
+    }
+
+    #[test]
+    fn maps_sampling_settings_into_generation_config() {
+        let sampling = Sampling {
+            temperature: Some(0.0),
+            top_p: Some(0.9),
+            seed: Some(42),
+            stop: vec!["END".to_string()],
+            frequency_penalty: Some(0.5),
+            ..Sampling::default()
+        };
+        let body = serde_json::to_value(build_request(
+            &[Message::new("user", "hi")],
+            &[],
+            64,
+            None,
+            &sampling,
+        ))
+        .unwrap();
+
+        assert_eq!(
+            body["generationConfig"],
+            json!({
+                "maxOutputTokens": 64,
+                "temperature": 0.0,
+                "topP": 0.9,
+                "seed": 42,
+                "stopSequences": ["END"],
+                "frequencyPenalty": 0.5,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_text_and_usage_metadata() {
+        let response = json!({
+            "candidates": [{
+                "content": { "role": "model", "parts": [{ "text": "Hello" }, { "text": " there" }] },
+                "finishReason": "STOP",
+            }],
+            "usageMetadata": { "promptTokenCount": 12, "candidatesTokenCount": 3, "totalTokenCount": 15 },
+        });
+
+        let mut completion = Completion::default();
+        apply_response(&response, &mut completion, None).unwrap();
+
+        assert_eq!(completion.content, "Hello there");
+        assert_eq!(
+            completion.usage,
+            Usage {
+                input_tokens: 12,
+                output_tokens: 3,
+                cached_tokens: 0,
+                reasoning_tokens: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn keeps_thought_summaries_out_of_the_answer() {
+        let request = build_request(
+            &[Message::new("user", "hi")],
+            &[],
+            256,
+            None,
+            &Sampling {
+                reasoning_effort: Some("low".to_string()),
+                ..Sampling::default()
+            },
+        );
+        assert_eq!(
+            serde_json::to_value(request).unwrap()["generationConfig"]["thinkingConfig"],
+            json!({ "thinkingBudget": 1024, "includeThoughts": true })
+        );
+
+        let response = json!({
+            "candidates": [{
+                "content": { "role": "model", "parts": [
+                    { "text": "The user greets me; reply in JSON.", "thought": true },
+                    { "text": "{\"message\":\"hi\"}" },
+                ] },
+                "finishReason": "STOP",
+            }],
+            "usageMetadata": { "promptTokenCount": 4, "candidatesTokenCount": 6, "thoughtsTokenCount": 20 },
+        });
+        let mut completion = Completion::default();
+        apply_response(&response, &mut completion, None).unwrap();
+
+        assert_eq!(completion.content, "{\"message\":\"hi\"}");
+        assert_eq!(completion.reasoning, "The user greets me; reply in JSON.");
+        assert_eq!(completion.usage.output_tokens, 26);
+        assert_eq!(completion.usage.reasoning_tokens, 20);
+    }
+
+    #[test]
+    fn surfaces_safety_blocks_as_errors() {
+        let prompt_blocked = json!({ "promptFeedback": { "blockReason": "SAFETY" } });
+        assert_eq!(
+            apply_response(&prompt_blocked, &mut Completion::default(), None),
+            Err(GeminiError::PromptBlocked {
+                reason: "SAFETY".to_string()
+            })
+        );
+
+        let candidate_blocked = json!({
+            "candidates": [{
+                "finishReason": "SAFETY",
+                "safetyRatings": [
+                    { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "probability": "HIGH", "blocked": true },
+                    { "category": "HARM_CATEGORY_HARASSMENT", "probability": "NEGLIGIBLE" },
+                ],
+            }],
+        });
+        assert_eq!(
+            apply_response(&candidate_blocked, &mut Completion::default(), None),
+            Err(GeminiError::ContentBlocked {
+                finish_reason: "SAFETY".to_string(),
+                categories: vec!["HARM_CATEGORY_DANGEROUS_CONTENT".to_string()],
+            })
+        );
+    }
+}
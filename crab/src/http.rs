@@ -0,0 +1,195 @@
+//! HTTP client settings for deployments behind proxies and private CAs:
+//!
+//! - `LLM_CONNECT_TIMEOUT` (default 10), `LLM_READ_TIMEOUT` (off) and `LLM_TIMEOUT`
+//!   (default 120), in seconds; `0` turns the read and total timeouts off. The read
+//!   timeout bounds the wait for response headers and for each streamed chunk.
+//! - `HTTPS_PROXY`, `HTTP_PROXY` and `ALL_PROXY`, with `NO_PROXY` exceptions.
+//! - `LLM_CA_BUNDLE`: PEM file of extra CA certificates to trust.
+//! - `LLM_CLIENT_CERT` and `LLM_CLIENT_KEY`: PEM certificate chain and PKCS#8 key for
+//!   mutual TLS.
+
+use crate::error::CrabError;
+use reqwest::{Certificate, Client, Identity, NoProxy, Proxy};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    pub read_timeout: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub https_proxy: Option<String>,
+    pub http_proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    pub ca_bundle: Option<PathBuf>,
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+fn read_file(path: &PathBuf, what: &str) -> Result<Vec<u8>, CrabError> {
+    fs::read(path)
+        .map_err(|e| CrabError::Config(format!("Cannot read {} {}: {}", what, path.display(), e)))
+}
+
+impl HttpConfig {
+    pub fn from_env() -> Result<Self, CrabError> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    /// Reads the settings through `lookup`. Proxy variables are also accepted in lower
+    /// case, as curl does. Values that do not parse are errors rather than warnings:
+    /// a half-applied network setup fails later in ways that are harder to read.
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, CrabError> {
+        let var = |key: &str| lookup(key).filter(|v| !v.trim().is_empty());
+        let proxy_var = |key: &str| var(key).or_else(|| var(&key.to_lowercase()));
+        let seconds = |key: &str| -> Result<Option<Duration>, CrabError> {
+            var(key)
+                .map(|v| {
+                    v.trim()
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|s| *s >= 0.0)
+                        .ok_or_else(|| {
+                            CrabError::Config(format!(
+                                "{} must be a number of seconds, got '{}'",
+                                key, v
+                            ))
+                        })
+                })
+                .transpose()
+                .map(|secs| secs.map(Duration::from_secs_f64))
+        };
+        let disabled_if_zero = |timeout: Option<Duration>, default: Option<Duration>| match timeout
+        {
+            Some(timeout) if timeout.is_zero() => None,
+            Some(timeout) => Some(timeout),
+            None => default,
+        };
+
+        let client_cert = match (var("LLM_CLIENT_CERT"), var("LLM_CLIENT_KEY")) {
+            (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            (None, None) => None,
+            _ => {
+                return Err(CrabError::Config(
+                    "LLM_CLIENT_CERT and LLM_CLIENT_KEY must be set together".to_string(),
+                ))
+            }
+        };
+
+        Ok(Self {
+            connect_timeout: seconds("LLM_CONNECT_TIMEOUT")?
+                .filter(|t| !t.is_zero())
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            read_timeout: disabled_if_zero(seconds("LLM_READ_TIMEOUT")?, None),
+            timeout: disabled_if_zero(seconds("LLM_TIMEOUT")?, Some(DEFAULT_TIMEOUT)),
+            https_proxy: proxy_var("HTTPS_PROXY").or_else(|| proxy_var("ALL_PROXY")),
+            http_proxy: proxy_var("HTTP_PROXY").or_else(|| proxy_var("ALL_PROXY")),
+            no_proxy: proxy_var("NO_PROXY"),
+            ca_bundle: var("LLM_CA_BUNDLE").map(PathBuf::from),
+            client_cert,
+        })
+    }
+
+    /// Builds the client, reporting unreadable files, bad certificates and bad proxy
+    /// URLs as configuration errors.
+    pub fn build_client(&self) -> Result<Client, CrabError> {
+        let mut builder = Client::builder().connect_timeout(self.connect_timeout);
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        let no_proxy = || self.no_proxy.as_deref().and_then(NoProxy::from_string);
+        let proxy_error =
+            |var: &str, e: reqwest::Error| CrabError::Config(format!("Invalid {}: {}", var, e));
+        if let Some(url) = &self.https_proxy {
+            let proxy = Proxy::https(url).map_err(|e| proxy_error("HTTPS_PROXY", e))?;
+            builder = builder.proxy(proxy.no_proxy(no_proxy()));
+        }
+        if let Some(url) = &self.http_proxy {
+            let proxy = Proxy::http(url).map_err(|e| proxy_error("HTTP_PROXY", e))?;
+            builder = builder.proxy(proxy.no_proxy(no_proxy()));
+        }
+
+        if let Some(path) = &self.ca_bundle {
+            let certificates = Certificate::from_pem_bundle(&read_file(path, "CA bundle")?)
+                .map_err(|e| {
+                    CrabError::Config(format!("Invalid CA bundle {}: {}", path.display(), e))
+                })?;
+            if certificates.is_empty() {
+                return Err(CrabError::Config(format!(
+                    "No certificates in CA bundle {}",
+                    path.display()
+                )));
+            }
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        if let Some((cert, key)) = &self.client_cert {
+            let identity = Identity::from_pkcs8_pem(
+                &read_file(cert, "client certificate")?,
+                &read_file(key, "client key")?,
+            )
+            .map_err(|e| CrabError::Config(format!("Invalid client certificate: {}", e)))?;
+            builder = builder.identity(identity);
+        }
+
+        builder
+            .build()
+            .map_err(|e| CrabError::Config(format!("Failed to create HTTP client: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(vars: &[(&str, &str)]) -> Result<HttpConfig, CrabError> {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        HttpConfig::from_lookup(|key| vars.get(key).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn reads_timeouts_and_proxies_and_reports_bad_settings() {
+        let defaults = config(&[]).unwrap();
+        assert_eq!(defaults.connect_timeout, DEFAULT_CONNECT_TIMEOUT);
+        assert_eq!(defaults.read_timeout, None);
+        assert_eq!(defaults.timeout, Some(DEFAULT_TIMEOUT));
+        assert!(defaults.build_client().is_ok());
+
+        let configured = config(&[
+            ("LLM_READ_TIMEOUT", "30"),
+            ("LLM_TIMEOUT", "0"),
+            ("https_proxy", "http://proxy.corp:3128"),
+            ("NO_PROXY", "172.17.0.1,.internal"),
+        ])
+        .unwrap();
+        assert_eq!(configured.read_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(configured.timeout, None);
+        assert_eq!(
+            configured.https_proxy.as_deref(),
+            Some("http://proxy.corp:3128")
+        );
+        assert_eq!(configured.http_proxy, None);
+        assert!(configured.build_client().is_ok());
+
+        let error = |vars: &[(&str, &str)]| match config(vars).and_then(|c| c.build_client()) {
+            Err(CrabError::Config(message)) => message,
+            other => panic!("expected a config error, got {:?}", other.map(|_| ())),
+        };
+        assert!(error(&[("LLM_TIMEOUT", "soon")]).contains("LLM_TIMEOUT"));
+        assert!(error(&[("LLM_CLIENT_CERT", "/certs/crab.pem")]).contains("LLM_CLIENT_KEY"));
+        assert!(error(&[("LLM_CA_BUNDLE", "/nonexistent/ca.pem")]).contains("/nonexistent/ca.pem"));
+
+        let garbage = env::temp_dir().join(format!("crab-ca-{}.pem", std::process::id()));
+        fs::write(&garbage, "not a certificate").unwrap();
+        assert!(error(&[("LLM_CA_BUNDLE", garbage.to_str().unwrap())]).contains("CA bundle"));
+        let _ = fs::remove_file(&garbage);
+    }
+}
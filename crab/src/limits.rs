@@ -0,0 +1,50 @@
+use serde::Deserialize;
+use std::env;
+use std::fs;
+
+const DEFAULT_LIMITS_FILE: &str = "/app/config/limits.json";
+
+/// Operator limits from `config/limits.json`, the same file the orchestrator reads.
+/// The orchestrator also passes them as environment variables, which win over the
+/// file because the config directory is not mounted into every cubicle.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Limits {
+    pub max_history_messages: usize,
+    /// Tokens this agent may spend per UTC day, across runs.
+    pub max_tokens_per_day: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_history_messages: 10,
+            max_tokens_per_day: None,
+        }
+    }
+}
+
+impl Limits {
+    pub fn load() -> Self {
+        let path = env::var("LIMITS_FILE").unwrap_or_else(|_| DEFAULT_LIMITS_FILE.to_string());
+        let mut limits: Limits = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+
+        if let Some(n) = env::var("MAX_HISTORY_MESSAGES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            limits.max_history_messages = n;
+        }
+        if let Some(n) = env::var("MAX_TOKENS_PER_DAY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            limits.max_tokens_per_day = Some(n).filter(|&n| n > 0);
+        }
+
+        limits
+    }
+}
@@ -1,26 +1,223 @@
-use reqwest::blocking::Client;
+use crate::anthropic;
+use crate::cassette::{Cassette, CassetteMode};
+use crate::context::{message_tokens, ModelFamily};
+use crate::embeddings;
+use crate::error::CrabError;
+use crate::gemini;
+use crate::http::HttpConfig;
+use crate::mock::MockProvider;
+use crate::models::{self, Capability, ModelInfo};
+use crate::prompt_cache::{self, CacheRegistry, Cached, GEMINI_CACHE_TTL};
+use crate::rate_limit::RateLimiter;
+use crate::retry::{self, RetryPolicy};
+use crate::sampling::Sampling;
+use crate::stream::{self, read_sse, ContentStream};
+use reqwest::{Client, Method, RequestBuilder, Response};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
     pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// Name of the tool a `tool` message answers; Gemini matches results by name, not id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Images sent along with `content`, for vision models.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImagePart>,
+    /// Anthropic's signed thinking blocks, sent back unchanged with the tool calls
+    /// they led to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thinking: Vec<Value>,
+}
+
+impl Message {
+    pub fn new(role: &str, content: impl Into<String>) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_images(role: &str, content: impl Into<String>, images: Vec<ImagePart>) -> Self {
+        Self {
+            images,
+            ..Self::new(role, content)
+        }
+    }
+
+    pub fn tool_result(call: &ToolCall, content: impl Into<String>) -> Self {
+        Self {
+            role: "tool".to_string(),
+            content: content.into(),
+            tool_call_id: Some(call.id.clone()),
+            name: Some(call.name.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+/// A base64-encoded image, already within the size limits (see `vision`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImagePart {
+    pub media_type: String,
+    pub data: String,
+}
+
+impl ImagePart {
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+/// A function the model may call, described with a JSON Schema for its arguments.
+#[derive(Debug, Clone)]
+pub struct ToolDefinition {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub parameters: Value,
+}
+
+/// Token counts for one completion. `input_tokens` excludes prompt tokens served
+/// from the provider's cache; those are in `cached_tokens` and billed at their own rate.
+/// `reasoning_tokens` is the part of `output_tokens` spent thinking.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub cached_tokens: u32,
+    pub reasoning_tokens: u32,
+}
+
+impl Usage {
+    pub fn total(&self) -> u32 {
+        self.input_tokens + self.output_tokens + self.cached_tokens
+    }
+
+    pub fn add(&mut self, other: &Usage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cached_tokens += other.cached_tokens;
+        self.reasoning_tokens += other.reasoning_tokens;
+    }
+
+    /// From an OpenAI-style `usage` object, where `prompt_tokens` includes cached
+    /// tokens (`prompt_cache_hit_tokens` on DeepSeek). Servers that only report
+    /// `total_tokens` have it counted as input.
+    pub fn from_openai(usage: &Value) -> Self {
+        let count = |pointer: &str| usage.pointer(pointer).and_then(|v| v.as_u64());
+        let cached = count("/prompt_tokens_details/cached_tokens")
+            .or_else(|| count("/prompt_cache_hit_tokens"))
+            .unwrap_or(0) as u32;
+
+        match (count("/prompt_tokens"), count("/completion_tokens")) {
+            (None, None) => Self {
+                input_tokens: count("/total_tokens").unwrap_or(0) as u32,
+                ..Default::default()
+            },
+            (prompt, completion) => Self {
+                input_tokens: (prompt.unwrap_or(0) as u32).saturating_sub(cached),
+                output_tokens: completion.unwrap_or(0) as u32,
+                cached_tokens: cached,
+                reasoning_tokens: count("/completion_tokens_details/reasoning_tokens").unwrap_or(0)
+                    as u32,
+            },
+        }
+    }
+
+    /// From an Anthropic `usage` object. Cache writes are billed like input.
+    pub fn from_anthropic(usage: &Value) -> Self {
+        let count = |key: &str| usage.get(key).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+        Self {
+            input_tokens: count("input_tokens") + count("cache_creation_input_tokens"),
+            output_tokens: count("output_tokens"),
+            cached_tokens: count("cache_read_input_tokens"),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Completion {
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+    /// The model's reasoning, kept apart from `content` so it is never read as the reply.
+    pub reasoning: String,
+    /// Anthropic thinking blocks to replay with `tool_calls`; see `Message::thinking`.
+    pub thinking: Vec<Value>,
+    pub usage: Usage,
+    /// The provider and model that actually produced this completion.
+    pub provider: String,
+    pub model: String,
 }
 
 #[derive(Debug, Serialize)]
 struct ChatRequest {
     model: String,
-    messages: Vec<Message>,
+    messages: Vec<Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     max_tokens: Option<u32>,
+    /// OpenAI reasoning models reject `max_tokens`, which would not cover their reasoning.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    /// Mistral's name for `seed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    random_seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f64>,
+    /// OpenRouter provider routing preferences.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_effort: Option<String>,
+    /// OpenRouter's reasoning settings, in place of `reasoning_effort`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning: Option<Value>,
+    /// Helps OpenAI route requests sharing a prompt prefix to the same cache.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_cache_key: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct ChatResponse {
     choices: Vec<Choice>,
-    usage: Option<Usage>,
+    usage: Option<Value>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,327 +227,1432 @@ struct Choice {
 
 #[derive(Debug, Deserialize)]
 struct ResponseMessage {
-    content: String,
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<OpenAIToolCall>,
+    /// DeepSeek's reasoning.
+    reasoning_content: Option<String>,
+    /// OpenRouter's name for it.
+    reasoning: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
-struct Usage {
-    #[serde(rename = "total_tokens")]
-    total_tokens: Option<u32>,
+struct OpenAIToolCall {
+    id: String,
+    function: OpenAIFunctionCall,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIFunctionCall {
+    name: String,
+    arguments: String,
 }
 
 #[derive(Clone)]
 pub struct LLMClient {
     client: Client,
+    targets: Vec<ProviderTarget>,
+    stream: bool,
+    retry: RetryPolicy,
+    agent_id: i32,
+    /// Set when a target uses the scripted `mock` provider.
+    mock: Option<Arc<MockProvider>>,
+    cassette: Option<Arc<Cassette>>,
+    /// JSON Schema the reply must follow, for providers with a structured-output mode.
+    response_schema: Option<Value>,
+    sampling: Sampling,
+    /// Marks the system prompt as cacheable where the provider needs to be told.
+    prompt_cache: bool,
+    gemini_caches: CacheRegistry,
+    /// Shared with the other crab processes in the cubicle; see `rate_limit`.
+    rate_limiter: Option<RateLimiter>,
+    /// `LLM_EMBEDDING_MODEL`; the provider's default embedding model when unset.
+    embedding_model: Option<String>,
+    /// How long to wait for response headers or the next streamed chunk.
+    read_timeout: Option<Duration>,
+}
+
+/// Default API base URL for each built-in provider. `openai-compatible` and
+/// `proxy` have no fixed endpoint and return `None`.
+pub fn get_provider_config(provider: &str) -> Option<&'static str> {
+    match provider {
+        "openai" => Some("https://api.openai.com/v1"),
+        "anthropic" => Some("https://api.anthropic.com/v1"),
+        "google" => Some("https://generativelanguage.googleapis.com/v1beta"),
+        "groq" => Some("https://api.groq.com/openai/v1"),
+        "openrouter" => Some("https://openrouter.ai/api/v1"),
+        "mistral" => Some("https://api.mistral.ai/v1"),
+        "deepseek" => Some("https://api.deepseek.com/v1"),
+        "xai" => Some("https://api.x.ai/v1"),
+        "ollama" => Some("http://localhost:11434/v1"),
+        _ => None,
+    }
+}
+
+/// Tool arguments arrive as a JSON-encoded string on OpenAI-compatible APIs. Keep the
+/// raw string when it does not parse so the agent loop can report the bad call back.
+fn parse_tool_arguments(raw: &str) -> Value {
+    if raw.trim().is_empty() {
+        return json!({});
+    }
+    serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()))
+}
+
+fn openai_message(message: &Message) -> Value {
+    let mut value = json!({
+        "role": message.role,
+        "content": message.content,
+    });
+
+    if !message.images.is_empty() {
+        let mut parts = vec![json!({ "type": "text", "text": message.content })];
+        parts.extend(
+            message.images.iter().map(
+                |image| json!({ "type": "image_url", "image_url": { "url": image.data_url() } }),
+            ),
+        );
+        value["content"] = Value::Array(parts);
+    }
+
+    if !message.tool_calls.is_empty() {
+        value["tool_calls"] = message
+            .tool_calls
+            .iter()
+            .map(|call| {
+                let arguments = match &call.arguments {
+                    Value::String(raw) => raw.clone(),
+                    other => other.to_string(),
+                };
+                json!({
+                    "id": call.id,
+                    "type": "function",
+                    "function": { "name": call.name, "arguments": arguments },
+                })
+            })
+            .collect();
+    }
+
+    if let Some(id) = &message.tool_call_id {
+        value["tool_call_id"] = json!(id);
+    }
+
+    value
+}
+
+/// Turns the leading system message into a text part with a cache breakpoint.
+fn mark_system_prompt_cacheable(messages: &mut [Value]) {
+    let Some(system) = messages.first_mut().filter(|m| m["role"] == "system") else {
+        return;
+    };
+    let text = system["content"].take();
+    system["content"] = json!([
+        { "type": "text", "text": text, "cache_control": { "type": "ephemeral" } },
+    ]);
+}
+
+fn openai_response_format(provider: &str, schema: &Value) -> Value {
+    match provider {
+        "openai" | "azure" | "openrouter" | "xai" | "ollama" => json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response_contract", "strict": true, "schema": schema },
+        }),
+        _ => json!({ "type": "json_object" }),
+    }
+}
+
+/// Environment variable holding each provider's API key, and the model used when
+/// `LLM_MODEL` is not set.
+fn provider_defaults(provider: &str) -> (Option<&'static str>, &'static str) {
+    match provider {
+        "openai" => (Some("OPENAI_API_KEY"), "gpt-4o"),
+        "azure" => (Some("AZURE_OPENAI_API_KEY"), "gpt-4o"),
+        "anthropic" => (Some("ANTHROPIC_API_KEY"), "claude-3-5-sonnet-20241022"),
+        "google" => (Some("GOOGLE_API_KEY"), "gemini-1.5-pro"),
+        "groq" => (Some("GROQ_API_KEY"), "llama-3.3-70b-versatile"),
+        "openrouter" => (Some("OPENROUTER_API_KEY"), "anthropic/claude-3.5-sonnet"),
+        "mistral" => (Some("MISTRAL_API_KEY"), "mistral-large-latest"),
+        "deepseek" => (Some("DEEPSEEK_API_KEY"), "deepseek-chat"),
+        "xai" => (Some("XAI_API_KEY"), "grok-beta"),
+        // The orchestrator picks the real provider and model; the token only proves
+        // which container is asking.
+        "proxy" => (Some("LLM_PROXY_TOKEN"), "default"),
+        // Local servers usually need no key; one is sent only if configured.
+        "ollama" => (Some("OLLAMA_API_KEY"), "llama3.1"),
+        "openai-compatible" => (Some("OPENAI_COMPATIBLE_API_KEY"), "default"),
+        // Canned replies from a script file; see `mock`.
+        "mock" => (None, "scripted"),
+        _ => (None, "auto"),
+    }
+}
+
+/// Local inference servers usually run without authentication, and providers with
+/// no key variable never need one.
+fn key_optional(provider: &str) -> bool {
+    provider_defaults(provider).0.is_none() || matches!(provider, "ollama" | "openai-compatible")
+}
+
+/// One provider/model pair the client can send a completion to.
+#[derive(Debug, Clone)]
+pub struct ProviderTarget {
+    pub provider: String,
+    pub model: String,
     api_key: String,
-    provider: String,
-    model: String,
+    base_url: Option<String>,
+    extra_headers: Vec<(String, String)>,
+    /// Sent as the `api-version` query parameter; only Azure uses one.
+    api_version: Option<String>,
 }
 
-pub fn get_provider_config(provider: &str) -> (&'static str, &'static str) {
+/// Azure OpenAI API version used when `AZURE_OPENAI_API_VERSION` is not set.
+const AZURE_API_VERSION: &str = "2024-10-21";
+
+/// Azure serves each deployment under its own path on the resource endpoint, e.g.
+/// `https://my-resource.openai.azure.com/openai/deployments/gpt-4o-prod`.
+fn azure_base_url(endpoint: &str, deployment: &str) -> String {
+    format!(
+        "{}/openai/deployments/{}",
+        endpoint.trim_end_matches('/'),
+        deployment
+    )
+}
+
+fn non_empty_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn provider_base_url(provider: &str, model: &str) -> Option<String> {
     match provider {
-        "openai" => ("https://api.openai.com/v1/chat/completions", "Bearer"),
-        "anthropic" => ("https://api.anthropic.com/v1/messages", "x-api-key"),
-        "google" => (
-            "https://generativelanguage.googleapis.com/v1beta/models",
-            "Key",
+        // The deployment defaults to the model name, which is how most are named.
+        "azure" => non_empty_var("AZURE_OPENAI_ENDPOINT").map(|endpoint| {
+            let deployment =
+                non_empty_var("AZURE_OPENAI_DEPLOYMENT").unwrap_or_else(|| model.to_string());
+            azure_base_url(&endpoint, &deployment)
+        }),
+        "proxy" => Some(
+            env::var("ORCHESTRATOR_URL").unwrap_or_else(|_| "http://172.17.0.1:3000".to_string()),
         ),
-        "groq" => ("https://api.groq.com/openai/v1/chat/completions", "Bearer"),
-        "openrouter" => ("https://openrouter.ai/api/v1/chat/completions", "Bearer"),
-        "mistral" => ("https://api.mistral.ai/v1/chat/completions", "Bearer"),
-        "deepseek" => ("https://api.deepseek.com/v1/chat/completions", "Bearer"),
-        "xai" => ("https://api.x.ai/v1/chat/completions", "Bearer"),
-        _ => ("https://openrouter.ai/api/v1/chat/completions", "Bearer"),
+        "ollama" => env::var("OLLAMA_HOST")
+            .ok()
+            .filter(|host| !host.is_empty())
+            .map(|host| format!("{}/v1", host.trim_end_matches('/')))
+            .or_else(|| get_provider_config(provider).map(str::to_string)),
+        _ => get_provider_config(provider).map(str::to_string),
     }
 }
 
-impl LLMClient {
-    pub fn new() -> Self {
-        let provider = env::var("LLM_PROVIDER").unwrap_or_else(|_| "openrouter".to_string());
+fn api_version(provider: &str) -> Option<String> {
+    (provider == "azure").then(|| {
+        non_empty_var("AZURE_OPENAI_API_VERSION").unwrap_or_else(|| AZURE_API_VERSION.to_string())
+    })
+}
 
-        let (api_key, model) = match provider.as_str() {
-            "openai" => (
-                env::var("OPENAI_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "gpt-4o".to_string()),
-            ),
-            "anthropic" => (
-                env::var("ANTHROPIC_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "claude-3-5-sonnet-20241022".to_string()),
-            ),
-            "google" => (
-                env::var("GOOGLE_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "gemini-1.5-pro".to_string()),
-            ),
-            "groq" => (
-                env::var("GROQ_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "llama-3.3-70b-versatile".to_string()),
-            ),
-            "openrouter" => (
-                env::var("OPENROUTER_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "anthropic/claude-3.5-sonnet".to_string()),
-            ),
-            "mistral" => (
-                env::var("MISTRAL_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "mistral-large-latest".to_string()),
-            ),
-            "deepseek" => (
-                env::var("DEEPSEEK_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "deepseek-chat".to_string()),
-            ),
-            "xai" => (
-                env::var("XAI_API_KEY")
-                    .or_else(|_| env::var("LLM_API_KEY"))
-                    .unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "grok-beta".to_string()),
-            ),
-            _ => (
-                env::var("LLM_API_KEY").unwrap_or_default(),
-                env::var("LLM_MODEL").unwrap_or_else(|_| "auto".to_string()),
-            ),
-        };
+/// Parses `LLM_HEADERS`, a JSON object of extra headers sent with every request to
+/// the primary provider, e.g. `{"X-Team": "research"}`.
+pub fn parse_extra_headers(spec: &str) -> Result<Vec<(String, String)>, String> {
+    if spec.trim().is_empty() {
+        return Ok(Vec::new());
+    }
 
-        let client = Client::builder()
-            .timeout(std::time::Duration::from_secs(120))
-            .build()
-            .expect("Failed to create HTTP client");
+    let parsed: serde_json::Map<String, Value> =
+        serde_json::from_str(spec).map_err(|e| format!("Invalid LLM_HEADERS: {}", e))?;
+
+    Ok(parsed
+        .into_iter()
+        .map(|(name, value)| match value {
+            Value::String(v) => (name, v),
+            other => (name, other.to_string()),
+        })
+        .collect())
+}
+
+impl ProviderTarget {
+    /// The configured provider. It also accepts the generic `LLM_API_KEY` and `LLM_MODEL`.
+    fn primary() -> Self {
+        let provider = env::var("LLM_PROVIDER").unwrap_or_else(|_| "openrouter".to_string());
+        let (key_var, default_model) = provider_defaults(&provider);
+
+        let api_key = key_var
+            .and_then(|var| env::var(var).ok())
+            .or_else(|| env::var("LLM_API_KEY").ok())
+            .unwrap_or_default();
+        let model = env::var("LLM_MODEL").unwrap_or_else(|_| default_model.to_string());
+        let base_url = env::var("LLM_BASE_URL")
+            .ok()
+            .filter(|url| !url.is_empty())
+            .or_else(|| provider_base_url(&provider, &model));
+        let extra_headers = parse_extra_headers(&env::var("LLM_HEADERS").unwrap_or_default())
+            .unwrap_or_else(|e| {
+                eprintln!("Warning: {}", e);
+                Vec::new()
+            });
 
         Self {
-            client,
-            api_key,
+            api_version: api_version(&provider),
             provider,
             model,
+            api_key,
+            base_url,
+            extra_headers,
+        }
+    }
+
+    /// A fallback entry. Only the provider's own key variable is consulted, since
+    /// `LLM_API_KEY` belongs to the primary provider. Fails with the reason the entry
+    /// cannot be used.
+    fn fallback(provider: &str, model: Option<&str>) -> Result<Self, String> {
+        let (key_var, default_model) = provider_defaults(provider);
+        let api_key = key_var
+            .and_then(|var| env::var(var).ok())
+            .unwrap_or_default();
+        if api_key.is_empty() && !key_optional(provider) {
+            return Err(format!("{} is not set", key_var.unwrap_or("no API key")));
+        }
+        let model = model.unwrap_or(default_model);
+
+        Ok(Self {
+            provider: provider.to_string(),
+            model: model.to_string(),
+            api_key,
+            base_url: provider_base_url(provider, model),
+            extra_headers: Vec::new(),
+            api_version: api_version(provider),
+        })
+    }
+}
+
+/// Parses `LLM_FALLBACKS`, e.g. `anthropic:claude-3-5-haiku-latest,groq`. The model
+/// part is optional; everything after the first `:` is the model name.
+pub fn parse_fallbacks(spec: &str) -> Vec<(String, Option<String>)> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once(':') {
+            Some((provider, model)) if !model.trim().is_empty() => {
+                (provider.trim().to_string(), Some(model.trim().to_string()))
+            }
+            Some((provider, _)) => (provider.trim().to_string(), None),
+            None => (entry.to_string(), None),
+        })
+        .collect()
+}
+
+impl LLMClient {
+    /// Fails when the HTTP client cannot be built from the `http` settings.
+    pub fn new() -> Result<Self, CrabError> {
+        let mut targets = vec![ProviderTarget::primary()];
+
+        let fallbacks = env::var("LLM_FALLBACKS").unwrap_or_default();
+        for (provider, model) in parse_fallbacks(&fallbacks) {
+            match ProviderTarget::fallback(&provider, model.as_deref()) {
+                Ok(target) => targets.push(target),
+                Err(reason) => eprintln!(
+                    "Warning: Skipping fallback provider '{}': {}",
+                    provider, reason
+                ),
+            }
+        }
+
+        let http = HttpConfig::from_env()?;
+        let client = http.build_client()?;
+
+        let stream = env::var("LLM_STREAM").unwrap_or_else(|_| "false".to_string()) == "true";
+        let agent_id = env::var("AGENT_ID")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+
+        let mock = targets
+            .iter()
+            .any(|t| t.provider == "mock")
+            .then(|| Arc::new(MockProvider::from_env()));
+
+        Ok(Self {
+            client,
+            targets,
+            stream,
+            retry: RetryPolicy::from_env(),
+            agent_id,
+            mock,
+            cassette: None,
+            response_schema: None,
+            sampling: Sampling::load(),
+            prompt_cache: prompt_cache::enabled(),
+            gemini_caches: CacheRegistry::from_env(),
+            rate_limiter: RateLimiter::from_env(),
+            embedding_model: non_empty_var("LLM_EMBEDDING_MODEL"),
+            read_timeout: http.read_timeout,
+        })
+    }
+
+    /// A client whose completions are constrained to `schema` where the provider
+    /// supports it: a strict JSON schema on OpenAI, Azure, OpenRouter, xAI and Ollama,
+    /// plain JSON mode on the other OpenAI-compatible APIs, and `responseSchema` on
+    /// Gemini when no tools are declared. Anthropic has no such mode and relies on the
+    /// prompt.
+    pub fn with_response_schema(&self, schema: Value) -> Self {
+        Self {
+            response_schema: Some(schema),
+            ..self.clone()
         }
     }
 
-    pub fn complete(&self, messages: &[Message], max_tokens: u32) -> Result<(String, u32), String> {
-        if self.provider == "google" {
-            return self.complete_google(messages, max_tokens);
+    /// Records every completion to `cassette`, or answers from it when replaying.
+    pub fn with_cassette(mut self, cassette: Option<Cassette>) -> Self {
+        self.cassette = cassette.map(Arc::new);
+        self
+    }
+
+    fn replaying(&self) -> Option<&Cassette> {
+        self.cassette
+            .as_deref()
+            .filter(|c| c.mode() == CassetteMode::Replay)
+    }
+
+    /// The primary provider, as configured by `LLM_PROVIDER`.
+    pub fn primary(&self) -> &ProviderTarget {
+        &self.targets[0]
+    }
+
+    pub fn has_api_key(&self) -> bool {
+        let primary = self.primary();
+        self.replaying().is_some() || !primary.api_key.is_empty() || key_optional(&primary.provider)
+    }
+
+    fn base_url<'a>(&self, target: &'a ProviderTarget) -> Result<&'a str, CrabError> {
+        target
+            .base_url
+            .as_deref()
+            .map(|url| url.trim_end_matches('/'))
+            .ok_or_else(|| {
+                let setting = match target.provider.as_str() {
+                    "azure" => "AZURE_OPENAI_ENDPOINT",
+                    _ => "LLM_BASE_URL",
+                };
+                CrabError::Config(format!(
+                    "No base URL for LLM provider '{}': set {}",
+                    target.provider, setting
+                ))
+            })
+    }
+
+    /// Starts a request with the target's auth header and custom headers. No auth
+    /// header is sent when no key is configured.
+    fn request(&self, method: Method, target: &ProviderTarget, url: &str) -> RequestBuilder {
+        let mut request = self.client.request(method, url);
+
+        if !target.api_key.is_empty() {
+            request = match target.provider.as_str() {
+                "anthropic" => request.header("x-api-key", &target.api_key),
+                "azure" => request.header("api-key", &target.api_key),
+                "google" => request.header("x-goog-api-key", &target.api_key),
+                _ => request.header("Authorization", format!("Bearer {}", target.api_key)),
+            };
         }
 
-        if self.provider == "anthropic" {
-            return self.complete_anthropic(messages, max_tokens);
+        for (name, value) in &target.extra_headers {
+            request = request.header(name.as_str(), value.as_str());
         }
 
-        let (url, auth_prefix) = get_provider_config(&self.provider);
+        if let Some(version) = &target.api_version {
+            request = request.query(&[("api-version", version)]);
+        }
+
+        request
+    }
+
+    /// Lists the models the primary provider serves, via the OpenAI-style
+    /// `GET /models` endpoint that Ollama, vLLM and llama.cpp also implement.
+    pub async fn list_models(&self) -> Result<Vec<String>, CrabError> {
+        let target = self.primary();
+        let url = format!("{}/models", self.base_url(target)?);
+
+        let body: Value = self
+            .send(target, self.request(Method::GET, target, &url))
+            .await?
+            .json()
+            .await
+            .map_err(parse_error)?;
+
+        let entries = body
+            .get("data")
+            .or_else(|| body.get("models"))
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| {
+                CrabError::InvalidResponse("Unexpected model list format".to_string())
+            })?;
+
+        Ok(entries
+            .iter()
+            .filter_map(|m| m.get("id").or_else(|| m.get("name"))?.as_str())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// The model `embed` uses. `None` when the primary provider has no embeddings
+    /// endpoint or completions are replayed from a cassette.
+    pub fn embedding_model(&self) -> Option<String> {
+        let provider = self.primary().provider.as_str();
+        if self.replaying().is_some() || matches!(provider, "proxy" | "mock" | "anthropic") {
+            return None;
+        }
+        self.embedding_model
+            .clone()
+            .or_else(|| embeddings::default_model(provider).map(str::to_string))
+    }
+
+    /// Embeds `texts` with the primary provider, one vector per text in order. Gemini
+    /// has `batchEmbedContents`; the others the OpenAI-style `POST /embeddings`.
+    pub async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, CrabError> {
+        let target = self.primary();
+        let model = self.embedding_model().ok_or_else(|| {
+            CrabError::Config(format!(
+                "LLM provider '{}' has no embeddings endpoint",
+                target.provider
+            ))
+        })?;
+        let base_url = self.base_url(target)?;
+
+        if target.provider == "google" {
+            let url = format!("{}/models/{}:batchEmbedContents", base_url, model);
+            let requests: Vec<Value> = texts
+                .iter()
+                .map(|text| {
+                    json!({
+                        "model": format!("models/{}", model),
+                        "content": { "parts": [{ "text": text }] },
+                    })
+                })
+                .collect();
+            let body: Value = self
+                .send(
+                    target,
+                    self.request(Method::POST, target, &url)
+                        .json(&json!({ "requests": requests })),
+                )
+                .await?
+                .json()
+                .await
+                .map_err(parse_error)?;
+            return body["embeddings"]
+                .as_array()
+                .ok_or_else(|| unexpected_embeddings(&body))?
+                .iter()
+                .map(|embedding| embedding_vector(&embedding["values"]))
+                .collect();
+        }
+
+        let url = match target.provider.as_str() {
+            // Embedding models are deployed separately from the chat deployment.
+            "azure" => {
+                let deployment = non_empty_var("AZURE_OPENAI_EMBEDDING_DEPLOYMENT")
+                    .unwrap_or_else(|| model.clone());
+                let deployments = base_url.rsplit_once('/').map_or(base_url, |(p, _)| p);
+                format!("{}/{}/embeddings", deployments, deployment)
+            }
+            _ => format!("{}/embeddings", base_url),
+        };
+        let body: Value = self
+            .send(
+                target,
+                self.request(Method::POST, target, &url)
+                    .json(&json!({ "model": model, "input": texts })),
+            )
+            .await?
+            .json()
+            .await
+            .map_err(parse_error)?;
+
+        let mut data = body["data"]
+            .as_array()
+            .cloned()
+            .ok_or_else(|| unexpected_embeddings(&body))?;
+        data.sort_by_key(|entry| entry["index"].as_u64().unwrap_or(0));
+        data.iter()
+            .map(|entry| embedding_vector(&entry["embedding"]))
+            .collect()
+    }
+
+    /// What the model registry knows about the primary model.
+    pub fn model_info(&self) -> ModelInfo {
+        let primary = self.primary();
+        models::lookup(&primary.provider, &primary.model)
+    }
+
+    /// Fails when the registry says the primary model lacks `capability`.
+    pub fn require(&self, capability: Capability) -> Result<(), CrabError> {
+        if self.model_info().supports(capability) {
+            return Ok(());
+        }
+        let primary = self.primary();
+        Err(models::unsupported(
+            &primary.provider,
+            &primary.model,
+            capability,
+        ))
+    }
+
+    /// The orchestrator proxy only relays plain chat messages, so native tools are
+    /// declared only when talking to a provider directly, and only to models that
+    /// can call them.
+    pub fn supports_tools(&self) -> bool {
+        self.targets.iter().all(|t| {
+            t.provider != "proxy"
+                && models::lookup(&t.provider, &t.model).supports(Capability::Tools)
+        })
+    }
+
+    /// The response schema, unless `target`'s model has no structured-output mode.
+    fn response_schema_for(&self, target: &ProviderTarget) -> Option<&Value> {
+        self.response_schema.as_ref().filter(|_| {
+            models::lookup(&target.provider, &target.model).supports(Capability::JsonMode)
+        })
+    }
+
+    /// Sends `request` to `target`, retrying timeouts, dropped connections, 429s and
+    /// 5xx responses with backoff. Other failures are classified and returned straight
+    /// away. Each retry is another request to the provider, so it waits for its own
+    /// slot in the rate limiter.
+    async fn send(
+        &self,
+        target: &ProviderTarget,
+        request: RequestBuilder,
+    ) -> Result<Response, CrabError> {
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let last_attempt = attempt >= self.retry.max_attempts;
+            let pending = request
+                .try_clone()
+                .ok_or_else(|| CrabError::Config("Request body cannot be retried".to_string()))?;
+
+            let (reason, delay) = match self.send_once(pending).await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => {
+                    let status = response.status();
+                    let headers = response.headers().clone();
+                    let body = response.text().await.unwrap_or_default();
+                    let error =
+                        CrabError::from_api_error(Some(status.as_u16()), &body, Some(&headers));
+
+                    if last_attempt || !error.is_retryable() {
+                        return Err(error);
+                    }
+                    (
+                        format!("API error ({})", status),
+                        self.retry.delay_for(attempt, Some((status, &headers))),
+                    )
+                }
+                Err(error) => {
+                    if last_attempt || !error.is_retryable() {
+                        return Err(error);
+                    }
+                    (error.to_string(), self.retry.delay_for(attempt, None))
+                }
+            };
+
+            retry::announce(attempt, self.retry.max_attempts, delay, &reason);
+            tokio::time::sleep(delay).await;
+            if let Some(limiter) = &self.rate_limiter {
+                limiter.acquire(&target.provider, 0).await;
+            }
+        }
+    }
+
+    /// One attempt, which gives up when no response headers arrive within the read
+    /// timeout.
+    async fn send_once(&self, request: RequestBuilder) -> Result<Response, CrabError> {
+        let sent = match self.read_timeout {
+            Some(limit) => tokio::time::timeout(limit, request.send())
+                .await
+                .map_err(|_| stream::read_timed_out(limit))?,
+            None => request.send().await,
+        };
+        sent.map_err(|e| CrabError::from_reqwest(&e))
+    }
+
+    /// Sends the conversation to the primary provider, moving down the `LLM_FALLBACKS`
+    /// chain when a provider keeps failing with retryable errors.
+    pub async fn complete(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        max_tokens: u32,
+    ) -> Result<Completion, CrabError> {
+        if let Some(cassette) = self.replaying() {
+            return cassette.replay(messages);
+        }
+
+        let result = self.complete_live(messages, tools, max_tokens).await;
+        if let Some(cassette) = &self.cassette {
+            cassette.record(messages, tools, max_tokens, &result);
+        }
+        result
+    }
+
+    async fn complete_live(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        max_tokens: u32,
+    ) -> Result<Completion, CrabError> {
+        let mut last_error = None;
+
+        for (i, target) in self.targets.iter().enumerate() {
+            // Reserves the prompt plus the most the reply can take; settled below, and
+            // refunded if the call fails or is cancelled.
+            let reservation = match &self.rate_limiter {
+                Some(limiter) if target.provider != "mock" => {
+                    let family = ModelFamily::of(&target.model);
+                    let tokens = messages
+                        .iter()
+                        .map(|m| message_tokens(m, family))
+                        .sum::<u32>()
+                        + max_tokens;
+                    Some(limiter.reserve(&target.provider, tokens).await)
+                }
+                _ => None,
+            };
+
+            let result = match target.provider.as_str() {
+                "google" => {
+                    self.complete_google(target, messages, tools, max_tokens)
+                        .await
+                }
+                "anthropic" => {
+                    self.complete_anthropic(target, messages, tools, max_tokens)
+                        .await
+                }
+                "proxy" => {
+                    self.complete_proxy(target, messages, tools, max_tokens)
+                        .await
+                }
+                "mock" => match &self.mock {
+                    Some(mock) => mock.complete(messages, tools, max_tokens),
+                    None => Err(CrabError::Config("Mock provider not set up".to_string())),
+                },
+                _ => {
+                    self.complete_openai(target, messages, tools, max_tokens)
+                        .await
+                }
+            };
+
+            if let Some(reservation) = reservation {
+                match &result {
+                    Ok(completion) => reservation.settle(completion.usage.total()).await,
+                    Err(_) => reservation.refund().await,
+                }
+            }
+
+            match result {
+                Ok(mut completion) => {
+                    completion.provider = target.provider.clone();
+                    completion.model = target.model.clone();
+                    return Ok(completion);
+                }
+                Err(e) if e.is_retryable() => {
+                    if let Some(next) = self.targets.get(i + 1) {
+                        println!(
+                            "[FAILOVER] {}/{} unavailable, switching to {}/{}: {}",
+                            target.provider, target.model, next.provider, next.model, e
+                        );
+                    }
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| CrabError::Config("No LLM provider configured".to_string())))
+    }
+
+    /// Relays the conversation through the orchestrator's `/api/internal/llm` endpoint,
+    /// so real provider keys never have to live inside the cubicle. The request is in
+    /// OpenAI's shape and the orchestrator translates it for the upstream provider.
+    /// Settings it cannot translate are refused here rather than dropped on the way.
+    async fn complete_proxy(
+        &self,
+        target: &ProviderTarget,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        max_tokens: u32,
+    ) -> Result<Completion, CrabError> {
+        let url = format!("{}/api/internal/llm", self.base_url(target)?);
+
+        let unrelayed: Vec<&str> = [
+            ("tool definitions (set LLM_TOOLS=false)", !tools.is_empty()),
+            ("provider routing", self.sampling.provider_routing.is_some()),
+            (
+                "a reasoning effort",
+                self.sampling.reasoning_effort.is_some(),
+            ),
+            ("a thinking budget", self.sampling.thinking_budget.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(setting, set)| set.then_some(setting))
+        .collect();
+        if !unrelayed.is_empty() {
+            return Err(CrabError::Config(format!(
+                "The orchestrator proxy cannot relay {}",
+                unrelayed.join(", ")
+            )));
+        }
+
+        let mut request_body = json!({
+            "agentId": self.agent_id,
+            "messages": messages.iter().map(openai_message).collect::<Vec<_>>(),
+            "max_tokens": max_tokens,
+            "temperature": self.sampling.temperature,
+            "top_p": self.sampling.top_p,
+            "seed": self.sampling.seed,
+            "stop": Some(&self.sampling.stop).filter(|stop| !stop.is_empty()),
+            "presence_penalty": self.sampling.presence_penalty,
+            "frequency_penalty": self.sampling.frequency_penalty,
+            "response_format": self
+                .response_schema_for(target)
+                .map(|schema| openai_response_format("openai", schema)),
+        });
+        if let Some(fields) = request_body.as_object_mut() {
+            fields.retain(|_, value| !value.is_null());
+        }
+
+        let response = self
+            .send(
+                target,
+                self.request(Method::POST, target, &url)
+                    .header("X-Agent-Id", self.agent_id.to_string())
+                    .header("Content-Type", "application/json")
+                    .json(&request_body),
+            )
+            .await?;
+
+        let body: Value = response.json().await.map_err(parse_error)?;
+
+        if let Some(error) = body.get("error").and_then(|v| v.as_str()) {
+            return Err(proxy_error(error));
+        }
+
+        let output = body
+            .get("output")
+            .and_then(|v| v.as_str())
+            .ok_or_else(no_response)?;
+
+        // The orchestrator reports upstream failures as "❌ ..." text with a 200 status.
+        if output.starts_with('❌') {
+            return Err(proxy_error(output));
+        }
+
+        Ok(Completion {
+            content: output.to_string(),
+            usage: body
+                .get("usage")
+                .map(Usage::from_openai)
+                .unwrap_or_default(),
+            ..Default::default()
+        })
+    }
+
+    async fn complete_openai(
+        &self,
+        target: &ProviderTarget,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        max_tokens: u32,
+    ) -> Result<Completion, CrabError> {
+        let url = format!("{}/chat/completions", self.base_url(target)?);
+
+        let effort = self.sampling.reasoning_effort.clone();
+        // OpenAI's reasoning models reject `max_tokens` and sampling settings, and the
+        // other models reject `reasoning_effort`.
+        let reasoning_model = matches!(target.provider.as_str(), "openai" | "azure")
+            && models::lookup(&target.provider, &target.model).reasoning == Some(true);
+        let sampled = |value: Option<f64>| value.filter(|_| !reasoning_model);
+
+        let mut wire_messages: Vec<Value> = messages.iter().map(openai_message).collect();
+        // OpenRouter passes `cache_control` through to Anthropic models; others cache
+        // long prefixes by themselves.
+        if self.prompt_cache
+            && target.provider == "openrouter"
+            && target.model.starts_with("anthropic/")
+        {
+            mark_system_prompt_cacheable(&mut wire_messages);
+        }
 
         let request_body = ChatRequest {
-            model: self.model.clone(),
-            messages: messages.to_vec(),
-            max_tokens: Some(max_tokens),
+            model: target.model.clone(),
+            messages: wire_messages,
+            max_tokens: (!reasoning_model).then_some(max_tokens),
+            max_completion_tokens: reasoning_model.then_some(max_tokens),
+            tools: tools
+                .iter()
+                .map(|t| {
+                    json!({
+                        "type": "function",
+                        "function": {
+                            "name": t.name,
+                            "description": t.description,
+                            "parameters": t.parameters,
+                        },
+                    })
+                })
+                .collect(),
+            stream: self.stream,
+            // Only OpenAI and Azure need to be asked for usage on the final chunk; the
+            // other compatible providers include it by default.
+            stream_options: (self.stream && matches!(target.provider.as_str(), "openai" | "azure"))
+                .then(|| json!({ "include_usage": true })),
+            response_format: self
+                .response_schema_for(target)
+                .map(|schema| openai_response_format(&target.provider, schema)),
+            temperature: sampled(self.sampling.temperature),
+            top_p: sampled(self.sampling.top_p),
+            seed: self.sampling.seed.filter(|_| target.provider != "mistral"),
+            random_seed: self.sampling.seed.filter(|_| target.provider == "mistral"),
+            stop: self.sampling.stop.clone(),
+            presence_penalty: sampled(self.sampling.presence_penalty),
+            frequency_penalty: sampled(self.sampling.frequency_penalty),
+            provider: self
+                .sampling
+                .provider_routing
+                .clone()
+                .filter(|_| target.provider == "openrouter"),
+            // DeepSeek's reasoner always reasons, and Mistral rejects unknown fields.
+            reasoning_effort: effort.clone().filter(|_| match target.provider.as_str() {
+                "openai" | "azure" => reasoning_model,
+                "openrouter" | "deepseek" | "mistral" => false,
+                _ => true,
+            }),
+            reasoning: (target.provider == "openrouter" && self.sampling.reasoning()).then(
+                || match (&effort, self.sampling.thinking_budget) {
+                    (Some(effort), _) => json!({ "effort": effort }),
+                    (None, budget) => json!({ "max_tokens": budget }),
+                },
+            ),
+            prompt_cache_key: (self.prompt_cache && target.provider == "openai")
+                .then(|| format!("hermit-agent-{}", self.agent_id)),
         };
 
         let mut request = self
-            .client
-            .post(url)
-            .header("Authorization", format!("{} {}", auth_prefix, self.api_key))
+            .request(Method::POST, target, &url)
             .header("Content-Type", "application/json");
 
-        if self.provider == "openrouter" {
+        if target.provider == "openrouter" {
             request = request
                 .header("HTTP-Referer", "https://crabshell.local")
                 .header("X-Title", "CrabShell");
         }
 
-        let response = request
-            .json(&request_body)
-            .send()
-            .map_err(|e| format!("Request failed: {}", e))?;
+        let response = self.send(target, request.json(&request_body)).await?;
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().unwrap_or_default();
-            return Err(format!("API error ({}): {}", status, body));
+        if self.stream {
+            return read_openai_stream(response, self.read_timeout)
+                .await
+                .map(split_think_tags);
         }
 
-        let body: ChatResponse = response
-            .json()
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
+        let body: ChatResponse = response.json().await.map_err(parse_error)?;
 
-        let content = body
+        let message = body
             .choices
-            .first()
-            .map(|c| c.message.content.clone())
-            .ok_or_else(|| "No response from API".to_string())?;
-
-        let tokens = body.usage.and_then(|u| u.total_tokens).unwrap_or(0);
+            .into_iter()
+            .next()
+            .map(|c| c.message)
+            .ok_or_else(no_response)?;
+
+        let tool_calls = message
+            .tool_calls
+            .into_iter()
+            .map(|c| ToolCall {
+                id: c.id,
+                name: c.function.name,
+                arguments: parse_tool_arguments(&c.function.arguments),
+            })
+            .collect();
 
-        Ok((content, tokens))
+        Ok(split_think_tags(Completion {
+            content: message.content.unwrap_or_default(),
+            tool_calls,
+            reasoning: message
+                .reasoning_content
+                .or(message.reasoning)
+                .unwrap_or_default(),
+            usage: body
+                .usage
+                .as_ref()
+                .map(Usage::from_openai)
+                .unwrap_or_default(),
+            ..Default::default()
+        }))
     }
 
-    fn complete_anthropic(
+    async fn complete_anthropic(
         &self,
+        target: &ProviderTarget,
         messages: &[Message],
+        tools: &[ToolDefinition],
         max_tokens: u32,
-    ) -> Result<(String, u32), String> {
-        let url = "https://api.anthropic.com/v1/messages";
-
-        #[derive(Serialize)]
-        struct AnthropicRequest {
-            model: String,
-            messages: Vec<Message>,
-            max_tokens: u32,
-        }
+    ) -> Result<Completion, CrabError> {
+        let url = format!("{}/messages", self.base_url(target)?);
 
-        let request_body = AnthropicRequest {
-            model: self.model.clone(),
-            messages: messages.to_vec(),
+        let request_body = anthropic::build_request(
+            &target.model,
+            messages,
+            tools,
             max_tokens,
-        };
+            self.stream,
+            &self.sampling,
+            self.prompt_cache,
+        );
 
         let response = self
-            .client
-            .post(url)
-            .header("x-api-key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .map_err(|e| format!("Request failed: {}", e))?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().unwrap_or_default();
-            return Err(format!("API error ({}): {}", status, body));
+            .send(
+                target,
+                self.request(Method::POST, target, &url)
+                    .header("anthropic-version", "2023-06-01")
+                    .header("Content-Type", "application/json")
+                    .json(&request_body),
+            )
+            .await?;
+
+        if self.stream {
+            return read_anthropic_stream(response, self.read_timeout).await;
         }
 
         #[derive(Deserialize)]
         struct AnthropicResponse {
             content: Vec<AnthropicContent>,
-            usage: Option<AnthropicUsage>,
+            usage: Option<Value>,
         }
 
         #[derive(Deserialize)]
-        struct AnthropicContent {
-            text: String,
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum AnthropicContent {
+            Text {
+                text: String,
+            },
+            ToolUse {
+                id: String,
+                name: String,
+                input: Value,
+            },
+            Thinking {
+                thinking: String,
+                signature: String,
+            },
+            RedactedThinking {
+                data: String,
+            },
+            #[serde(other)]
+            Other,
         }
 
-        #[derive(Deserialize)]
-        struct AnthropicUsage {
-            input_tokens: u32,
-            output_tokens: u32,
-        }
+        let body: AnthropicResponse = response.json().await.map_err(parse_error)?;
 
-        let body: AnthropicResponse = response
-            .json()
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
+        if body.content.is_empty() {
+            return Err(no_response());
+        }
 
-        let content = body
-            .content
-            .first()
-            .map(|c| c.text.clone())
-            .ok_or_else(|| "No response from API".to_string())?;
+        let mut completion = Completion::default();
+        for block in body.content {
+            match block {
+                AnthropicContent::Text { text } => completion.content.push_str(&text),
+                AnthropicContent::ToolUse { id, name, input } => {
+                    completion.tool_calls.push(ToolCall {
+                        id,
+                        name,
+                        arguments: input,
+                    })
+                }
+                AnthropicContent::Thinking {
+                    thinking,
+                    signature,
+                } => {
+                    completion.thinking.push(json!({
+                        "type": "thinking",
+                        "thinking": thinking,
+                        "signature": signature,
+                    }));
+                    completion.reasoning.push_str(&thinking);
+                }
+                AnthropicContent::RedactedThinking { data } => completion
+                    .thinking
+                    .push(json!({ "type": "redacted_thinking", "data": data })),
+                AnthropicContent::Other => {}
+            }
+        }
 
-        let tokens = body
+        completion.usage = body
             .usage
-            .map(|u| u.input_tokens + u.output_tokens)
-            .unwrap_or(0);
+            .as_ref()
+            .map(Usage::from_anthropic)
+            .unwrap_or_default();
 
-        Ok((content, tokens))
+        Ok(completion)
     }
 
-    fn complete_google(
+    async fn complete_google(
         &self,
+        target: &ProviderTarget,
         messages: &[Message],
+        tools: &[ToolDefinition],
         max_tokens: u32,
-    ) -> Result<(String, u32), String> {
-        let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-            self.model, self.api_key
-        );
+    ) -> Result<Completion, CrabError> {
+        let base_url = self.base_url(target)?;
+        let url = if self.stream {
+            format!(
+                "{}/models/{}:streamGenerateContent?alt=sse",
+                base_url, target.model
+            )
+        } else {
+            format!("{}/models/{}:generateContent", base_url, target.model)
+        };
 
-        #[derive(Serialize)]
-        struct GoogleRequest {
-            contents: Vec<GoogleContent>,
-            generationConfig: GoogleConfig,
+        let mut request_body = gemini::build_request(
+            messages,
+            tools,
+            max_tokens,
+            self.response_schema_for(target),
+            &self.sampling,
+        );
+        if self.prompt_cache {
+            if let Some(name) = self.gemini_cached_content(target, &request_body).await {
+                request_body.use_cached_content(name);
+            }
         }
 
-        #[derive(Serialize)]
-        struct GoogleContent {
-            parts: Vec<GooglePart>,
+        let response = self
+            .send(
+                target,
+                self.request(Method::POST, target, &url)
+                    .header("Content-Type", "application/json")
+                    .json(&request_body),
+            )
+            .await?;
+
+        if self.stream {
+            return read_google_stream(response, self.read_timeout).await;
         }
 
-        #[derive(Serialize, Deserialize)]
-        struct GooglePart {
-            text: String,
+        let body: Value = response.json().await.map_err(parse_error)?;
+
+        let mut completion = Completion::default();
+        gemini::apply_response(&body, &mut completion, None)?;
+
+        if completion.content.is_empty() && completion.tool_calls.is_empty() {
+            return Err(gemini::GeminiError::Empty.into());
         }
 
-        #[derive(Serialize)]
-        struct GoogleConfig {
-            maxOutputTokens: u32,
+        Ok(completion)
+    }
+
+    /// The `cachedContents` resource holding the request's system instruction and
+    /// tools, created on first use. A prompt Gemini will not cache (usually one below
+    /// its minimum size) is remembered too, so it is not retried on every call.
+    async fn gemini_cached_content(
+        &self,
+        target: &ProviderTarget,
+        request: &gemini::GeminiRequest,
+    ) -> Option<String> {
+        let mut body = request.cacheable_prefix()?;
+        let key = CacheRegistry::key(&target.model, &body);
+        match self.gemini_caches.lookup(&key) {
+            Some(Cached::Name(name)) => return Some(name),
+            Some(Cached::Unavailable) => return None,
+            None => {}
         }
 
-        let contents: Vec<GoogleContent> = messages
-            .iter()
-            .map(|m| GoogleContent {
-                parts: vec![GooglePart {
-                    text: m.content.clone(),
-                }],
-            })
-            .collect();
+        body["model"] = json!(format!("models/{}", target.model));
+        body["ttl"] = json!(format!("{}s", GEMINI_CACHE_TTL.as_secs()));
+        let url = format!("{}/cachedContents", self.base_url(target).ok()?);
+        let created = match self
+            .send(target, self.request(Method::POST, target, &url).json(&body))
+            .await
+        {
+            Ok(response) => response.json::<Value>().await.map_err(parse_error),
+            Err(e) => Err(e),
+        };
 
-        let request_body = GoogleRequest {
-            contents,
-            generationConfig: GoogleConfig {
-                maxOutputTokens: max_tokens,
+        let cached = match created {
+            Ok(created) => match created.get("name").and_then(|v| v.as_str()) {
+                Some(name) => Cached::Name(name.to_string()),
+                None => Cached::Unavailable,
             },
+            // Worth another try on the next call.
+            Err(e) if e.is_retryable() => return None,
+            Err(e) => {
+                eprintln!("[Cache] Gemini did not cache the system prompt: {}", e);
+                Cached::Unavailable
+            }
         };
+        self.gemini_caches.store(&key, &cached, GEMINI_CACHE_TTL);
+        match cached {
+            Cached::Name(name) => Some(name),
+            Cached::Unavailable => None,
+        }
+    }
+}
 
-        let response = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .map_err(|e| format!("Request failed: {}", e))?;
+fn parse_error(error: impl std::fmt::Display) -> CrabError {
+    CrabError::InvalidResponse(format!("Failed to parse response: {}", error))
+}
+
+fn unexpected_embeddings(body: &Value) -> CrabError {
+    CrabError::InvalidResponse(format!("Unexpected embeddings response: {}", body))
+}
+
+fn embedding_vector(values: &Value) -> Result<Vec<f32>, CrabError> {
+    values
+        .as_array()
+        .and_then(|values| {
+            values
+                .iter()
+                .map(|v| v.as_f64().map(|v| v as f32))
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| CrabError::InvalidResponse("Embedding is not a list of numbers".to_string()))
+}
+
+fn no_response() -> CrabError {
+    CrabError::InvalidResponse("No response from API".to_string())
+}
+
+fn proxy_error(message: &str) -> CrabError {
+    CrabError::Http {
+        status: None,
+        code: Some("proxy_error".to_string()),
+        message: message.to_string(),
+    }
+}
+
+/// Local reasoning models such as DeepSeek-R1 and Qwen3 served by Ollama put their
+/// reasoning at the start of the content, inside `<think>` tags.
+fn split_think_tags(mut completion: Completion) -> Completion {
+    let Some((thought, answer)) = completion
+        .content
+        .trim_start()
+        .strip_prefix("<think>")
+        .and_then(|rest| rest.split_once("</think>"))
+    else {
+        return completion;
+    };
+
+    let (thought, answer) = (thought.trim().to_string(), answer.trim_start().to_string());
+    if !completion.reasoning.is_empty() {
+        completion.reasoning.push('\n');
+    }
+    completion.reasoning.push_str(&thought);
+    completion.content = answer;
+    completion
+}
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().unwrap_or_default();
-            return Err(format!("API error ({}): {}", status, body));
+fn finish_stream(completion: Completion) -> Result<Completion, CrabError> {
+    if completion.content.is_empty() && completion.tool_calls.is_empty() {
+        return Err(no_response());
+    }
+    Ok(completion)
+}
+
+async fn read_openai_stream(
+    response: Response,
+    read_timeout: Option<Duration>,
+) -> Result<Completion, CrabError> {
+    let mut completion = Completion::default();
+    // Tool calls arrive as fragments keyed by index: (id, name, arguments so far).
+    let mut pending_calls: Vec<(String, String, String)> = Vec::new();
+    let mut shown = ContentStream::default();
+
+    read_sse(response, read_timeout, |event| {
+        let chunk: Value = serde_json::from_str(&event.data).map_err(|e| {
+            CrabError::InvalidResponse(format!("Failed to parse stream chunk: {}", e))
+        })?;
+
+        if chunk.get("error").is_some() {
+            return Err(CrabError::from_api_error(None, &event.data, None));
         }
 
-        #[derive(Deserialize)]
-        struct GoogleResponse {
-            candidates: Vec<GoogleCandidate>,
+        if let Some(delta) = chunk
+            .pointer("/choices/0/delta/content")
+            .and_then(|v| v.as_str())
+        {
+            completion.content.push_str(delta);
+            shown.update(&completion.content);
         }
 
-        #[derive(Deserialize)]
-        struct GoogleCandidate {
-            content: GoogleResponseContent,
+        // Reasoning is not streamed to the user, only collected.
+        if let Some(delta) = chunk
+            .pointer("/choices/0/delta/reasoning_content")
+            .or_else(|| chunk.pointer("/choices/0/delta/reasoning"))
+            .and_then(|v| v.as_str())
+        {
+            completion.reasoning.push_str(delta);
         }
 
-        #[derive(Deserialize)]
-        struct GoogleResponseContent {
-            parts: Vec<GooglePart>,
+        if let Some(calls) = chunk
+            .pointer("/choices/0/delta/tool_calls")
+            .and_then(|v| v.as_array())
+        {
+            for call in calls {
+                let index = call.get("index").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+                if pending_calls.len() <= index {
+                    pending_calls.resize(index + 1, Default::default());
+                }
+                let pending = &mut pending_calls[index];
+                if let Some(id) = call.get("id").and_then(|v| v.as_str()) {
+                    pending.0 = id.to_string();
+                }
+                if let Some(name) = call.pointer("/function/name").and_then(|v| v.as_str()) {
+                    pending.1.push_str(name);
+                }
+                if let Some(args) = call.pointer("/function/arguments").and_then(|v| v.as_str()) {
+                    pending.2.push_str(args);
+                }
+            }
         }
 
-        let body: GoogleResponse = response
-            .json()
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
+        if let Some(usage) = chunk.get("usage").filter(|u| !u.is_null()) {
+            completion.usage = Usage::from_openai(usage);
+        }
 
-        let content = body
-            .candidates
-            .first()
-            .and_then(|c| c.content.parts.first())
-            .map(|p| p.text.clone())
-            .ok_or_else(|| "No response from API".to_string())?;
+        Ok(true)
+    })
+    .await?;
+
+    completion.tool_calls = pending_calls
+        .into_iter()
+        .filter(|(_, name, _)| !name.is_empty())
+        .map(|(id, name, args)| ToolCall {
+            id,
+            name,
+            arguments: parse_tool_arguments(&args),
+        })
+        .collect();
+
+    finish_stream(completion)
+}
 
-        Ok((content, 0))
-    }
+async fn read_anthropic_stream(
+    response: Response,
+    read_timeout: Option<Duration>,
+) -> Result<Completion, CrabError> {
+    let mut completion = Completion::default();
+    let mut shown = ContentStream::default();
+    // The tool_use block currently being streamed: (id, name, partial JSON input).
+    let mut pending_call: Option<(String, String, String)> = None;
+    // The thinking or redacted_thinking block currently being streamed.
+    let mut pending_thinking: Option<Value> = None;
+
+    read_sse(response, read_timeout, |event| {
+        let payload: Value = serde_json::from_str(&event.data).map_err(|e| {
+            CrabError::InvalidResponse(format!("Failed to parse stream event: {}", e))
+        })?;
+
+        let event_type = event
+            .event
+            .as_deref()
+            .or_else(|| payload.get("type").and_then(|v| v.as_str()))
+            .unwrap_or_default();
+
+        match event_type {
+            "message_start" => {
+                if let Some(usage) = payload.pointer("/message/usage") {
+                    completion.usage = Usage::from_anthropic(usage);
+                }
+            }
+            "content_block_start" => {
+                let block = &payload["content_block"];
+                match block["type"].as_str() {
+                    Some("tool_use") => {
+                        pending_call = Some((
+                            block["id"].as_str().unwrap_or_default().to_string(),
+                            block["name"].as_str().unwrap_or_default().to_string(),
+                            String::new(),
+                        ))
+                    }
+                    Some("thinking" | "redacted_thinking") => {
+                        pending_thinking = Some(block.clone())
+                    }
+                    _ => {}
+                }
+            }
+            "content_block_delta" => {
+                if let Some(delta) = payload.pointer("/delta/text").and_then(|v| v.as_str()) {
+                    completion.content.push_str(delta);
+                    shown.update(&completion.content);
+                }
+                if let (Some(partial), Some(call)) = (
+                    payload
+                        .pointer("/delta/partial_json")
+                        .and_then(|v| v.as_str()),
+                    pending_call.as_mut(),
+                ) {
+                    call.2.push_str(partial);
+                }
+                if let Some(block) = pending_thinking.as_mut() {
+                    let delta = &payload["delta"];
+                    if let Some(thinking) = delta["thinking"].as_str() {
+                        completion.reasoning.push_str(thinking);
+                        let so_far = block["thinking"].as_str().unwrap_or_default();
+                        block["thinking"] = json!(format!("{}{}", so_far, thinking));
+                    }
+                    if let Some(signature) = delta["signature"].as_str() {
+                        block["signature"] = json!(signature);
+                    }
+                }
+            }
+            "content_block_stop" => {
+                completion.thinking.extend(pending_thinking.take());
+                if let Some((id, name, input)) = pending_call.take() {
+                    completion.tool_calls.push(ToolCall {
+                        id,
+                        name,
+                        arguments: parse_tool_arguments(&input),
+                    });
+                }
+            }
+            "message_delta" => {
+                if let Some(n) = payload
+                    .pointer("/usage/output_tokens")
+                    .and_then(|v| v.as_u64())
+                {
+                    completion.usage.output_tokens = n as u32;
+                }
+            }
+            "message_stop" => return Ok(false),
+            "error" => return Err(CrabError::from_api_error(None, &event.data, None)),
+            _ => {}
+        }
+
+        Ok(true)
+    })
+    .await?;
+
+    finish_stream(completion)
+}
+
+async fn read_google_stream(
+    response: Response,
+    read_timeout: Option<Duration>,
+) -> Result<Completion, CrabError> {
+    let mut completion = Completion::default();
+    let mut shown = ContentStream::default();
+
+    read_sse(response, read_timeout, |event| {
+        let chunk: Value = serde_json::from_str(&event.data).map_err(|e| {
+            CrabError::InvalidResponse(format!("Failed to parse stream chunk: {}", e))
+        })?;
+
+        if chunk.get("error").is_some() {
+            return Err(CrabError::from_api_error(None, &event.data, None));
+        }
+
+        gemini::apply_response(&chunk, &mut completion, Some(&mut shown))?;
+
+        Ok(true)
+    })
+    .await?;
+
+    finish_stream(completion)
 }
 
 pub fn build_system_prompt(agent_name: &str, agent_role: &str, image: &str) -> String {
@@ -474,3 +1776,765 @@ pub fn extract_command(response: &str) -> Option<String> {
     }
     None
 }
+
+#[cfg(test)]
+impl ProviderTarget {
+    /// `provider` serving `model` at `base_url`, with a test key.
+    pub(crate) fn stub(provider: &str, model: &str, base_url: &str) -> Self {
+        Self {
+            provider: provider.to_string(),
+            model: model.to_string(),
+            api_key: "test-key".to_string(),
+            base_url: Some(base_url.to_string()),
+            extra_headers: Vec::new(),
+            api_version: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl LLMClient {
+    /// A single-attempt client for tests that talk to a stub server, as agent 7. Each
+    /// one gets its own Gemini cache registry so parallel tests do not share entries.
+    pub(crate) fn stub(targets: Vec<ProviderTarget>) -> Self {
+        static REGISTRIES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let registry = std::env::temp_dir().join(format!(
+            "crab-test-gemini-cache-{}-{}.json",
+            std::process::id(),
+            REGISTRIES.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+
+        Self {
+            client: Client::new(),
+            targets,
+            stream: false,
+            retry: RetryPolicy {
+                max_attempts: 1,
+                ..RetryPolicy::default()
+            },
+            agent_id: 7,
+            mock: None,
+            cassette: None,
+            response_schema: None,
+            sampling: Sampling::default(),
+            prompt_cache: false,
+            gemini_caches: CacheRegistry::new(registry),
+            rate_limiter: None,
+            embedding_model: None,
+            read_timeout: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{StubResponse, StubServer};
+
+    #[tokio::test]
+    async fn openai_compatible_server_gets_custom_headers_and_no_auth_without_key() {
+        let server = StubServer::start(vec![StubResponse::json(
+            200,
+            json!({
+                "choices": [{ "message": { "role": "assistant", "content": "pong" } }],
+                "usage": { "total_tokens": 9 },
+            }),
+        )]);
+        let client = LLMClient::stub(vec![ProviderTarget {
+            provider: "ollama".to_string(),
+            model: "llama3.1".to_string(),
+            api_key: String::new(),
+            base_url: Some(format!("{}/v1", server.url)),
+            extra_headers: parse_extra_headers(r#"{"X-Team": "research"}"#).unwrap(),
+            api_version: None,
+        }]);
+
+        let completion = client
+            .complete(&[Message::new("user", "ping")], &[], 64)
+            .await
+            .unwrap();
+
+        assert_eq!(completion.content, "pong");
+        assert_eq!(completion.usage.total(), 9);
+
+        let request = &server.requests()[0];
+        assert_eq!(request.path, "/v1/chat/completions");
+        assert_eq!(request.header("authorization"), None);
+        assert_eq!(request.header("x-team"), Some("research"));
+        assert_eq!(request.json()["model"], "llama3.1");
+    }
+
+    #[tokio::test]
+    async fn rate_limits_every_attempt_and_refunds_failed_calls() {
+        let outage = || StubResponse::json(503, json!({ "error": { "message": "overloaded" } }));
+        let server = StubServer::start(vec![outage(), outage()]);
+        let path = env::temp_dir().join(format!("crab-llm-rate-limit-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut client =
+            LLMClient::stub(vec![ProviderTarget::stub("openai", "gpt-4o", &server.url)]);
+        client.retry = RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        client.rate_limiter = Some(RateLimiter::new(Some(60), Some(100_000), path.clone()));
+
+        assert!(client
+            .complete(&[Message::new("user", "ping")], &[], 1_000)
+            .await
+            .is_err());
+
+        assert_eq!(server.requests().len(), 2);
+        let state: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let bucket = &state["openai"];
+        assert!(bucket["requests"].as_f64().unwrap() < 58.1);
+        assert!(bucket["tokens"].as_f64().unwrap() > 99_999.0);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn reads_streamed_text_and_tool_call_fragments() {
+        let events = [
+            json!({ "choices": [{ "delta": { "content": "Let me " } }] }),
+            json!({ "choices": [{ "delta": { "content": "check." } }] }),
+            json!({ "choices": [{ "delta": { "tool_calls": [{ "index": 0, "id": "call_1", "function": { "name": "run_terminal", "arguments": "{\"comm" } }] } }] }),
+            json!({ "choices": [{ "delta": { "tool_calls": [{ "index": 0, "function": { "arguments": "and\": \"ls\"}" } }] } }] }),
+            json!({ "choices": [], "usage": { "prompt_tokens": 12, "completion_tokens": 5 } }),
+        ];
+        let body: String = events
+            .iter()
+            .map(|e| format!("data: {}\n\n", e))
+            .chain(["data: [DONE]\n\n".to_string()])
+            .collect();
+        let server = StubServer::start(vec![StubResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "text/event-stream".to_string())],
+            body,
+        }]);
+        let mut client =
+            LLMClient::stub(vec![ProviderTarget::stub("openai", "gpt-4o", &server.url)]);
+        client.stream = true;
+
+        let completion = client
+            .complete(&[Message::new("user", "list files")], &[], 64)
+            .await
+            .unwrap();
+
+        assert_eq!(completion.content, "Let me check.");
+        assert_eq!(
+            completion.tool_calls,
+            vec![ToolCall {
+                id: "call_1".to_string(),
+                name: "run_terminal".to_string(),
+                arguments: json!({ "command": "ls" }),
+            }]
+        );
+        assert_eq!(completion.usage.total(), 17);
+        assert_eq!(
+            server.requests()[0].json()["stream_options"],
+            json!({ "include_usage": true })
+        );
+    }
+
+    #[test]
+    fn encodes_images_as_data_url_content_parts() {
+        let image = ImagePart {
+            media_type: "image/jpeg".to_string(),
+            data: "/9j/4AAQ".to_string(),
+        };
+        let message = Message::with_images("user", "describe", vec![image]);
+
+        assert_eq!(
+            openai_message(&message)["content"],
+            json!([
+                { "type": "text", "text": "describe" },
+                { "type": "image_url", "image_url": { "url": "data:image/jpeg;base64,/9j/4AAQ" } },
+            ])
+        );
+        assert_eq!(
+            openai_message(&Message::new("user", "plain"))["content"],
+            "plain"
+        );
+    }
+
+    #[tokio::test]
+    async fn asks_for_strict_json_schema_or_plain_json_mode_by_provider() {
+        let server = StubServer::start(vec![
+            StubResponse::ok_chat("{}"),
+            StubResponse::ok_chat("{}"),
+        ]);
+        let schema = json!({ "type": "object" });
+        let messages = [Message::new("user", "hi")];
+
+        LLMClient::stub(vec![ProviderTarget::stub("openai", "gpt-4o", &server.url)])
+            .with_response_schema(schema.clone())
+            .complete(&messages, &[], 64)
+            .await
+            .unwrap();
+        LLMClient::stub(vec![ProviderTarget::stub(
+            "deepseek",
+            "deepseek-chat",
+            &server.url,
+        )])
+        .with_response_schema(schema)
+        .complete(&messages, &[], 64)
+        .await
+        .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].json()["response_format"],
+            json!({
+                "type": "json_schema",
+                "json_schema": { "name": "response_contract", "strict": true, "schema": { "type": "object" } },
+            })
+        );
+        assert_eq!(
+            requests[1].json()["response_format"],
+            json!({ "type": "json_object" })
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_sampling_settings_and_routing_only_where_supported() {
+        let reply = || StubResponse::ok_chat("ok");
+        let server = StubServer::start(vec![reply(), reply()]);
+        let sampling = Sampling {
+            temperature: Some(0.0),
+            seed: Some(7),
+            stop: vec!["###".to_string()],
+            provider_routing: Some(json!({ "order": ["Anthropic"] })),
+            ..Sampling::default()
+        };
+        let messages = [Message::new("user", "hi")];
+
+        for provider in ["openrouter", "mistral"] {
+            let mut client =
+                LLMClient::stub(vec![ProviderTarget::stub(provider, "m", &server.url)]);
+            client.sampling = sampling.clone();
+            client.complete(&messages, &[], 64).await.unwrap();
+        }
+
+        let requests = server.requests();
+        let openrouter = requests[0].json();
+        assert_eq!(openrouter["temperature"], 0.0);
+        assert_eq!(openrouter["seed"], 7);
+        assert_eq!(openrouter["stop"], json!(["###"]));
+        assert_eq!(openrouter["provider"], json!({ "order": ["Anthropic"] }));
+
+        let mistral = requests[1].json();
+        assert_eq!(mistral["random_seed"], 7);
+        assert!(mistral.get("seed").is_none() && mistral.get("provider").is_none());
+    }
+
+    #[tokio::test]
+    async fn keeps_reasoning_fields_and_think_tags_out_of_the_content() {
+        let reply = |message: Value, usage: Value| {
+            StubResponse::json(
+                200,
+                json!({ "choices": [{ "message": message }], "usage": usage }),
+            )
+        };
+        let server = StubServer::start(vec![
+            reply(
+                json!({ "content": "{\"message\":\"4\"}" }),
+                json!({
+                    "prompt_tokens": 10,
+                    "completion_tokens": 90,
+                    "completion_tokens_details": { "reasoning_tokens": 80 },
+                }),
+            ),
+            reply(
+                json!({ "content": "{\"message\":\"4\"}", "reasoning_content": "2 + 2 = 4" }),
+                json!({ "prompt_tokens": 10, "completion_tokens": 20 }),
+            ),
+            reply(
+                json!({ "content": "<think>\nJust add them.\n</think>\n\n{\"message\":\"4\"}" }),
+                json!({}),
+            ),
+            reply(json!({ "content": "{\"message\":\"4\"}" }), json!({})),
+        ]);
+        let messages = [Message::new("user", "2 + 2?")];
+
+        let mut completions = Vec::new();
+        for (provider, model) in [
+            ("openai", "o3-mini"),
+            ("deepseek", "m"),
+            ("ollama", "m"),
+            ("openai", "gpt-4o"),
+        ] {
+            let mut client =
+                LLMClient::stub(vec![ProviderTarget::stub(provider, model, &server.url)]);
+            client.sampling.reasoning_effort = Some("high".to_string());
+            client.sampling.temperature = Some(0.2);
+            completions.push(client.complete(&messages, &[], 64).await.unwrap());
+        }
+
+        assert!(completions
+            .iter()
+            .all(|c| c.content == "{\"message\":\"4\"}"));
+        assert_eq!(completions[0].usage.reasoning_tokens, 80);
+        assert_eq!(completions[1].reasoning, "2 + 2 = 4");
+        assert_eq!(completions[2].reasoning, "Just add them.");
+
+        let requests = server.requests();
+        let openai = requests[0].json();
+        assert_eq!(openai["reasoning_effort"], "high");
+        assert_eq!(openai["max_completion_tokens"], 64);
+        assert!(openai.get("max_tokens").is_none());
+        assert!(openai.get("temperature").is_none());
+        let deepseek = requests[1].json();
+        assert!(deepseek.get("reasoning_effort").is_none());
+        assert_eq!(deepseek["max_tokens"], 64);
+        // The effort setting applies to every agent; models that do not reason ignore it.
+        let gpt = requests[3].json();
+        assert!(gpt.get("reasoning_effort").is_none());
+        assert_eq!(gpt["max_tokens"], 64);
+        assert_eq!(gpt["temperature"], 0.2);
+    }
+
+    #[tokio::test]
+    async fn creates_a_gemini_cache_once_and_refers_to_it_afterwards() {
+        let answer = || {
+            StubResponse::json(
+                200,
+                json!({
+                    "candidates": [{ "content": { "parts": [{ "text": "ok" }] }, "finishReason": "STOP" }],
+                    "usageMetadata": { "promptTokenCount": 3000, "cachedContentTokenCount": 2800, "candidatesTokenCount": 5 },
+                }),
+            )
+        };
+        let server = StubServer::start(vec![
+            StubResponse::json(200, json!({ "name": "cachedContents/abc123" })),
+            answer(),
+            answer(),
+        ]);
+        let registry =
+            std::env::temp_dir().join(format!("crab-gemini-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&registry);
+        let mut client = LLMClient::stub(vec![ProviderTarget::stub(
+            "google",
+            "gemini-2.5-flash",
+            &server.url,
+        )]);
+        client.prompt_cache = true;
+        client.gemini_caches = CacheRegistry::new(registry.clone());
+        let messages = [
+            Message::new("system", "You are Crab."),
+            Message::new("user", "hi"),
+        ];
+
+        for _ in 0..2 {
+            let completion = client.complete(&messages, &[], 64).await.unwrap();
+            assert_eq!(completion.usage.cached_tokens, 2800);
+            assert_eq!(completion.usage.input_tokens, 200);
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].path, "/cachedContents");
+        let created = requests[0].json();
+        assert_eq!(created["model"], "models/gemini-2.5-flash");
+        assert_eq!(
+            created["systemInstruction"]["parts"][0]["text"],
+            "You are Crab."
+        );
+        for request in &requests[1..] {
+            let body = request.json();
+            assert_eq!(body["cachedContent"], "cachedContents/abc123");
+            assert!(body.get("systemInstruction").is_none());
+        }
+        let _ = std::fs::remove_file(&registry);
+    }
+
+    #[tokio::test]
+    async fn marks_cache_breakpoints_on_openrouter_and_counts_deepseek_cache_hits() {
+        let server = StubServer::start(vec![
+            StubResponse::ok_chat("ok"),
+            StubResponse::json(
+                200,
+                json!({
+                    "choices": [{ "message": { "content": "ok" } }],
+                    "usage": { "prompt_tokens": 1000, "completion_tokens": 10, "prompt_cache_hit_tokens": 900 },
+                }),
+            ),
+        ]);
+        let messages = [
+            Message::new("system", "You are Crab."),
+            Message::new("user", "hi"),
+        ];
+
+        let mut completions = Vec::new();
+        for (provider, model) in [
+            ("openrouter", "anthropic/claude-3.5-sonnet"),
+            ("deepseek", "deepseek-chat"),
+        ] {
+            let mut client =
+                LLMClient::stub(vec![ProviderTarget::stub(provider, model, &server.url)]);
+            client.prompt_cache = true;
+            completions.push(client.complete(&messages, &[], 64).await.unwrap());
+        }
+
+        assert_eq!(
+            server.requests()[0].json()["messages"][0]["content"],
+            json!([{ "type": "text", "text": "You are Crab.", "cache_control": { "type": "ephemeral" } }])
+        );
+        assert_eq!(completions[1].usage.cached_tokens, 900);
+        assert_eq!(completions[1].usage.input_tokens, 100);
+    }
+
+    #[tokio::test]
+    async fn collects_anthropic_thinking_blocks_with_their_signatures() {
+        let events = [
+            (
+                "message_start",
+                json!({ "message": { "usage": { "input_tokens": 20 } } }),
+            ),
+            (
+                "content_block_start",
+                json!({ "index": 0, "content_block": { "type": "thinking", "thinking": "" } }),
+            ),
+            (
+                "content_block_delta",
+                json!({ "index": 0, "delta": { "type": "thinking_delta", "thinking": "Need to " } }),
+            ),
+            (
+                "content_block_delta",
+                json!({ "index": 0, "delta": { "type": "thinking_delta", "thinking": "list files." } }),
+            ),
+            (
+                "content_block_delta",
+                json!({ "index": 0, "delta": { "type": "signature_delta", "signature": "sig-1" } }),
+            ),
+            ("content_block_stop", json!({ "index": 0 })),
+            (
+                "content_block_start",
+                json!({ "index": 1, "content_block": { "type": "tool_use", "id": "toolu_1", "name": "run_terminal", "input": {} } }),
+            ),
+            (
+                "content_block_delta",
+                json!({ "index": 1, "delta": { "type": "input_json_delta", "partial_json": "{\"command\":\"ls\"}" } }),
+            ),
+            ("content_block_stop", json!({ "index": 1 })),
+            ("message_stop", json!({})),
+        ];
+        let body: String = events
+            .iter()
+            .map(|(event, data)| format!("event: {}\ndata: {}\n\n", event, data))
+            .collect();
+        let server = StubServer::start(vec![StubResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "text/event-stream".to_string())],
+            body,
+        }]);
+        let mut client = LLMClient::stub(vec![ProviderTarget::stub(
+            "anthropic",
+            "claude-test",
+            &server.url,
+        )]);
+        client.stream = true;
+        client.sampling.thinking_budget = Some(2048);
+
+        let completion = client
+            .complete(&[Message::new("user", "list files")], &[], 512)
+            .await
+            .unwrap();
+
+        assert_eq!(completion.content, "");
+        assert_eq!(completion.reasoning, "Need to list files.");
+        assert_eq!(
+            completion.thinking,
+            vec![
+                json!({ "type": "thinking", "thinking": "Need to list files.", "signature": "sig-1" })
+            ]
+        );
+        assert_eq!(
+            completion.tool_calls[0].arguments,
+            json!({ "command": "ls" })
+        );
+
+        let request = server.requests()[0].json();
+        assert_eq!(
+            request["thinking"],
+            json!({ "type": "enabled", "budget_tokens": 2048 })
+        );
+        assert_eq!(request["max_tokens"], 512 + 2048);
+    }
+
+    #[tokio::test]
+    async fn lists_models_from_openai_and_ollama_style_responses() {
+        let server = StubServer::start(vec![
+            StubResponse::json(
+                200,
+                json!({ "object": "list", "data": [{ "id": "qwen2.5" }, { "id": "llama3.1" }] }),
+            ),
+            StubResponse::json(200, json!({ "models": [{ "name": "mistral:7b" }] })),
+        ]);
+        let client = LLMClient::stub(vec![ProviderTarget {
+            provider: "openai-compatible".to_string(),
+            model: "default".to_string(),
+            api_key: "local-key".to_string(),
+            base_url: Some(server.url.clone()),
+            extra_headers: Vec::new(),
+            api_version: None,
+        }]);
+
+        assert_eq!(
+            client.list_models().await.unwrap(),
+            vec!["qwen2.5", "llama3.1"]
+        );
+        assert_eq!(client.list_models().await.unwrap(), vec!["mistral:7b"]);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(requests[0].path, "/models");
+        assert_eq!(
+            requests[0].header("authorization"),
+            Some("Bearer local-key")
+        );
+    }
+
+    #[tokio::test]
+    async fn embeds_with_openai_style_and_gemini_batch_endpoints() {
+        let server = StubServer::start(vec![
+            StubResponse::json(
+                200,
+                json!({ "data": [
+                    { "index": 1, "embedding": [0.0, 1.0] },
+                    { "index": 0, "embedding": [1.0, 0.5] },
+                ] }),
+            ),
+            StubResponse::json(200, json!({ "embeddings": [{ "values": [0.25, 0.75] }] })),
+        ]);
+        let target = |provider: &str| ProviderTarget {
+            provider: provider.to_string(),
+            model: "chat-model".to_string(),
+            api_key: "key".to_string(),
+            base_url: Some(server.url.clone()),
+            extra_headers: Vec::new(),
+            api_version: None,
+        };
+        let texts = vec!["query".to_string(), "memory".to_string()];
+
+        let openai = LLMClient::stub(vec![target("openai")]);
+        assert_eq!(
+            openai.embed(&texts).await.unwrap(),
+            vec![vec![1.0, 0.5], vec![0.0, 1.0]]
+        );
+        let google = LLMClient::stub(vec![target("google")]);
+        assert_eq!(
+            google.embed(&texts[..1]).await.unwrap(),
+            vec![vec![0.25, 0.75]]
+        );
+        assert!(LLMClient::stub(vec![target("anthropic")])
+            .embedding_model()
+            .is_none());
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/embeddings");
+        assert_eq!(
+            requests[0].json(),
+            json!({ "model": "text-embedding-3-small", "input": ["query", "memory"] })
+        );
+        assert_eq!(
+            requests[1].path,
+            "/models/text-embedding-004:batchEmbedContents"
+        );
+        assert_eq!(
+            requests[1].json()["requests"][0]["content"]["parts"][0]["text"],
+            "query"
+        );
+    }
+
+    #[tokio::test]
+    async fn azure_addresses_the_deployment_with_api_version_and_api_key() {
+        let server = StubServer::start(vec![StubResponse::ok_chat("{\"message\":\"hi\"}")]);
+        let azure = |base_url| ProviderTarget {
+            provider: "azure".to_string(),
+            model: "gpt-4o".to_string(),
+            api_key: "azure-key".to_string(),
+            base_url,
+            extra_headers: Vec::new(),
+            api_version: Some("2024-10-21".to_string()),
+        };
+        let client = LLMClient::stub(vec![azure(Some(azure_base_url(
+            &format!("{}/", server.url),
+            "gpt-4o-prod",
+        )))])
+        .with_response_schema(json!({ "type": "object" }));
+
+        let completion = client
+            .complete(&[Message::new("user", "hi")], &[], 16)
+            .await
+            .unwrap();
+        assert_eq!(completion.content, "{\"message\":\"hi\"}");
+
+        let request = &server.requests()[0];
+        assert_eq!(
+            request.path,
+            "/openai/deployments/gpt-4o-prod/chat/completions?api-version=2024-10-21"
+        );
+        assert_eq!(request.header("api-key"), Some("azure-key"));
+        assert_eq!(request.header("authorization"), None);
+        assert_eq!(request.json()["response_format"]["type"], "json_schema");
+
+        let error = LLMClient::stub(vec![azure(None)])
+            .complete(&[Message::new("user", "hi")], &[], 16)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), "config");
+        assert!(
+            error.to_string().contains("AZURE_OPENAI_ENDPOINT"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_provider_without_base_url_fails_instead_of_using_openrouter() {
+        let client = LLMClient::stub(vec![ProviderTarget {
+            provider: "my-llm".to_string(),
+            model: "m".to_string(),
+            api_key: "k".to_string(),
+            base_url: provider_base_url("my-llm", "m"),
+            extra_headers: Vec::new(),
+            api_version: None,
+        }]);
+
+        let error = client
+            .complete(&[Message::new("user", "hi")], &[], 16)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), "config");
+        assert!(error.to_string().contains("LLM_BASE_URL"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn proxy_sends_agent_id_token_and_messages_to_orchestrator() {
+        let server = StubServer::start(vec![StubResponse::json(
+            200,
+            json!({
+                "output": "{\"message\":\"hi\"}",
+                "usage": { "prompt_tokens": 40, "completion_tokens": 6 },
+            }),
+        )]);
+        let mut client = LLMClient::stub(vec![ProviderTarget {
+            provider: "proxy".to_string(),
+            model: "default".to_string(),
+            api_key: "container-token".to_string(),
+            base_url: Some(format!("{}/", server.url)),
+            extra_headers: Vec::new(),
+            api_version: None,
+        }])
+        .with_response_schema(json!({ "type": "object" }));
+        client.sampling.temperature = Some(0.3);
+        client.sampling.stop = vec!["###".to_string()];
+
+        let completion = client
+            .complete(
+                &[
+                    Message::new("system", "You are Crab."),
+                    Message::new("user", "hello"),
+                ],
+                &[],
+                256,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(completion.content, "{\"message\":\"hi\"}");
+        assert_eq!(completion.provider, "proxy");
+        assert_eq!(completion.usage.total(), 46);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/internal/llm");
+        assert_eq!(
+            request.header("authorization"),
+            Some("Bearer container-token")
+        );
+        assert_eq!(request.header("x-agent-id"), Some("7"));
+        assert_eq!(
+            request.json(),
+            json!({
+                "agentId": 7,
+                "messages": [
+                    { "role": "system", "content": "You are Crab." },
+                    { "role": "user", "content": "hello" },
+                ],
+                "max_tokens": 256,
+                "temperature": 0.3,
+                "stop": ["###"],
+                "response_format": {
+                    "type": "json_schema",
+                    "json_schema": { "name": "response_contract", "strict": true, "schema": { "type": "object" } },
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn proxy_surfaces_orchestrator_error_text_as_failure() {
+        let server = StubServer::start(vec![StubResponse::json(
+            200,
+            json!({ "output": "❌ **SYSTEM ERROR**: Missing API Key for 'openai'." }),
+        )]);
+        let mut client = LLMClient::stub(vec![ProviderTarget {
+            provider: "proxy".to_string(),
+            model: "default".to_string(),
+            api_key: String::new(),
+            base_url: Some(server.url.clone()),
+            extra_headers: Vec::new(),
+            api_version: None,
+        }]);
+
+        let error = client
+            .complete(&[Message::new("user", "hello")], &[], 256)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Missing API Key"));
+
+        // Settings the orchestrator cannot translate are refused before sending.
+        client.sampling.reasoning_effort = Some("high".to_string());
+        let error = client
+            .complete(&[Message::new("user", "hello")], &[], 256)
+            .await
+            .unwrap_err();
+        assert!(matches!(&error, CrabError::Config(m) if m.contains("a reasoning effort")));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn parses_fallback_chain() {
+        assert_eq!(
+            parse_fallbacks(
+                " anthropic:claude-3-5-haiku-latest, groq ,,openrouter:meta-llama/llama-3:free"
+            ),
+            vec![
+                (
+                    "anthropic".to_string(),
+                    Some("claude-3-5-haiku-latest".to_string())
+                ),
+                ("groq".to_string(), None),
+                (
+                    "openrouter".to_string(),
+                    Some("meta-llama/llama-3:free".to_string())
+                ),
+            ]
+        );
+        assert!(parse_fallbacks("").is_empty());
+    }
+
+    #[test]
+    fn keeps_keyless_fallbacks() {
+        let local = ProviderTarget::fallback("openai-compatible", Some("qwen2.5")).unwrap();
+        assert_eq!(local.model, "qwen2.5");
+        assert!(local.api_key.is_empty());
+        assert!(ProviderTarget::fallback("mock", None).is_ok());
+
+        let error = ProviderTarget::fallback("xai", None).unwrap_err();
+        assert_eq!(error, "XAI_API_KEY is not set");
+    }
+}
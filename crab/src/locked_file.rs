@@ -0,0 +1,37 @@
+//! JSON state files shared by the crab processes in a cubicle. Every read-modify-write
+//! happens under an exclusive `flock`, so concurrent runs see each other's updates
+//! instead of overwriting them.
+
+use rustix::fs::{flock, FlockOperation};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Runs `update` on the state in `path` while holding an exclusive lock on it, then
+/// writes it back. A missing or unreadable file starts from the default state. The
+/// lock is released when the file is closed.
+pub fn with_locked_state<S, T>(path: &Path, update: impl FnOnce(&mut S) -> T) -> io::Result<T>
+where
+    S: Serialize + DeserializeOwned + Default,
+{
+    let mut file: File = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    flock(&file, FlockOperation::LockExclusive)?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let mut state = serde_json::from_str(&contents).unwrap_or_default();
+    let result = update(&mut state);
+
+    let json = serde_json::to_string(&state).map_err(io::Error::other)?;
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(json.as_bytes())?;
+    Ok(result)
+}
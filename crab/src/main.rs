@@ -1,17 +1,70 @@
+mod anthropic;
+mod cancel;
+mod cassette;
+mod compact;
+mod context;
+mod contract;
+mod embeddings;
+mod error;
+mod gemini;
+mod http;
+mod limits;
 mod llm;
+mod locked_file;
+mod memory;
+mod mock;
+mod models;
+mod pricing;
+mod prompt_cache;
+mod rate_limit;
+mod retry;
+mod sampling;
+mod stream;
+#[cfg(test)]
+mod test_server;
 mod tools;
-
-use llm::{build_system_prompt, extract_command, LLMClient, Message};
+mod usage;
+mod vision;
+
+use cancel::CancelToken;
+use cassette::{Cassette, CassetteMode};
+use compact::{CompactionConfig, HISTORY_MARKER, SUMMARY_PREFIX};
+use context::{message_tokens, ContextWindow, ModelFamily};
+use error::CrabError;
+use limits::Limits;
+use llm::{build_system_prompt, extract_command, Completion, ImagePart, LLMClient, Message};
+use memory::MemoryStore;
+use models::Capability;
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::fs;
-use std::path::Path;
-use std::thread;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-use tools::{build_meeting_prompt, execute_command, extract_delegate_action};
+use tools::{
+    agent_tools, build_meeting_prompt, build_tool_prompt, execute_command, extract_delegate_action,
+    parse_tool_call, AgentAction,
+};
+use usage::UsageTracker;
+use vision::ImageLimits;
+
+const DEFAULT_WORKSPACE_DIR: &str = "/app/workspace";
+const DEFAULT_APPROVAL_FILE: &str = "/tmp/hermit_approval.lock";
+const DEFAULT_DENY_FILE: &str = "/tmp/hermit_deny.lock";
+
+/// Reads a path from `key`, falling back to `default` when it is unset or empty.
+fn path_from_env(key: &str, default: &str) -> String {
+    env::var(key)
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
 
-const WORKSPACE_DIR: &str = "/app/workspace";
+/// The agent's persistent workspace, `WORKSPACE_DIR` or `/app/workspace`.
+fn workspace_dir() -> String {
+    path_from_env("WORKSPACE_DIR", DEFAULT_WORKSPACE_DIR)
+}
 
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize)]
 struct Config {
     agent_name: String,
@@ -22,11 +75,6 @@ struct Config {
     max_tokens: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct MemoryEntry {
-    content: String,
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 struct MeetingContext {
     meeting_id: i32,
@@ -54,27 +102,27 @@ fn parse_history_from_file(file_path: &str) -> Vec<Message> {
     }
 }
 
-fn wait_for_approval(max_wait_secs: u64) -> bool {
-    let lock_file = "/tmp/hermit_approval.lock";
-    let deny_file = "/tmp/hermit_deny.lock";
+async fn wait_for_approval(max_wait_secs: u64) -> bool {
+    let lock_file = path_from_env("HITL_APPROVAL_FILE", DEFAULT_APPROVAL_FILE);
+    let deny_file = path_from_env("HITL_DENY_FILE", DEFAULT_DENY_FILE);
     let mut waited = 0;
 
     println!("[HITL] Waiting for approval...");
 
     while waited < max_wait_secs {
-        if Path::new(lock_file).exists() {
-            let _ = fs::remove_file(lock_file);
+        if Path::new(&lock_file).exists() {
+            let _ = fs::remove_file(&lock_file);
             println!("[HITL] Approved!");
             return true;
         }
 
-        if Path::new(deny_file).exists() {
-            let _ = fs::remove_file(deny_file);
+        if Path::new(&deny_file).exists() {
+            let _ = fs::remove_file(&deny_file);
             println!("[HITL] Denied!");
             return false;
         }
 
-        thread::sleep(Duration::from_secs(1));
+        tokio::time::sleep(Duration::from_secs(1)).await;
         waited += 1;
     }
 
@@ -83,7 +131,8 @@ fn wait_for_approval(max_wait_secs: u64) -> bool {
 }
 
 fn ensure_workspace_dir() {
-    let workspace = Path::new(WORKSPACE_DIR);
+    let workspace_dir = workspace_dir();
+    let workspace = Path::new(&workspace_dir);
     if !workspace.exists() {
         if let Err(e) = fs::create_dir_all(workspace) {
             eprintln!("Warning: Could not create workspace directory: {}", e);
@@ -91,19 +140,47 @@ fn ensure_workspace_dir() {
     }
 
     if let Ok(cwd) = env::current_dir() {
-        if cwd != Path::new(WORKSPACE_DIR) {
-            if let Err(e) = env::set_current_dir(WORKSPACE_DIR) {
+        if cwd != workspace {
+            if let Err(e) = env::set_current_dir(workspace) {
                 eprintln!("Warning: Could not change to workspace directory: {}", e);
             } else {
-                eprintln!("[Workspace] Working directory set to {}", WORKSPACE_DIR);
+                eprintln!("[Workspace] Working directory set to {}", workspace_dir);
             }
         }
     }
 }
 
+/// Loads the workspace images the user's message names, e.g. `in/photo_1.jpg`.
+/// Images that cannot be loaded are skipped with a warning.
+fn attach_referenced_images(user_msg: &str, limits: &ImageLimits) -> Vec<ImagePart> {
+    vision::referenced_images(Path::new(&workspace_dir()), user_msg)
+        .into_iter()
+        .filter_map(|path| match vision::load_image(&path, limits) {
+            Ok(image) => {
+                eprintln!("[Workspace] Attached image {}", path.display());
+                Some(image)
+            }
+            Err(e) => {
+                eprintln!("Warning: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Replayed commands run in a scratch directory so they cannot change the workspace.
+fn enter_scratch_dir() {
+    let scratch = cassette::scratch_dir();
+    match fs::create_dir_all(&scratch).and_then(|_| env::set_current_dir(&scratch)) {
+        Ok(()) => eprintln!("[Cassette] Replaying in {}", scratch.display()),
+        Err(e) => eprintln!("Warning: Could not enter replay directory: {}", e),
+    }
+}
+
+#[allow(dead_code)]
 fn save_meeting_note(meeting_id: i32, note: &str) {
     use std::io::Write;
-    let note_file = format!("{}/meeting_{}.txt", WORKSPACE_DIR, meeting_id);
+    let note_file = format!("{}/meeting_{}.txt", workspace_dir(), meeting_id);
     let timestamp = chrono_timestamp();
     let content = format!("[{}] {}\n", timestamp, note);
 
@@ -117,6 +194,7 @@ fn save_meeting_note(meeting_id: i32, note: &str) {
     }
 }
 
+#[allow(dead_code)]
 fn chrono_timestamp() -> String {
     use std::time::{SystemTime, UNIX_EPOCH};
     let duration = SystemTime::now()
@@ -135,13 +213,14 @@ fn chrono_timestamp() -> String {
     datetime
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let agent_name = env::var("AGENT_NAME").unwrap_or_else(|_| "CrabShell".to_string());
     let agent_role = env::var("AGENT_ROLE").unwrap_or_else(|_| "General Assistant".to_string());
     let docker_image = env::var("DOCKER_IMAGE").unwrap_or_else(|_| "hermit/base".to_string());
     let user_msg = env::var("USER_MSG").unwrap_or_default();
     let history_file = env::var("HISTORY_FILE").unwrap_or_default();
-    let max_tokens: u32 = env::var("MAX_TOKENS")
+    let mut max_tokens: u32 = env::var("MAX_TOKENS")
         .unwrap_or_else(|_| "1000".to_string())
         .parse()
         .unwrap_or(1000);
@@ -153,10 +232,64 @@ fn main() {
     let hitl_enabled = env::var("HITL_ENABLED").unwrap_or_else(|_| "false".to_string()) == "true";
 
     ensure_workspace_dir();
+    let cancel = cancel::listen(cancel::stop_file());
+
+    let limits = Limits::load();
+    let mut usage = UsageTracker::from_env(&limits);
+
+    let cassette = Cassette::from_env().unwrap_or_else(|e| exit_with(&e, &usage));
+    if let Some(cassette) = &cassette {
+        match cassette.mode() {
+            CassetteMode::Record => {
+                eprintln!("[Cassette] Recording to {}", cassette.path().display())
+            }
+            CassetteMode::Replay => enter_scratch_dir(),
+        }
+    }
+
+    let client = LLMClient::new()
+        .unwrap_or_else(|e| exit_with(&e, &usage))
+        .with_cassette(cassette);
+    if !client.has_api_key() {
+        exit_with(
+            &CrabError::Config(format!(
+                "No API key found for provider '{}'",
+                client.primary().provider
+            )),
+            &usage,
+        );
+    }
+
+    // Connectivity check for local and self-hosted servers.
+    if env::args().any(|arg| arg == "--list-models") {
+        match client.list_models().await {
+            Ok(models) => models.iter().for_each(|m| println!("{}", m)),
+            Err(e) => exit_with(&e, &usage),
+        }
+        return;
+    }
 
-    let api_key = env::var("OPENAI_API_KEY")
-        .or_else(|_| env::var("OPENROUTER_API_KEY"))
-        .expect("No API key found");
+    // Features asked for explicitly must be there; the rest are picked per model.
+    for (key, capability) in [
+        ("LLM_TOOLS", Capability::Tools),
+        ("LLM_JSON_MODE", Capability::JsonMode),
+        ("LLM_STREAM", Capability::Streaming),
+    ] {
+        if env::var(key).as_deref() == Ok("true") {
+            if let Err(e) = client.require(capability) {
+                exit_with(&e, &usage);
+            }
+        }
+    }
+    if let Some(limit) = client.model_info().max_output_tokens {
+        if max_tokens > limit {
+            eprintln!(
+                "Warning: MAX_TOKENS {} is above the model's limit; using {}",
+                max_tokens, limit
+            );
+            max_tokens = limit;
+        }
+    }
 
     let history = if history_file.is_empty() {
         let history_b64 = env::var("HISTORY").unwrap_or_default();
@@ -164,70 +297,198 @@ fn main() {
     } else {
         parse_history_from_file(&history_file)
     };
+    let history = compact_history(&client, &mut usage, &cancel, history, &history_file).await;
 
     let mut system_prompt = build_system_prompt(&agent_name, &agent_role, &docker_image);
     system_prompt.push_str(&build_meeting_prompt());
-    system_prompt.push_str(&format!("\n\nWORKSPACE: All file operations should be performed in {} directory. This is your persistent workspace that survives across sessions.\n", WORKSPACE_DIR));
+    system_prompt.push_str(&format!("\n\nWORKSPACE: All file operations should be performed in {} directory. This is your persistent workspace that survives across sessions.\n", workspace_dir()));
 
-    let memory_context = fetch_memory_from_shell(agent_id, &user_msg);
+    let memory_context = fetch_memory_from_shell(&client, agent_id, &user_msg).await;
 
     let meeting_context = fetch_meeting_context(agent_id);
 
-    let mut messages = vec![Message {
-        role: "system".to_string(),
-        content: system_prompt,
-    }];
+    let tools_enabled = env::var("LLM_TOOLS").unwrap_or_else(|_| "true".to_string()) != "false"
+        && client.supports_tools();
+    let tools = if tools_enabled {
+        agent_tools()
+    } else {
+        Vec::new()
+    };
+
+    if tools_enabled {
+        system_prompt.push_str(&build_tool_prompt());
+    }
+
+    let mut messages = vec![Message::new("system", system_prompt)];
 
     if !memory_context.is_empty() {
-        messages.push(Message {
-            role: "system".to_string(),
-            content: format!("Relevant past memories:\n{}", memory_context),
-        });
+        messages.push(Message::new(
+            "system",
+            format!("Relevant past memories:\n{}", memory_context),
+        ));
     }
 
     if !meeting_context.is_empty() {
-        messages.push(Message {
-            role: "system".to_string(),
-            content: format!("Active meeting context:\n{}", meeting_context),
-        });
+        messages.push(Message::new(
+            "system",
+            format!("Active meeting context:\n{}", meeting_context),
+        ));
     }
 
     for msg in &history {
         messages.push(msg.clone());
     }
 
-    messages.push(Message {
-        role: "user".to_string(),
-        content: user_msg,
-    });
+    let context = ContextWindow::for_model(
+        &client.primary().provider,
+        &client.primary().model,
+        max_tokens,
+        limits.max_history_messages,
+    );
+
+    // Everything from here on is the current turn and is never trimmed.
+    let turn_start = messages.len();
+    let image_limits = ImageLimits::from_env();
+    let images = attach_referenced_images(&user_msg, &image_limits);
+    if !images.is_empty() {
+        if let Err(e) = client.require(Capability::Vision) {
+            exit_with(&e, &usage);
+        }
+    }
+    messages.push(Message::with_images("user", user_msg, images));
+
+    // Final answers are held to the response contract: by the provider where it has a
+    // structured-output mode, and by validation with repair turns everywhere.
+    let json_mode = env::var("LLM_JSON_MODE").unwrap_or_else(|_| "true".to_string()) != "false";
+    let agent_client = if json_mode {
+        client.with_response_schema(contract::schema())
+    } else {
+        client.clone()
+    };
+    let max_repairs = contract::max_repairs();
+    let mut repairs = 0;
 
-    let client = LLMClient::new();
     let mut iterations = 0;
     let max_iterations = 5;
+    let mut file_action: Option<String> = None;
+    let mut panel_actions: Vec<String> = Vec::new();
 
     while iterations < max_iterations {
         iterations += 1;
 
-        match client.complete(&messages, max_tokens) {
-            Ok((response, _tokens)) => {
-                if let Some((role, task)) = extract_delegate_action(&response) {
-                    println!("[MEETING] Sub-task delegation requested...");
-                    println!("[MEETING] TARGET_ROLE: {}", role);
-                    println!("[MEETING] TASK: {}", task);
+        let (window, trim) = context.fit(&messages, turn_start);
+        if trim.dropped_messages > 0 || trim.truncated_sections > 0 {
+            eprintln!(
+                "[Context] Dropped {} history messages, truncated {} sections (~{} tokens)",
+                trim.dropped_messages, trim.truncated_sections, trim.estimated_tokens
+            );
+        }
 
-                    if hitl_enabled {
-                        println!("[HITL] DELEGATION_APPROVAL_REQUIRED for role: {}", role);
-                    }
+        let primary = client.primary();
+        if let Err(e) = usage.check(
+            &primary.provider,
+            &primary.model,
+            trim.estimated_tokens,
+            max_tokens,
+        ) {
+            exit_with(&e, &usage);
+        }
+
+        let result = cancel
+            .guard(agent_client.complete(&window, &tools, max_tokens))
+            .await
+            .and_then(|result| result);
+        if let Ok(completion) = &result {
+            report_usage(&mut usage, completion);
+        }
+
+        match result {
+            Ok(completion) if !completion.tool_calls.is_empty() => {
+                messages.push(Message {
+                    role: "assistant".to_string(),
+                    content: completion.content.clone(),
+                    tool_calls: completion.tool_calls.clone(),
+                    thinking: completion.thinking.clone(),
+                    ..Default::default()
+                });
+
+                // Tool results carry text only, so viewed images follow them as a user turn.
+                let mut viewed: Vec<(String, ImagePart)> = Vec::new();
+
+                for call in &completion.tool_calls {
+                    let result = match parse_tool_call(call) {
+                        Ok(AgentAction::RunTerminal { command }) => {
+                            println!("COMMAND: {}", command);
+                            match run_with_approval(&command, hitl_enabled, &cancel).await {
+                                Ok(output) => output,
+                                Err(e) => exit_cancelled(
+                                    &e,
+                                    file_action.as_deref(),
+                                    &panel_actions,
+                                    &usage,
+                                ),
+                            }
+                        }
+                        Ok(AgentAction::Delegate { agent_role, task }) => {
+                            log_delegation(&agent_role, &task, hitl_enabled);
+                            "Delegation request logged. Waiting for operator approval..."
+                                .to_string()
+                        }
+                        Ok(AgentAction::SendFile { filename }) => {
+                            println!("FILE:{}", filename);
+                            let result = format!("File {} queued for delivery.", filename);
+                            file_action = Some(format!("FILE:{}", filename));
+                            result
+                        }
+                        Ok(AgentAction::PanelAction { action }) => {
+                            panel_actions.push(action);
+                            "Panel action queued.".to_string()
+                        }
+                        Ok(AgentAction::ViewImage { path }) => {
+                            match client
+                                .require(Capability::Vision)
+                                .map_err(|e| e.to_string())
+                                .and_then(|_| {
+                                    vision::resolve_image(Path::new(&workspace_dir()), &path)
+                                        .ok_or_else(|| {
+                                            format!("No image at {} in the workspace", path)
+                                        })
+                                })
+                                .and_then(|file| vision::load_image(&file, &image_limits))
+                            {
+                                Ok(image) => {
+                                    viewed.push((path.clone(), image));
+                                    format!("Image {} attached below.", path)
+                                }
+                                Err(e) => format!("ERROR: {}", e),
+                            }
+                        }
+                        Err(e) => format!("ERROR: {}", e),
+                    };
+
+                    messages.push(Message::tool_result(call, result));
+                }
+
+                if !viewed.is_empty() {
+                    let names: Vec<&str> = viewed.iter().map(|(p, _)| p.as_str()).collect();
+                    messages.push(Message::with_images(
+                        "user",
+                        format!("Images requested with view_image: {}", names.join(", ")),
+                        viewed.into_iter().map(|(_, image)| image).collect(),
+                    ));
+                }
+            }
+            Ok(completion) => {
+                let response = completion.content;
+
+                if let Some((role, task)) = extract_delegate_action(&response) {
+                    log_delegation(&role, &task, hitl_enabled);
 
-                    messages.push(Message {
-                        role: "assistant".to_string(),
-                        content: response.clone(),
-                    });
-                    messages.push(Message {
-                        role: "user".to_string(),
-                        content: "Delegation request logged. Waiting for operator approval..."
-                            .to_string(),
-                    });
+                    messages.push(Message::new("assistant", response.clone()));
+                    messages.push(Message::new(
+                        "user",
+                        "Delegation request logged. Waiting for operator approval...",
+                    ));
                     continue;
                 }
 
@@ -240,81 +501,285 @@ fn main() {
                         }
                     }
 
-                    messages.push(Message {
-                        role: "assistant".to_string(),
-                        content: response.clone(),
-                    });
+                    messages.push(Message::new("assistant", response.clone()));
 
-                    let needs_approval = tools::is_dangerous_command(&cmd);
-
-                    if needs_approval && hitl_enabled {
-                        println!("[HITL] APPROVAL_REQUIRED: {}", cmd);
-
-                        let approved = wait_for_approval(600);
-
-                        if !approved {
-                            let error_msg = "ERROR: Command denied by user".to_string();
-                            messages.push(Message {
-                                role: "user".to_string(),
-                                content: error_msg,
-                            });
-                            continue;
-                        }
-
-                        println!("[HITL] EXECUTING: {}", cmd);
-                    }
-
-                    match execute_command(&cmd) {
-                        Ok(output) => {
-                            let output_msg = format!("COMMAND_OUTPUT:\n{}", output);
-                            messages.push(Message {
-                                role: "user".to_string(),
-                                content: output_msg,
-                            });
-                        }
+                    let output = match run_with_approval(&cmd, hitl_enabled, &cancel).await {
+                        Ok(output) => output,
                         Err(e) => {
-                            let error_msg = format!("ERROR: {}", e);
-                            messages.push(Message {
-                                role: "user".to_string(),
-                                content: error_msg,
-                            });
+                            exit_cancelled(&e, file_action.as_deref(), &panel_actions, &usage)
                         }
-                    }
+                    };
+                    messages.push(Message::new("user", output));
                 } else {
-                    println!("{}", response);
+                    let reply = match contract::validate(&response) {
+                        Ok(reply) => reply,
+                        Err(errors) if repairs < max_repairs => {
+                            repairs += 1;
+                            eprintln!(
+                                "[Contract] Reply rejected ({}), asking for a repair ({}/{})",
+                                errors.join("; "),
+                                repairs,
+                                max_repairs
+                            );
+                            messages.push(Message::new("assistant", response.clone()));
+                            messages.push(Message::new("user", contract::repair_prompt(&errors)));
+                            // Repair turns do not use up the agent's own iterations.
+                            iterations -= 1;
+                            continue;
+                        }
+                        Err(errors) => {
+                            eprintln!(
+                                "[Contract] Reply still invalid ({}), wrapping its text",
+                                errors.join("; ")
+                            );
+                            contract::fallback(&response)
+                        }
+                    };
+
+                    println!(
+                        "{}",
+                        finalize_response(
+                            &reply.to_string(),
+                            file_action.as_deref(),
+                            &panel_actions
+                        )
+                    );
                     break;
                 }
             }
-            Err(e) => {
-                eprintln!("Error: {}", e);
-                std::process::exit(1);
+            Err(e @ CrabError::Cancelled(_)) => {
+                exit_cancelled(&e, file_action.as_deref(), &panel_actions, &usage)
             }
+            Err(e) => exit_with(&e, &usage),
         }
     }
 
+    usage.print_summary();
     if iterations >= max_iterations {
         eprintln!("Max iterations reached");
         std::process::exit(1);
     }
 }
 
-fn fetch_memory_from_shell(agent_id: i32, _query: &str) -> String {
-    if agent_id == 0 {
-        return String::new();
+/// Lets the orchestrator bill and audit against whoever actually answered.
+fn report_usage(usage: &mut UsageTracker, completion: &Completion) {
+    let cost = usage.record(completion);
+    println!(
+        "[LLM] {}",
+        serde_json::json!({
+            "provider": completion.provider,
+            "model": completion.model,
+            "tokens": completion.usage.total(),
+            "inputTokens": completion.usage.input_tokens,
+            "outputTokens": completion.usage.output_tokens,
+            "cachedTokens": completion.usage.cached_tokens,
+            "reasoningTokens": completion.usage.reasoning_tokens,
+            "costUsd": cost,
+        })
+    );
+
+    // Reasoning is kept for the audit trail only; it never reaches the reply.
+    if !completion.reasoning.is_empty() {
+        println!(
+            "[REASONING] {}",
+            serde_json::json!({
+                "provider": completion.provider,
+                "model": completion.model,
+                "tokens": completion.usage.reasoning_tokens,
+                "text": completion.reasoning,
+            })
+        );
     }
+}
 
-    let memory_file = format!("{}/memory_{}.json", WORKSPACE_DIR, agent_id);
-    if let Ok(contents) = fs::read_to_string(&memory_file) {
-        if let Ok(memories) = serde_json::from_str::<Vec<MemoryEntry>>(&contents) {
-            return memories
-                .iter()
-                .map(|m| format!("- {}", m.content))
-                .collect::<Vec<_>>()
-                .join("\n");
+/// Summarizes older turns once the history is over budget and writes the result back:
+/// to `history_file` when there is one, otherwise as a `[HISTORY]` line for the
+/// orchestrator to store. Returns the history to use for this run; on failure or
+/// cancellation the original history is kept and the context window trims it instead.
+async fn compact_history(
+    client: &LLMClient,
+    usage: &mut UsageTracker,
+    cancel: &CancelToken,
+    history: Vec<Message>,
+    history_file: &str,
+) -> Vec<Message> {
+    let config = CompactionConfig::from_env();
+    let primary = client.primary();
+    let family = ModelFamily::of(&primary.model);
+    if !compact::needs_compaction(&history, family, &config) {
+        return history;
+    }
+
+    let input_tokens = history.iter().map(|m| message_tokens(m, family)).sum();
+    if let Err(e) = usage.check(
+        &primary.provider,
+        &primary.model,
+        input_tokens,
+        config.max_summary_tokens,
+    ) {
+        eprintln!("Warning: Skipping history compaction: {}", e);
+        return history;
+    }
+
+    let compaction = cancel
+        .guard(compact::compact(client, &history, &config))
+        .await
+        .and_then(|result| result);
+    let (compacted, completion) = match compaction {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Warning: History compaction failed: {}", e);
+            return history;
         }
+    };
+    report_usage(usage, &completion);
+    eprintln!(
+        "[Context] Compacted history into a summary plus {} recent messages",
+        compacted.len() - 1
+    );
+
+    let json = serde_json::to_string(&compacted).unwrap_or_default();
+    if history_file.is_empty() {
+        use base64::Engine;
+        println!(
+            "{} {}",
+            HISTORY_MARKER,
+            base64::engine::general_purpose::STANDARD.encode(json)
+        );
+    } else if let Err(e) = fs::write(history_file, json) {
+        eprintln!("Warning: Could not write compacted history: {}", e);
     }
 
-    String::new()
+    // Injected memories are not persisted but still belong in this run's context.
+    history
+        .into_iter()
+        .filter(|m| m.role == "system" && !m.content.starts_with(SUMMARY_PREFIX))
+        .chain(compacted)
+        .collect()
+}
+
+/// Reports `error` to the orchestrator as an `[ERROR]` line and a human-readable
+/// message, after the usage so far, then exits with the code for its kind.
+fn exit_with(error: &CrabError, usage: &UsageTracker) -> ! {
+    usage.print_summary();
+    println!("{}", error.report());
+    eprintln!("Error: {}", error);
+    std::process::exit(error.exit_code());
+}
+
+/// Ends a stopped run with an ordinary reply, so the orchestrator still stores a
+/// complete exchange, then reports the cancellation like any other error. Files and
+/// panel actions queued before the stop are kept.
+fn exit_cancelled(
+    error: &CrabError,
+    file_action: Option<&str>,
+    panel_actions: &[String],
+    usage: &UsageTracker,
+) -> ! {
+    let reason = match error {
+        CrabError::Cancelled(reason) => reason.as_str(),
+        _ => "unknown reason",
+    };
+    let reply = contract::fallback(&format!("Stopped before finishing ({}).", reason));
+    println!(
+        "{}",
+        finalize_response(&reply.to_string(), file_action, panel_actions)
+    );
+    exit_with(error, usage)
+}
+
+fn log_delegation(role: &str, task: &str, hitl_enabled: bool) {
+    println!("[MEETING] Sub-task delegation requested...");
+    println!("[MEETING] TARGET_ROLE: {}", role);
+    println!("[MEETING] TASK: {}", task);
+
+    if hitl_enabled {
+        println!("[HITL] DELEGATION_APPROVAL_REQUIRED for role: {}", role);
+    }
+}
+
+/// Runs `cmd`, pausing for operator approval first when it is dangerous and HITL is on.
+/// Returns the text to feed back to the model, or `CrabError::Cancelled` if the run is
+/// stopped while waiting or while the command runs.
+async fn run_with_approval(
+    cmd: &str,
+    hitl_enabled: bool,
+    cancel: &CancelToken,
+) -> Result<String, CrabError> {
+    let needs_approval = tools::is_dangerous_command(cmd);
+
+    if needs_approval && hitl_enabled {
+        println!("[HITL] APPROVAL_REQUIRED: {}", cmd);
+
+        if !cancel.guard(wait_for_approval(600)).await? {
+            return Ok("ERROR: Command denied by user".to_string());
+        }
+
+        println!("[HITL] EXECUTING: {}", cmd);
+    }
+
+    Ok(match cancel.guard(execute_command(cmd)).await? {
+        Ok(output) => format!("COMMAND_OUTPUT:\n{}", output),
+        Err(e) => format!("ERROR: {}", e),
+    })
+}
+
+/// Folds actions collected from tool calls into the final response contract so the
+/// orchestrator sees them even when the model's last turn did not repeat them.
+fn finalize_response(
+    response: &str,
+    file_action: Option<&str>,
+    panel_actions: &[String],
+) -> String {
+    if file_action.is_none() && panel_actions.is_empty() {
+        return response.to_string();
+    }
+
+    let mut parsed = match serde_json::from_str::<serde_json::Value>(response) {
+        Ok(value) if value.is_object() => value,
+        _ => serde_json::json!({
+            "message": response.trim(),
+            "action": "",
+            "terminal": "",
+            "panelActions": [],
+        }),
+    };
+
+    if let Some(action) = file_action {
+        if parsed["action"].as_str().unwrap_or_default().is_empty() {
+            parsed["action"] = serde_json::json!(action);
+        }
+    }
+
+    if !panel_actions.is_empty() {
+        let mut merged: Vec<serde_json::Value> = parsed["panelActions"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        for action in panel_actions {
+            if !merged.iter().any(|v| v.as_str() == Some(action)) {
+                merged.push(serde_json::json!(action));
+            }
+        }
+        parsed["panelActions"] = serde_json::Value::Array(merged);
+    }
+
+    parsed.to_string()
+}
+
+async fn fetch_memory_from_shell(client: &LLMClient, agent_id: i32, query: &str) -> String {
+    if agent_id == 0 {
+        return String::new();
+    }
+
+    let memory_file = format!("{}/memory_{}.json", workspace_dir(), agent_id);
+    MemoryStore::new(PathBuf::from(memory_file))
+        .relevant(client, query, memory::top_k_from_env())
+        .await
+        .iter()
+        .map(|content| format!("- {}", content))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 fn fetch_meeting_context(agent_id: i32) -> String {
@@ -322,7 +787,7 @@ fn fetch_meeting_context(agent_id: i32) -> String {
         return String::new();
     }
 
-    let meeting_file = format!("{}/meeting_context_{}.json", WORKSPACE_DIR, agent_id);
+    let meeting_file = format!("{}/meeting_context_{}.json", workspace_dir(), agent_id);
     if let Ok(contents) = fs::read_to_string(&meeting_file) {
         if let Ok(meetings) = serde_json::from_str::<Vec<MeetingContext>>(&contents) {
             return meetings
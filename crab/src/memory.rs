@@ -0,0 +1,206 @@
+//! The agent's long-term memories, read from `memory_{agent}.json` in the workspace.
+//! Only the `MEMORY_TOP_K` (default 5) most similar to the user's message go into
+//! the prompt. Each memory's vector is saved next to it with the model that made it,
+//! so a memory is embedded once rather than on every run.
+
+use crate::embeddings::{self, LOCAL_MODEL};
+use crate::llm::LLMClient;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const DEFAULT_TOP_K: usize = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryEntry {
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vector: Vec<f32>,
+    /// The model `vector` came from; vectors from different models do not compare.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding_model: Option<String>,
+    /// Whatever else the orchestrator stored, kept when the file is rewritten.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl MemoryEntry {
+    fn has_vector_from(&self, model: &str) -> bool {
+        !self.vector.is_empty() && self.embedding_model.as_deref() == Some(model)
+    }
+}
+
+pub fn top_k_from_env() -> usize {
+    env::var("MEMORY_TOP_K")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_TOP_K)
+}
+
+pub struct MemoryStore {
+    path: PathBuf,
+}
+
+impl MemoryStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn load(&self) -> Vec<MemoryEntry> {
+        fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    /// Failing to save only means the vectors are computed again next time.
+    fn save(&self, entries: &[MemoryEntry]) {
+        let written = serde_json::to_string(entries)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&self.path, json).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            eprintln!("Warning: Could not save memory vectors: {}", e);
+        }
+    }
+
+    /// The `k` memories most relevant to `query`, best first. With no more than `k`
+    /// memories all of them are returned and nothing is embedded.
+    pub async fn relevant(&self, client: &LLMClient, query: &str, k: usize) -> Vec<String> {
+        let mut entries = self.load();
+        if entries.len() <= k {
+            return entries.into_iter().map(|entry| entry.content).collect();
+        }
+
+        let (query_vector, vectors, changed) =
+            match embed_with_provider(client, query, &mut entries).await {
+                Some((query_vector, changed)) => {
+                    let vectors = entries.iter().map(|entry| entry.vector.clone()).collect();
+                    (query_vector, vectors, changed)
+                }
+                None => embed_locally(query, &mut entries),
+            };
+        if changed {
+            self.save(&entries);
+        }
+
+        embeddings::top_k(&query_vector, &vectors, k)
+            .into_iter()
+            .map(|i| entries[i].content.clone())
+            .collect()
+    }
+}
+
+/// Embeds the query and any memory without a vector from the provider's model.
+/// Returns the query vector and whether any memory changed, or `None` when the
+/// provider cannot embed.
+async fn embed_with_provider(
+    client: &LLMClient,
+    query: &str,
+    entries: &mut [MemoryEntry],
+) -> Option<(Vec<f32>, bool)> {
+    let model = client.embedding_model()?;
+    let stale: Vec<usize> = (0..entries.len())
+        .filter(|&i| !entries[i].has_vector_from(&model))
+        .collect();
+    let mut texts = vec![query.to_string()];
+    texts.extend(stale.iter().map(|&i| entries[i].content.clone()));
+
+    let mut vectors = match client.embed(&texts).await {
+        Ok(vectors) if vectors.len() == texts.len() => vectors,
+        Ok(vectors) => {
+            eprintln!(
+                "Warning: Got {} embeddings for {} texts; ranking memories locally",
+                vectors.len(),
+                texts.len()
+            );
+            return None;
+        }
+        Err(e) => {
+            eprintln!(
+                "Warning: Could not embed memories ({}); ranking them locally",
+                e
+            );
+            return None;
+        }
+    };
+
+    for (&i, vector) in stale.iter().zip(vectors.drain(1..)) {
+        entries[i].vector = vector;
+        entries[i].embedding_model = Some(model.clone());
+    }
+    Some((vectors.remove(0), !stale.is_empty()))
+}
+
+/// Ranks with local embeddings. Memories that already have a provider's vector keep
+/// it in the file; only those with none get the local one saved.
+fn embed_locally(query: &str, entries: &mut [MemoryEntry]) -> (Vec<f32>, Vec<Vec<f32>>, bool) {
+    let mut changed = false;
+    let vectors = entries
+        .iter_mut()
+        .map(|entry| {
+            if entry.has_vector_from(LOCAL_MODEL) {
+                return entry.vector.clone();
+            }
+            let vector = embeddings::local_embedding(&entry.content);
+            if entry.vector.is_empty() {
+                entry.vector = vector.clone();
+                entry.embedding_model = Some(LOCAL_MODEL.to_string());
+                changed = true;
+            }
+            vector
+        })
+        .collect();
+    (embeddings::local_embedding(query), vectors, changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ProviderTarget;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn relevant_memories_rank_above_unrelated_ones() {
+        let path = env::temp_dir().join(format!("crab-memory-{}.json", std::process::id()));
+        let memories = json!([
+            { "content": "The user's favourite colour is green", "id": 1 },
+            { "content": "Deployments go to the Kubernetes cluster in eu-west-1", "id": 2 },
+            { "content": "Lunch is usually at noon" },
+            { "content": "The user prefers pytest fixtures over unittest classes for Python tests" },
+            { "content": "The office wifi password changes every month" },
+        ]);
+        fs::write(&path, memories.to_string()).unwrap();
+        let store = MemoryStore::new(path.clone());
+        // The proxy has no embeddings endpoint, so this ranks with local embeddings.
+        let client = LLMClient::stub(vec![ProviderTarget::stub(
+            "proxy",
+            "default",
+            "http://127.0.0.1:9",
+        )]);
+
+        let found = store
+            .relevant(&client, "Write Python tests for the parser using pytest", 2)
+            .await;
+        assert_eq!(
+            found[0],
+            "The user prefers pytest fixtures over unittest classes for Python tests"
+        );
+        let found = store
+            .relevant(&client, "Deploy the new build to the kubernetes cluster", 1)
+            .await;
+        assert_eq!(
+            found,
+            vec!["Deployments go to the Kubernetes cluster in eu-west-1"]
+        );
+
+        let saved: Vec<MemoryEntry> =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(saved.iter().all(|entry| entry.has_vector_from(LOCAL_MODEL)));
+        assert_eq!(saved[1].extra["id"], 2);
+
+        let _ = fs::remove_file(&path);
+    }
+}
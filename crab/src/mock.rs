@@ -0,0 +1,283 @@
+//! Scripted `mock` provider for offline, deterministic runs of the agent loop.
+//!
+//! `LLM_MOCK_SCRIPT` points at a JSON array of canned replies:
+//!
+//! ```json
+//! [
+//!   { "match": "(?i)list files", "toolCalls": [{ "name": "run_terminal", "arguments": { "command": "ls" } }] },
+//!   { "content": "Here you go." },
+//!   { "error": { "status": 429, "body": "slow down" } }
+//! ]
+//! ```
+//!
+//! Entries with a `match` regex answer a new user message that it matches, and can be
+//! used any number of times. The others are served once each, in order, and also
+//! answer every turn that follows a tool result, so a keyed tool call is not repeated
+//! on each pass of the agent loop.
+//! Every request is kept in memory and, if `LLM_MOCK_RECORD` is set, appended to that
+//! file as one JSON line.
+
+use crate::error::CrabError;
+use crate::llm::{Completion, Message, ToolCall, ToolDefinition, Usage};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+const DEFAULT_SCRIPT: &str = "/app/workspace/mock.json";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ScriptEntry {
+    #[serde(rename = "match")]
+    pattern: Option<String>,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    tool_calls: Vec<ScriptedCall>,
+    error: Option<ScriptedError>,
+    #[serde(default)]
+    usage: ScriptedUsage,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ScriptedCall {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+/// Replayed through the same classification as a real provider error.
+#[derive(Debug, Clone, Deserialize)]
+struct ScriptedError {
+    status: u16,
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct ScriptedUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+#[derive(Debug, Default)]
+struct Script {
+    keyed: Vec<(Regex, ScriptEntry)>,
+    ordered: VecDeque<ScriptEntry>,
+}
+
+impl Script {
+    fn parse(source: &str) -> Result<Self, CrabError> {
+        let entries: Vec<ScriptEntry> = serde_json::from_str(source)
+            .map_err(|e| CrabError::Config(format!("Invalid mock script: {}", e)))?;
+
+        let mut script = Script::default();
+        for entry in entries {
+            match &entry.pattern {
+                Some(pattern) => {
+                    let regex = Regex::new(pattern).map_err(|e| {
+                        CrabError::Config(format!("Invalid mock pattern '{}': {}", pattern, e))
+                    })?;
+                    script.keyed.push((regex, entry));
+                }
+                None => script.ordered.push_back(entry),
+            }
+        }
+        Ok(script)
+    }
+
+    fn next(&mut self, user_turn: Option<&str>) -> Option<ScriptEntry> {
+        self.keyed
+            .iter()
+            .find(|(regex, _)| user_turn.is_some_and(|text| regex.is_match(text)))
+            .map(|(_, entry)| entry.clone())
+            .or_else(|| self.ordered.pop_front())
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    script: Option<Script>,
+    calls: usize,
+    requests: Vec<Value>,
+}
+
+/// Shared by clones of an `LLMClient` so the script is consumed once per run.
+#[derive(Debug)]
+pub struct MockProvider {
+    script_path: String,
+    record_path: Option<String>,
+    state: Mutex<State>,
+}
+
+impl MockProvider {
+    pub fn from_env() -> Self {
+        Self {
+            script_path: env::var("LLM_MOCK_SCRIPT").unwrap_or_else(|_| DEFAULT_SCRIPT.to_string()),
+            record_path: env::var("LLM_MOCK_RECORD").ok().filter(|p| !p.is_empty()),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn from_script(source: &str) -> Self {
+        Self {
+            script_path: String::new(),
+            record_path: None,
+            state: Mutex::new(State {
+                script: Some(Script::parse(source).expect("valid mock script")),
+                ..State::default()
+            }),
+        }
+    }
+
+    /// The requests received so far, as recorded.
+    #[cfg(test)]
+    pub fn requests(&self) -> Vec<Value> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Answers from the script. The script is read on first use so a bad or missing
+    /// file surfaces as a config error from the call, like any other provider.
+    pub fn complete(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+        max_tokens: u32,
+    ) -> Result<Completion, CrabError> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let request = json!({
+            "messages": messages,
+            "tools": tools.iter().map(|t| t.name).collect::<Vec<_>>(),
+            "maxTokens": max_tokens,
+        });
+        self.record(&request);
+        state.requests.push(request);
+
+        if state.script.is_none() {
+            let source = fs::read_to_string(&self.script_path).map_err(|e| {
+                CrabError::Config(format!(
+                    "Could not read mock script {}: {}",
+                    self.script_path, e
+                ))
+            })?;
+            state.script = Some(Script::parse(&source)?);
+        }
+
+        let user_turn = messages
+            .last()
+            .filter(|m| m.role == "user")
+            .map(|m| m.content.as_str());
+        let entry = state
+            .script
+            .as_mut()
+            .and_then(|script| script.next(user_turn))
+            .ok_or_else(|| {
+                CrabError::InvalidResponse(format!(
+                    "Mock script has no reply for request {}",
+                    state.calls + 1
+                ))
+            })?;
+        state.calls += 1;
+
+        if let Some(error) = entry.error {
+            return Err(CrabError::from_api_error(
+                Some(error.status),
+                &error.body,
+                None,
+            ));
+        }
+
+        let turn = state.calls;
+        Ok(Completion {
+            content: entry.content,
+            tool_calls: entry
+                .tool_calls
+                .into_iter()
+                .enumerate()
+                .map(|(i, call)| ToolCall {
+                    id: format!("mock_call_{}_{}", turn, i),
+                    name: call.name,
+                    arguments: call.arguments,
+                })
+                .collect(),
+            usage: Usage {
+                input_tokens: entry.usage.input_tokens,
+                output_tokens: entry.usage.output_tokens,
+                cached_tokens: 0,
+                reasoning_tokens: 0,
+            },
+            ..Default::default()
+        })
+    }
+
+    fn record(&self, request: &Value) {
+        let Some(path) = &self.record_path else {
+            return;
+        };
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", request));
+        if let Err(e) = written {
+            eprintln!("Warning: Could not record mock request: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"[
+        { "match": "(?i)weather", "content": "Always sunny." },
+        { "toolCalls": [{ "name": "run_terminal", "arguments": { "command": "ls" } }] },
+        { "content": "Found a.txt", "usage": { "inputTokens": 20, "outputTokens": 3 } },
+        { "error": { "status": 429, "body": "{\"error\": {\"message\": \"slow down\"}}" } }
+    ]"#;
+
+    #[test]
+    fn serves_keyed_replies_then_ordered_ones_and_records_requests() {
+        let mock = MockProvider::from_script(SCRIPT);
+        let ask = |text: &str| mock.complete(&[Message::new("user", text)], &[], 100);
+
+        assert_eq!(ask("What's the weather?").unwrap().content, "Always sunny.");
+
+        let first = ask("list my files").unwrap();
+        assert_eq!(first.tool_calls[0].name, "run_terminal");
+        assert_eq!(first.tool_calls[0].arguments["command"], "ls");
+
+        // Keyed entries are not used up, but only answer the user.
+        assert_eq!(ask("weather again").unwrap().content, "Always sunny.");
+        let call = &first.tool_calls[0];
+        let after_tool = mock
+            .complete(
+                &[
+                    Message::new("user", "weather?"),
+                    Message::tool_result(call, "a.txt"),
+                ],
+                &[],
+                100,
+            )
+            .unwrap();
+        assert_eq!(after_tool.content, "Found a.txt");
+
+        assert_eq!(after_tool.usage.total(), 23);
+
+        assert!(matches!(ask("again"), Err(CrabError::RateLimited { .. })));
+        assert!(matches!(ask("more"), Err(CrabError::InvalidResponse(_))));
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 6);
+        assert_eq!(requests[1]["messages"][0]["content"], "list my files");
+        assert_eq!(requests[1]["maxTokens"], 100);
+    }
+}
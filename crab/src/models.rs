@@ -0,0 +1,207 @@
+//! What each model can do: context window, output limit, vision, tool calling, JSON
+//! mode, streaming, whether it is an OpenAI-style reasoning model, and price.
+//! The bundled table (`models.json`) is extended by `LLM_MODELS_FILE` (default
+//! `/app/config/models.json`), whose entries take precedence:
+//!
+//! ```json
+//! [{ "match": "my-finetune", "provider": "openai-compatible", "contextLength": 16384,
+//!    "vision": false, "tools": true, "price": { "input": 0, "output": 0, "cached": 0 } }]
+//! ```
+//!
+//! `match` is a prefix of the model id (after any `vendor/` part); `provider` is
+//! optional. Each field comes from the first matching entry that sets it, so a
+//! specific entry only needs what differs from a broader one. A field no entry sets
+//! is unknown, and unknown capabilities are assumed to be there.
+
+use crate::error::CrabError;
+use crate::pricing::Price;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::sync::OnceLock;
+
+const BUNDLED: &str = include_str!("models.json");
+
+const DEFAULT_MODELS_FILE: &str = "/app/config/models.json";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Entry {
+    #[serde(rename = "match")]
+    prefix: String,
+    provider: Option<String>,
+    context_length: Option<u32>,
+    max_output_tokens: Option<u32>,
+    vision: Option<bool>,
+    tools: Option<bool>,
+    json_mode: Option<bool>,
+    streaming: Option<bool>,
+    reasoning: Option<bool>,
+    price: Option<Price>,
+}
+
+/// Everything known about one model; `None` where nothing is known.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelInfo {
+    pub context_length: Option<u32>,
+    pub max_output_tokens: Option<u32>,
+    pub vision: Option<bool>,
+    pub tools: Option<bool>,
+    pub json_mode: Option<bool>,
+    pub streaming: Option<bool>,
+    /// Takes `max_completion_tokens` and `reasoning_effort` instead of `max_tokens`
+    /// and sampling settings.
+    pub reasoning: Option<bool>,
+    pub price: Option<Price>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    Vision,
+    Tools,
+    JsonMode,
+    Streaming,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capability::Vision => "image input",
+            Capability::Tools => "tool calling",
+            Capability::JsonMode => "JSON mode",
+            Capability::Streaming => "streaming",
+        })
+    }
+}
+
+impl ModelInfo {
+    /// False only when the registry says the model lacks `capability`.
+    pub fn supports(&self, capability: Capability) -> bool {
+        let known = match capability {
+            Capability::Vision => self.vision,
+            Capability::Tools => self.tools,
+            Capability::JsonMode => self.json_mode,
+            Capability::Streaming => self.streaming,
+        };
+        known != Some(false)
+    }
+}
+
+/// The error for an agent configured to use `capability` with a model that lacks it.
+pub fn unsupported(provider: &str, model: &str, capability: Capability) -> CrabError {
+    CrabError::Config(format!(
+        "Model '{}' on '{}' does not support {}; choose another LLM_MODEL or add an entry to LLM_MODELS_FILE if this is wrong",
+        model, provider, capability
+    ))
+}
+
+#[derive(Debug)]
+pub struct Registry {
+    entries: Vec<Entry>,
+}
+
+fn base_name(model: &str) -> String {
+    model
+        .rsplit('/')
+        .next()
+        .unwrap_or(model)
+        .to_ascii_lowercase()
+}
+
+impl Registry {
+    fn parse(json: &str) -> Result<Vec<Entry>, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    pub fn bundled() -> Self {
+        Self {
+            entries: Self::parse(BUNDLED).expect("bundled models.json is valid"),
+        }
+    }
+
+    /// The bundled table under the entries of `LLM_MODELS_FILE`. A file that does
+    /// not parse is reported and ignored.
+    fn load() -> Self {
+        let mut registry = Self::bundled();
+        let path = env::var("LLM_MODELS_FILE").unwrap_or_else(|_| DEFAULT_MODELS_FILE.to_string());
+        if let Ok(contents) = fs::read_to_string(&path) {
+            match Self::parse(&contents) {
+                Ok(mut entries) => {
+                    entries.append(&mut registry.entries);
+                    registry.entries = entries;
+                }
+                Err(e) => eprintln!("Warning: Ignoring invalid models file {}: {}", path, e),
+            }
+        }
+        registry
+    }
+
+    pub fn global() -> &'static Registry {
+        static REGISTRY: OnceLock<Registry> = OnceLock::new();
+        REGISTRY.get_or_init(Registry::load)
+    }
+
+    pub fn lookup(&self, provider: &str, model: &str) -> ModelInfo {
+        let name = base_name(model);
+        let mut info = ModelInfo::default();
+        for entry in self.entries.iter().filter(|entry| {
+            name.starts_with(&entry.prefix.to_ascii_lowercase())
+                && entry.provider.as_deref().is_none_or(|p| p == provider)
+        }) {
+            info.context_length = info.context_length.or(entry.context_length);
+            info.max_output_tokens = info.max_output_tokens.or(entry.max_output_tokens);
+            info.vision = info.vision.or(entry.vision);
+            info.tools = info.tools.or(entry.tools);
+            info.json_mode = info.json_mode.or(entry.json_mode);
+            info.streaming = info.streaming.or(entry.streaming);
+            info.reasoning = info.reasoning.or(entry.reasoning);
+            info.price = info.price.or(entry.price);
+        }
+        info
+    }
+}
+
+/// `model` on `provider` in the global registry.
+pub fn lookup(provider: &str, model: &str) -> ModelInfo {
+    Registry::global().lookup(provider, model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_win_field_by_field_over_the_bundled_table() {
+        let mut registry = Registry::bundled();
+        let bundled = registry.lookup("openai", "gpt-4o-mini-2024-07-18");
+        assert_eq!(bundled.context_length, Some(128_000));
+        assert_eq!(bundled.vision, Some(true));
+        assert_eq!(bundled.reasoning, None);
+        assert_eq!(
+            registry.lookup("azure", "o4-mini-2025-04-16").reasoning,
+            Some(true)
+        );
+        assert!(!registry
+            .lookup("deepseek", "deepseek-reasoner")
+            .supports(Capability::Tools));
+        assert!(registry
+            .lookup("ollama", "brand-new-model")
+            .supports(Capability::Vision));
+
+        let mut overrides = Registry::parse(
+            r#"[{ "match": "gpt-4o-mini", "provider": "azure", "vision": false, "contextLength": 64000 }]"#,
+        )
+        .unwrap();
+        overrides.append(&mut registry.entries);
+        registry.entries = overrides;
+
+        let azure = registry.lookup("azure", "gpt-4o-mini");
+        assert_eq!(azure.context_length, Some(64_000));
+        assert!(!azure.supports(Capability::Vision));
+        assert_eq!(azure.tools, Some(true));
+        assert_eq!(azure.price, bundled.price);
+        assert_eq!(registry.lookup("openai", "gpt-4o-mini"), bundled);
+        assert!(Registry::parse(r#"[{ "match": "x", "vison": true }]"#).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use crate::llm::Usage;
+use crate::models;
+use serde::Deserialize;
+
+/// Prices in USD per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+    pub cached: f64,
+}
+
+impl Price {
+    const FREE: Price = Price {
+        input: 0.0,
+        output: 0.0,
+        cached: 0.0,
+    };
+
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        (usage.input_tokens as f64 * self.input
+            + usage.output_tokens as f64 * self.output
+            + usage.cached_tokens as f64 * self.cached)
+            / 1_000_000.0
+    }
+}
+
+/// Price for `model` on `provider`, from the model registry. Local servers are free;
+/// the orchestrator proxy and unknown models have no price, so their cost is
+/// reported as unknown.
+pub fn price_for(provider: &str, model: &str) -> Option<Price> {
+    match provider {
+        "ollama" | "openai-compatible" | "mock" => return Some(Price::FREE),
+        "proxy" => return None,
+        _ => {}
+    }
+
+    models::lookup(provider, model).price
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prices_input_output_and_cached_tokens_separately() {
+        let price = price_for("openrouter", "anthropic/claude-3.5-sonnet").unwrap();
+        let usage = Usage {
+            input_tokens: 1_000_000,
+            output_tokens: 100_000,
+            cached_tokens: 2_000_000,
+            reasoning_tokens: 0,
+        };
+        assert!((price.cost(&usage) - (3.00 + 1.50 + 0.60)).abs() < 1e-9);
+
+        assert_eq!(
+            price_for("openai", "gpt-4o-mini-2024-07-18").unwrap().input,
+            0.15
+        );
+        assert_eq!(price_for("ollama", "llama3.1"), Some(Price::FREE));
+        assert_eq!(price_for("openrouter", "auto"), None);
+    }
+}
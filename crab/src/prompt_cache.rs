@@ -0,0 +1,150 @@
+//! Prompt caching for the system prompt, which is resent unchanged on every loop
+//! iteration. Anthropic caches what `cache_control` marks and OpenAI caches long
+//! prompt prefixes by itself, but Gemini needs a `cachedContents` resource created up
+//! front. Those resources are remembered in `GEMINI_CACHE_FILE` (default
+//! `/app/workspace/data/gemini_cache.json`) so later runs reuse them until they expire.
+//! `LLM_PROMPT_CACHE=false` turns caching off.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_REGISTRY: &str = "/app/workspace/data/gemini_cache.json";
+
+/// How long a Gemini cache lives. Messages to an agent tend to come in bursts.
+pub const GEMINI_CACHE_TTL: Duration = Duration::from_secs(600);
+
+/// A cache this close to expiring is not reused, so it cannot lapse mid-request.
+const EXPIRY_MARGIN_SECS: u64 = 30;
+
+pub fn enabled() -> bool {
+    env::var("LLM_PROMPT_CACHE").unwrap_or_default() != "false"
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cached {
+    /// The resource name to send as `cachedContent`.
+    Name(String),
+    /// Gemini would not cache this prompt, usually because it is below the minimum size.
+    Unavailable,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    /// Empty when the prompt could not be cached.
+    name: String,
+    expires_at: u64,
+}
+
+/// Gemini caches created by earlier calls, keyed by model and cached content.
+#[derive(Debug, Clone)]
+pub struct CacheRegistry {
+    path: PathBuf,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl CacheRegistry {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(PathBuf::from(
+            env::var("GEMINI_CACHE_FILE").unwrap_or_else(|_| DEFAULT_REGISTRY.to_string()),
+        ))
+    }
+
+    pub fn key(model: &str, content: &Value) -> String {
+        let mut hasher = DefaultHasher::new();
+        model.hash(&mut hasher);
+        content.to_string().hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    fn load(&self) -> HashMap<String, Entry> {
+        fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn lookup(&self, key: &str) -> Option<Cached> {
+        let entry = self
+            .load()
+            .remove(key)
+            .filter(|entry| entry.expires_at > now() + EXPIRY_MARGIN_SECS)?;
+        Some(if entry.name.is_empty() {
+            Cached::Unavailable
+        } else {
+            Cached::Name(entry.name)
+        })
+    }
+
+    /// Remembers `cached` under `key` for `ttl`, dropping entries that have expired.
+    /// Failing to write only means the next run creates the cache again.
+    pub fn store(&self, key: &str, cached: &Cached, ttl: Duration) {
+        let now = now();
+        let mut entries = self.load();
+        entries.retain(|_, entry| entry.expires_at > now);
+        entries.insert(
+            key.to_string(),
+            Entry {
+                name: match cached {
+                    Cached::Name(name) => name.clone(),
+                    Cached::Unavailable => String::new(),
+                },
+                expires_at: now + ttl.as_secs(),
+            },
+        );
+
+        let written = serde_json::to_string(&entries)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&self.path, json).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            eprintln!("Warning: Could not save Gemini cache registry: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn remembers_caches_until_shortly_before_they_expire() {
+        let path = env::temp_dir().join(format!("crab-gemini-cache-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let registry = CacheRegistry::new(path.clone());
+
+        let prompt = CacheRegistry::key("gemini-2.5-flash", &json!({ "text": "You are Crab." }));
+        let tiny = CacheRegistry::key("gemini-2.5-flash", &json!({ "text": "Hi." }));
+        let expiring = CacheRegistry::key("gemini-2.5-pro", &json!({ "text": "You are Crab." }));
+        assert_ne!(prompt, expiring);
+        assert_eq!(registry.lookup(&prompt), None);
+
+        let name = Cached::Name("cachedContents/abc".to_string());
+        registry.store(&prompt, &name, GEMINI_CACHE_TTL);
+        registry.store(&tiny, &Cached::Unavailable, GEMINI_CACHE_TTL);
+        registry.store(&expiring, &name, Duration::from_secs(EXPIRY_MARGIN_SECS));
+
+        assert_eq!(registry.lookup(&prompt), Some(name));
+        assert_eq!(registry.lookup(&tiny), Some(Cached::Unavailable));
+        assert_eq!(registry.lookup(&expiring), None);
+
+        let _ = fs::remove_file(&path);
+    }
+}
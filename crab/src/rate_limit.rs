@@ -0,0 +1,297 @@
+//! Client-side rate limiting shared by every crab process in the cubicle. Each
+//! provider gets token buckets for requests and tokens per minute
+//! (`LLM_RATE_LIMIT_RPM`, `LLM_RATE_LIMIT_TPM`). Their state lives in
+//! `LLM_RATE_LIMIT_FILE` (default `/app/workspace/data/rate_limit.json`) and is only
+//! touched under an exclusive `flock`, so concurrent runs draw on one budget and wait
+//! their turn instead of tripping the provider's limits.
+
+use crate::locked_file::with_locked_state;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+const DEFAULT_STATE_FILE: &str = "/app/workspace/data/rate_limit.json";
+
+/// Upper bound on a single sleep, so a waiting run rechecks the shared state
+/// (another run may have returned unused tokens) and can be cancelled promptly.
+const MAX_WAIT: Duration = Duration::from_secs(5);
+
+/// What is left in one provider's buckets as of `updated_ms`. Tokens can go negative
+/// when a request used more than it reserved; the debt is paid off by refilling.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bucket {
+    requests: f64,
+    tokens: f64,
+    updated_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    rpm: Option<f64>,
+    tpm: Option<f64>,
+    path: PathBuf,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl RateLimiter {
+    pub fn new(rpm: Option<u32>, tpm: Option<u32>, path: PathBuf) -> Self {
+        Self {
+            rpm: rpm.map(f64::from),
+            tpm: tpm.map(f64::from),
+            path,
+        }
+    }
+
+    /// `None` unless a requests- or tokens-per-minute limit is set.
+    pub fn from_env() -> Option<Self> {
+        let read = |key: &str| {
+            env::var(key)
+                .ok()
+                .and_then(|v| v.trim().parse::<u32>().ok())
+                .filter(|&v| v > 0)
+        };
+        let (rpm, tpm) = (read("LLM_RATE_LIMIT_RPM"), read("LLM_RATE_LIMIT_TPM"));
+        if rpm.is_none() && tpm.is_none() {
+            return None;
+        }
+
+        let path =
+            env::var("LLM_RATE_LIMIT_FILE").unwrap_or_else(|_| DEFAULT_STATE_FILE.to_string());
+        Some(Self::new(rpm, tpm, PathBuf::from(path)))
+    }
+
+    /// Refills `bucket` for the time since it was last updated.
+    fn refill(&self, bucket: &mut Bucket, now_ms: u64) {
+        if bucket.updated_ms == 0 {
+            *bucket = Bucket {
+                requests: self.rpm.unwrap_or(0.0),
+                tokens: self.tpm.unwrap_or(0.0),
+                updated_ms: now_ms,
+            };
+            return;
+        }
+
+        let minutes = now_ms.saturating_sub(bucket.updated_ms) as f64 / 60_000.0;
+        if let Some(rpm) = self.rpm {
+            bucket.requests = (bucket.requests + minutes * rpm).min(rpm);
+        }
+        if let Some(tpm) = self.tpm {
+            bucket.tokens = (bucket.tokens + minutes * tpm).min(tpm);
+        }
+        bucket.updated_ms = now_ms;
+    }
+
+    /// Takes one request and `tokens` from `provider`'s buckets if both have enough,
+    /// returning `None`; otherwise takes nothing and returns how long until they will.
+    fn try_take(
+        &self,
+        state: &mut HashMap<String, Bucket>,
+        provider: &str,
+        tokens: f64,
+        now_ms: u64,
+    ) -> Option<Duration> {
+        let bucket = state.entry(provider.to_string()).or_default();
+        self.refill(bucket, now_ms);
+
+        // A request larger than the whole budget only waits for a full bucket.
+        let tokens = self.tpm.map_or(tokens, |tpm| tokens.min(tpm));
+        let wait_minutes =
+            |have: f64, need: f64, per_minute: f64| ((need - have) / per_minute).max(0.0);
+        let wait = f64::max(
+            self.rpm
+                .map_or(0.0, |rpm| wait_minutes(bucket.requests, 1.0, rpm)),
+            self.tpm
+                .map_or(0.0, |tpm| wait_minutes(bucket.tokens, tokens, tpm)),
+        );
+        if wait > 0.0 {
+            return Some(Duration::from_secs_f64(wait * 60.0));
+        }
+
+        bucket.requests -= 1.0;
+        bucket.tokens -= tokens;
+        None
+    }
+
+    /// Waits until `provider` has room for one request of about `tokens`, then
+    /// reserves it. A state file that cannot be used is reported and not enforced,
+    /// so the limiter never fails a run by itself.
+    pub async fn acquire(&self, provider: &str, tokens: u32) {
+        let mut announced = false;
+        loop {
+            let limiter = self.clone();
+            let provider_key = provider.to_string();
+            let taken = tokio::task::spawn_blocking(move || {
+                with_locked_state(&limiter.path, |state| {
+                    limiter.try_take(state, &provider_key, tokens as f64, now_ms())
+                })
+            })
+            .await
+            .map_err(io::Error::other)
+            .and_then(|result| result);
+
+            let wait = match taken {
+                Ok(None) => return,
+                Ok(Some(wait)) => wait,
+                Err(e) => {
+                    eprintln!("Warning: Rate limiter disabled for this call: {}", e);
+                    return;
+                }
+            };
+            if !announced {
+                eprintln!(
+                    "[RateLimit] {} budget used up, waiting about {}s",
+                    provider,
+                    wait.as_secs().max(1)
+                );
+                announced = true;
+            }
+            tokio::time::sleep(wait.min(MAX_WAIT)).await;
+        }
+    }
+
+    /// Waits for room like `acquire` and returns the reservation, which is refunded
+    /// unless the call settles it.
+    pub async fn reserve(&self, provider: &str, tokens: u32) -> Reservation {
+        self.acquire(provider, tokens).await;
+        Reservation {
+            limiter: self.clone(),
+            provider: provider.to_string(),
+            tokens,
+            settled: false,
+        }
+    }
+
+    /// Gives back the part of a reservation the request did not use, or takes the
+    /// overrun when it used more.
+    fn settle(&self, provider: &str, reserved: u32, used: u32) -> io::Result<()> {
+        with_locked_state(&self.path, |state: &mut HashMap<String, Bucket>| {
+            if let Some(bucket) = state.get_mut(provider) {
+                let tokens = self
+                    .tpm
+                    .map_or(reserved as f64, |tpm| (reserved as f64).min(tpm));
+                bucket.tokens += tokens - used as f64;
+                if let Some(tpm) = self.tpm {
+                    bucket.tokens = bucket.tokens.min(tpm);
+                }
+            }
+        })
+    }
+}
+
+/// Tokens reserved for one call. A call that fails, or is dropped when the run is
+/// cancelled, used nothing the provider counts, so it is refunded in full: explicitly
+/// on failure, and in the background when a cancelled call drops it.
+pub struct Reservation {
+    limiter: RateLimiter,
+    provider: String,
+    tokens: u32,
+    settled: bool,
+}
+
+impl Reservation {
+    /// Charges what the call used instead of what was reserved. Zero means the
+    /// provider did not report usage, and the reservation is kept as charged.
+    pub async fn settle(mut self, used: u32) {
+        self.settled = true;
+        if used > 0 {
+            let _ = self.charge(&Handle::current(), used).await;
+        }
+    }
+
+    /// Gives the whole reservation back, for a call the provider did not count.
+    pub async fn refund(mut self) {
+        self.settled = true;
+        if self.tokens > 0 {
+            let _ = self.charge(&Handle::current(), 0).await;
+        }
+    }
+
+    /// Settles on the blocking pool, since taking the file lock can block, and logs a
+    /// failure instead of failing the call.
+    fn charge(&self, runtime: &Handle, used: u32) -> JoinHandle<()> {
+        let (limiter, provider, reserved) =
+            (self.limiter.clone(), self.provider.clone(), self.tokens);
+        runtime.spawn_blocking(move || {
+            if let Err(e) = limiter.settle(&provider, reserved, used) {
+                eprintln!(
+                    "[RateLimit] Could not settle {} reserved tokens for {}: {}",
+                    reserved, provider, e
+                );
+            }
+        })
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.settled || self.tokens == 0 {
+            return;
+        }
+        match Handle::try_current() {
+            Ok(runtime) => {
+                self.charge(&runtime, 0);
+            }
+            Err(_) => eprintln!(
+                "[RateLimit] Could not refund {} reserved tokens for {}: no runtime",
+                self.tokens, self.provider
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take(limiter: &RateLimiter, tokens: f64, at_secs: u64) -> Option<Duration> {
+        with_locked_state(&limiter.path, |state| {
+            limiter.try_take(state, "openai", tokens, 1_000_000 + at_secs * 1000)
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn processes_sharing_the_state_file_share_one_budget() {
+        let path = env::temp_dir().join(format!("crab-rate-limit-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // Two limiters on one file stand in for two crab processes.
+        let first = RateLimiter::new(Some(2), Some(1_200), path.clone());
+        let second = RateLimiter::new(Some(2), Some(1_200), path.clone());
+
+        assert_eq!(take(&first, 1_000.0, 0), None);
+        // 600 tokens short at 20 tokens a second.
+        assert_eq!(take(&second, 800.0, 0), Some(Duration::from_secs(30)));
+        assert_eq!(take(&second, 800.0, 30), None);
+
+        // The first request only used 100 of the 1000 tokens it reserved.
+        first.settle("openai", 1_000, 100).unwrap();
+        assert_eq!(take(&first, 900.0, 30), None);
+        // Both requests are spent again; one comes back every 30 seconds.
+        assert_eq!(take(&second, 1.0, 30), Some(Duration::from_secs(30)));
+
+        // A call that failed gives its whole reservation back.
+        Reservation {
+            limiter: second.clone(),
+            provider: "openai".to_string(),
+            tokens: 1_000,
+            settled: false,
+        }
+        .refund()
+        .await;
+        assert_eq!(take(&second, 1_000.0, 60), None);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
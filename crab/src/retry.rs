@@ -0,0 +1,349 @@
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Marker prefix printed before every retry so the orchestrator can tell the user
+/// the agent is still alive.
+pub const RETRY_MARKER: &str = "[RETRY]";
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(1000),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let read = |key: &str| env::var(key).ok().and_then(|v| v.parse::<u64>().ok());
+
+        Self {
+            max_attempts: read("LLM_RETRY_ATTEMPTS")
+                .map(|n| n.max(1) as u32)
+                .unwrap_or(defaults.max_attempts),
+            base_delay: read("LLM_RETRY_BASE_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.base_delay),
+            max_delay: read("LLM_RETRY_MAX_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.max_delay),
+        }
+    }
+
+    /// Delay before retry number `attempt` (1-based): exponential growth capped at
+    /// `max_delay`, with "equal jitter" so concurrent agents do not retry in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let half = exp / 2;
+        let jitter_ms = random_u64() % (half.as_millis() as u64 + 1);
+        half + Duration::from_millis(jitter_ms)
+    }
+
+    /// Delay for a retryable response, preferring whatever the provider asked for.
+    /// Rate-limit reset headers only count on a 429: Anthropic sends them on 5xx
+    /// responses too, where they say nothing about when the overload will clear.
+    pub fn delay_for(&self, attempt: u32, response: Option<(StatusCode, &HeaderMap)>) -> Duration {
+        response
+            .and_then(|(status, headers)| {
+                retry_after(headers).or_else(|| {
+                    (status == StatusCode::TOO_MANY_REQUESTS)
+                        .then(|| rate_limit_reset(headers))
+                        .flatten()
+                })
+            })
+            .map(|d| d.min(self.max_delay))
+            .unwrap_or_else(|| self.backoff(attempt))
+    }
+}
+
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 408 | 409 | 425 | 429 | 500..=504 | 529)
+}
+
+pub fn announce(attempt: u32, max_attempts: u32, delay: Duration, reason: &str) {
+    println!(
+        "{} attempt {}/{} in {:.1}s: {}",
+        RETRY_MARKER,
+        attempt + 1,
+        max_attempts,
+        delay.as_secs_f64(),
+        reason
+    );
+}
+
+/// Reads how long the provider wants us to wait from the standard `Retry-After`
+/// header or its millisecond variant.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.trim().parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+    }
+
+    if let Some(value) = header("retry-after") {
+        if let Ok(secs) = value.trim().parse::<f64>() {
+            return Some(Duration::from_secs_f64(secs.max(0.0)));
+        }
+        if let Some(at) = parse_http_date(value) {
+            return Some(until(at));
+        }
+    }
+
+    None
+}
+
+/// Reads when the provider's rate-limit window resets, taking the latest of the
+/// request and token windows.
+pub fn rate_limit_reset(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    // OpenAI, Groq and friends: "1s", "6m0s", "250ms".
+    let openai = ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"]
+        .iter()
+        .filter_map(|name| header(name).and_then(parse_go_duration))
+        .max();
+    if openai.is_some() {
+        return openai;
+    }
+
+    // Anthropic: RFC 3339 timestamps.
+    [
+        "anthropic-ratelimit-requests-reset",
+        "anthropic-ratelimit-tokens-reset",
+        "anthropic-ratelimit-input-tokens-reset",
+        "anthropic-ratelimit-output-tokens-reset",
+    ]
+    .iter()
+    .filter_map(|name| header(name).and_then(parse_rfc3339))
+    .map(until)
+    .max()
+}
+
+fn until(at_unix_secs: u64) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Duration::from_secs(at_unix_secs.saturating_sub(now))
+}
+
+fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish()
+}
+
+/// Parses Go-style durations such as `1m30s`, `2.5s` or `120ms`.
+fn parse_go_duration(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..split].parse().ok()?;
+        rest = &rest[split..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let factor = match &rest[..unit_len] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        rest = &rest[unit_len..];
+        total += number * factor;
+    }
+
+    Some(Duration::from_secs_f64(total))
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn to_unix(year: i64, month: u32, day: u32, h: u64, m: u64, s: u64) -> Option<u64> {
+    let days = days_from_civil(year, month, day);
+    u64::try_from(days)
+        .ok()
+        .map(|d| d * 86400 + h * 3600 + m * 60 + s)
+}
+
+/// Parses `2024-05-01T12:00:30Z` (fractional seconds and offsets other than `Z`
+/// are accepted but the offset is ignored; providers always send UTC).
+fn parse_rfc3339(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (date, time) = value.split_once('T')?;
+    let mut date = date.split('-');
+    let year = date.next()?.parse().ok()?;
+    let month = date.next()?.parse().ok()?;
+    let day = date.next()?.parse().ok()?;
+
+    let time = time.get(..8)?;
+    let mut time = time.split(':');
+    let h = time.next()?.parse().ok()?;
+    let m = time.next()?.parse().ok()?;
+    let s = time.next()?.parse().ok()?;
+
+    to_unix(year, month, day, h, m, s)
+}
+
+/// Parses an IMF-fixdate such as `Wed, 21 Oct 2015 07:28:00 GMT`.
+fn parse_http_date(value: &str) -> Option<u64> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.len() != 6 {
+        return None;
+    }
+
+    let day = parts[1].parse().ok()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|m| *m == parts[2])? as u32
+        + 1;
+    let year = parts[3].parse().ok()?;
+
+    let mut time = parts[4].split(':');
+    let h = time.next()?.parse().ok()?;
+    let m = time.next()?.parse().ok()?;
+    let s = time.next()?.parse().ok()?;
+
+    to_unix(year, month, day, h, m, s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn reads_retry_after_variants() {
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "7")])),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(
+            retry_after(&headers(&[("retry-after-ms", "1500")])),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            rate_limit_reset(&headers(&[
+                ("x-ratelimit-reset-requests", "1m30s"),
+                ("x-ratelimit-reset-tokens", "250ms"),
+            ])),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(retry_after(&headers(&[])), None);
+    }
+
+    #[test]
+    fn honours_reset_headers_only_on_429() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(60),
+        };
+        let reset = headers(&[
+            ("x-ratelimit-reset-tokens", "45s"),
+            ("anthropic-ratelimit-tokens-reset", "2999-01-01T00:00:00Z"),
+        ]);
+
+        assert_eq!(
+            policy.delay_for(1, Some((StatusCode::TOO_MANY_REQUESTS, &reset))),
+            Duration::from_secs(45)
+        );
+        assert!(
+            policy.delay_for(1, Some((StatusCode::SERVICE_UNAVAILABLE, &reset)))
+                <= Duration::from_millis(100)
+        );
+
+        let asked = headers(&[("retry-after", "2"), ("x-ratelimit-reset-tokens", "45s")]);
+        assert_eq!(
+            policy.delay_for(1, Some((StatusCode::SERVICE_UNAVAILABLE, &asked))),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn parses_absolute_dates() {
+        assert_eq!(
+            parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(1445412480)
+        );
+        assert_eq!(parse_rfc3339("2015-10-21T07:28:00Z"), Some(1445412480));
+        assert_eq!(parse_rfc3339("2015-10-21T07:28:00.123Z"), Some(1445412480));
+    }
+
+    #[test]
+    fn backoff_grows_and_stays_within_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+        };
+
+        let first = policy.backoff(1);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+
+        for attempt in 1..10 {
+            assert!(policy.backoff(attempt) <= Duration::from_millis(300));
+        }
+        assert!(policy.backoff(4) >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn classifies_statuses() {
+        for code in [429, 500, 502, 503, 504, 529] {
+            assert!(is_retryable_status(StatusCode::from_u16(code).unwrap()));
+        }
+        for code in [400, 401, 403, 404, 422] {
+            assert!(!is_retryable_status(StatusCode::from_u16(code).unwrap()));
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Per-agent sampling settings. Defaults come from `LLM_*` environment variables;
+//! the agent's own `LLM_SAMPLING_FILE` (default `/app/workspace/sampling.json`, inside
+//! its workspace) overrides them field by field:
+//!
+//! ```json
+//! { "temperature": 0, "topP": 1, "seed": 7, "stop": ["</answer>"],
+//!   "presencePenalty": 0, "frequencyPenalty": 0.2,
+//!   "providerRouting": { "order": ["Anthropic"], "allow_fallbacks": false },
+//!   "reasoningEffort": "high", "thinkingBudget": 8000 }
+//! ```
+//!
+//! Unset fields are left out of requests so each provider's own defaults apply.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::env;
+use std::fs;
+
+const DEFAULT_SAMPLING_FILE: &str = "/app/workspace/sampling.json";
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct Sampling {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub seed: Option<i64>,
+    pub stop: Vec<String>,
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    /// OpenRouter's `provider` preferences (order, fallbacks, data collection...),
+    /// passed through as is. Other providers ignore it.
+    pub provider_routing: Option<Value>,
+    /// `reasoning_effort` for OpenAI-style reasoning models, e.g. `low` or `high`.
+    pub reasoning_effort: Option<String>,
+    /// Thinking tokens for Anthropic and Gemini; derived from the effort when unset.
+    pub thinking_budget: Option<u32>,
+}
+
+/// The smallest thinking budget Anthropic accepts.
+pub const MIN_THINKING_BUDGET: u32 = 1024;
+
+impl Sampling {
+    /// `LLM_TEMPERATURE`, `LLM_TOP_P`, `LLM_SEED`, `LLM_STOP`, `LLM_PRESENCE_PENALTY`,
+    /// `LLM_FREQUENCY_PENALTY`, `LLM_PROVIDER_ROUTING`, `LLM_REASONING_EFFORT` and
+    /// `LLM_THINKING_BUDGET`, then the settings file.
+    /// Values that do not parse are skipped with a warning.
+    pub fn load() -> Self {
+        let mut sampling = Self::default();
+        for warning in sampling.apply_env(|key| env::var(key).ok()) {
+            eprintln!("Warning: {}", warning);
+        }
+
+        let path =
+            env::var("LLM_SAMPLING_FILE").unwrap_or_else(|_| DEFAULT_SAMPLING_FILE.to_string());
+        if let Ok(contents) = fs::read_to_string(&path) {
+            match serde_json::from_str(&contents) {
+                Ok(file) => sampling.merge(file),
+                Err(e) => eprintln!("Warning: Ignoring invalid sampling file {}: {}", path, e),
+            }
+        }
+        sampling
+    }
+
+    /// Takes every setting `other` has.
+    fn merge(&mut self, other: Sampling) {
+        self.temperature = other.temperature.or(self.temperature);
+        self.top_p = other.top_p.or(self.top_p);
+        self.seed = other.seed.or(self.seed);
+        if !other.stop.is_empty() {
+            self.stop = other.stop;
+        }
+        self.presence_penalty = other.presence_penalty.or(self.presence_penalty);
+        self.frequency_penalty = other.frequency_penalty.or(self.frequency_penalty);
+        self.provider_routing = other.provider_routing.or(self.provider_routing.take());
+        self.reasoning_effort = other.reasoning_effort.or(self.reasoning_effort.take());
+        self.thinking_budget = other.thinking_budget.or(self.thinking_budget);
+    }
+
+    /// Whether reasoning output was asked for at all.
+    pub fn reasoning(&self) -> bool {
+        self.thinking_budget().is_some() || self.reasoning_effort.is_some()
+    }
+
+    /// Thinking tokens to request from providers that take a budget rather than an
+    /// effort level. An effort of `none` turns thinking off.
+    pub fn thinking_budget(&self) -> Option<u32> {
+        let budget = match (self.thinking_budget, self.reasoning_effort.as_deref()) {
+            (Some(budget), _) => budget,
+            (None, None | Some("none")) => return None,
+            (None, Some("minimal" | "low")) => MIN_THINKING_BUDGET,
+            (None, Some("high")) => 16_384,
+            (None, Some(_)) => 4_096,
+        };
+        Some(budget.max(MIN_THINKING_BUDGET))
+    }
+
+    /// Applies the `LLM_*` variables found by `lookup`; an empty value clears the
+    /// setting. Returns a warning for each value that could not be used.
+    fn apply_env(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let mut number = |key: &str, field: &mut Option<f64>| {
+            let Some(value) = lookup(key) else { return };
+            match value.trim() {
+                "" => *field = None,
+                value => match value.parse() {
+                    Ok(n) => *field = Some(n),
+                    Err(_) => warnings.push(format!("{} must be a number, got '{}'", key, value)),
+                },
+            }
+        };
+        number("LLM_TEMPERATURE", &mut self.temperature);
+        number("LLM_TOP_P", &mut self.top_p);
+        number("LLM_PRESENCE_PENALTY", &mut self.presence_penalty);
+        number("LLM_FREQUENCY_PENALTY", &mut self.frequency_penalty);
+
+        if let Some(value) = lookup("LLM_SEED") {
+            match value.trim() {
+                "" => self.seed = None,
+                value => match value.parse() {
+                    Ok(n) => self.seed = Some(n),
+                    Err(_) => {
+                        warnings.push(format!("LLM_SEED must be an integer, got '{}'", value))
+                    }
+                },
+            }
+        }
+
+        if let Some(value) = lookup("LLM_THINKING_BUDGET") {
+            match value.trim() {
+                "" => self.thinking_budget = None,
+                value => match value.parse() {
+                    Ok(n) => self.thinking_budget = Some(n),
+                    Err(_) => warnings.push(format!(
+                        "LLM_THINKING_BUDGET must be a token count, got '{}'",
+                        value
+                    )),
+                },
+            }
+        }
+
+        if let Some(value) = lookup("LLM_REASONING_EFFORT") {
+            let effort = value.trim().to_lowercase();
+            self.reasoning_effort = (!effort.is_empty()).then_some(effort);
+        }
+
+        // A JSON array of sequences, or a single sequence taken literally.
+        if let Some(value) = lookup("LLM_STOP") {
+            self.stop = match serde_json::from_str::<Vec<String>>(&value) {
+                Ok(sequences) => sequences,
+                Err(_) if value.is_empty() => Vec::new(),
+                Err(_) => vec![value],
+            };
+        }
+
+        if let Some(value) = lookup("LLM_PROVIDER_ROUTING") {
+            match serde_json::from_str::<Value>(&value) {
+                _ if value.trim().is_empty() => self.provider_routing = None,
+                Ok(routing @ Value::Object(_)) => self.provider_routing = Some(routing),
+                _ => warnings.push("LLM_PROVIDER_ROUTING must be a JSON object".to_string()),
+            }
+        }
+
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn file_overrides_environment_defaults_and_bad_values_are_reported() {
+        let env: HashMap<&str, &str> = HashMap::from([
+            ("LLM_TEMPERATURE", "0.7"),
+            ("LLM_TOP_P", "high"),
+            ("LLM_SEED", "3"),
+            ("LLM_STOP", r#"["\n\n", "---"]"#),
+            ("LLM_PROVIDER_ROUTING", "[]"),
+            ("LLM_REASONING_EFFORT", " High "),
+        ]);
+        let mut sampling = Sampling::default();
+        let warnings = sampling.apply_env(|key| env.get(key).map(|v| v.to_string()));
+        assert_eq!(
+            warnings,
+            vec![
+                "LLM_TOP_P must be a number, got 'high'",
+                "LLM_PROVIDER_ROUTING must be a JSON object",
+            ]
+        );
+
+        sampling.merge(
+            serde_json::from_value(json!({
+                "temperature": 0,
+                "providerRouting": { "order": ["Together"] },
+            }))
+            .unwrap(),
+        );
+        assert_eq!(
+            sampling,
+            Sampling {
+                temperature: Some(0.0),
+                seed: Some(3),
+                stop: vec!["\n\n".to_string(), "---".to_string()],
+                provider_routing: Some(json!({ "order": ["Together"] })),
+                reasoning_effort: Some("high".to_string()),
+                ..Sampling::default()
+            }
+        );
+        assert_eq!(sampling.thinking_budget(), Some(16_384));
+        sampling.thinking_budget = Some(200);
+        assert_eq!(sampling.thinking_budget(), Some(MIN_THINKING_BUDGET));
+        assert!(serde_json::from_value::<Sampling>(json!({ "temprature": 0 })).is_err());
+    }
+}
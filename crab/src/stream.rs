@@ -0,0 +1,263 @@
+use crate::error::CrabError;
+use reqwest::Response;
+use std::io::Write;
+use std::time::Duration;
+
+/// Marker prefix for incremental completion output. Each delta is printed on its own
+/// line as a JSON string so embedded newlines never split an event across lines.
+pub const STREAM_MARKER: &str = "[STREAM]";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Incremental Server-Sent Events parser. Bytes go in as they arrive off the wire, in
+/// chunks of any size; complete events come out.
+#[derive(Debug, Default)]
+struct SseParser {
+    /// The current line, up to the end of the last chunk.
+    partial: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        for &byte in chunk {
+            if byte != b'\n' {
+                self.partial.push(byte);
+                continue;
+            }
+            // Lines are decoded whole so a multi-byte character split across chunks survives.
+            let line = String::from_utf8_lossy(&self.partial).into_owned();
+            self.partial.clear();
+            events.extend(self.line(line.trim_end_matches('\r')));
+        }
+        events
+    }
+
+    fn line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            if self.data.is_empty() {
+                self.event = None;
+                return None;
+            }
+            let dispatched = SseEvent {
+                event: self.event.take(),
+                data: self.data.join("\n"),
+            };
+            self.data.clear();
+            return Some(dispatched);
+        }
+
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => self.data.push(value.to_string()),
+            _ => {}
+        }
+        None
+    }
+
+    /// Whatever the stream ended on without a final blank line.
+    fn finish(mut self) -> Option<SseEvent> {
+        if !self.partial.is_empty() {
+            let line = String::from_utf8_lossy(&self.partial).into_owned();
+            self.partial.clear();
+            if let Some(event) = self.line(line.trim_end_matches('\r')) {
+                return Some(event);
+            }
+        }
+        self.line("")
+    }
+}
+
+/// Passes `events` to `on_event` in order. Returns `Ok(false)` once the callback
+/// asks to stop or the OpenAI-style `[DONE]` sentinel arrives.
+fn dispatch<F>(events: Vec<SseEvent>, on_event: &mut F) -> Result<bool, CrabError>
+where
+    F: FnMut(SseEvent) -> Result<bool, CrabError>,
+{
+    for event in events {
+        if event.data == "[DONE]" || !on_event(event)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+pub fn read_timed_out(limit: Duration) -> CrabError {
+    CrabError::Timeout(format!(
+        "No data from the provider for {}s (LLM_READ_TIMEOUT)",
+        limit.as_secs_f64()
+    ))
+}
+
+/// Reads Server-Sent Events from the body of `response` as it arrives, calling
+/// `on_event` once per dispatched event. Stops early when the callback returns
+/// `Ok(false)` or on the OpenAI-style `[DONE]` sentinel, and fails when the stream
+/// stalls for longer than `read_timeout`.
+pub async fn read_sse<F>(
+    mut response: Response,
+    read_timeout: Option<Duration>,
+    mut on_event: F,
+) -> Result<(), CrabError>
+where
+    F: FnMut(SseEvent) -> Result<bool, CrabError>,
+{
+    let mut parser = SseParser::default();
+
+    loop {
+        let next = match read_timeout {
+            Some(limit) => tokio::time::timeout(limit, response.chunk())
+                .await
+                .map_err(|_| read_timed_out(limit))?,
+            None => response.chunk().await,
+        };
+        let Some(chunk) =
+            next.map_err(|e| CrabError::Network(format!("Stream read failed: {}", e)))?
+        else {
+            break;
+        };
+        if !dispatch(parser.feed(&chunk), &mut on_event)? {
+            return Ok(());
+        }
+    }
+
+    dispatch(parser.finish().into_iter().collect(), &mut on_event)?;
+    Ok(())
+}
+
+pub fn emit_delta(delta: &str) {
+    if delta.is_empty() {
+        return;
+    }
+
+    let encoded = serde_json::to_string(delta).unwrap_or_default();
+    let stdout = std::io::stdout();
+    let mut handle = stdout.lock();
+    let _ = writeln!(handle, "{} {}", STREAM_MARKER, encoded);
+    let _ = handle.flush();
+}
+
+/// The part of streamed `content` that is the answer. A leading `<think>` block,
+/// which local reasoning models put before it, is held back until it closes, and
+/// content that may still turn out to be one is held back too.
+fn visible_content(content: &str) -> &str {
+    let trimmed = content.trim_start();
+    if "<think>".starts_with(trimmed) {
+        return "";
+    }
+    match trimmed.strip_prefix("<think>") {
+        Some(rest) => rest
+            .split_once("</think>")
+            .map_or("", |(_, answer)| answer.trim_start()),
+        None => content,
+    }
+}
+
+/// Streams content as it accumulates, leaving out reasoning in `<think>` tags.
+#[derive(Debug, Default)]
+pub struct ContentStream {
+    emitted: usize,
+}
+
+impl ContentStream {
+    /// Emits whatever of `content`, everything received so far, has not been shown.
+    pub fn update(&mut self, content: &str) {
+        let visible = visible_content(content);
+        if visible.len() > self.emitted {
+            emit_delta(&visible[self.emitted..]);
+            self.emitted = visible.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `input` three bytes at a time, the way a slow connection delivers it.
+    fn collect(input: &str) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        let mut on_event = |e| {
+            events.push(e);
+            Ok(true)
+        };
+        let mut parser = SseParser::default();
+        for chunk in input.as_bytes().chunks(3) {
+            if !dispatch(parser.feed(chunk), &mut on_event).unwrap() {
+                return events;
+            }
+        }
+        dispatch(parser.finish().into_iter().collect(), &mut on_event).unwrap();
+        events
+    }
+
+    #[test]
+    fn parses_named_and_multiline_events() {
+        let events = collect(
+            "event: message_start\ndata: {\"a\":1}\n\n: keep-alive\n\ndata: line one\ndata: line two\n\n",
+        );
+
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("message_start".to_string()),
+                    data: "{\"a\":1}".to_string(),
+                },
+                SseEvent {
+                    event: None,
+                    data: "line one\nline two".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_done_sentinel() {
+        let events = collect("data: first\r\n\r\ndata: [DONE]\n\ndata: after\n\n");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "first");
+    }
+
+    #[test]
+    fn dispatches_trailing_event_without_blank_line() {
+        let events = collect("data: tail 🦀");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "tail 🦀");
+    }
+
+    #[test]
+    fn holds_back_a_leading_think_block() {
+        let visible_after = |deltas: &[&str]| {
+            let mut content = String::new();
+            deltas
+                .iter()
+                .map(|delta| {
+                    content.push_str(delta);
+                    visible_content(&content).to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            visible_after(&["\n<thi", "nk>Add", " them.</th", "ink>\n\n", "4", " apples"]),
+            ["", "", "", "", "4", "4 apples"]
+        );
+        assert_eq!(visible_after(&["<", "b>4</b>"]), ["", "<b>4</b>"]);
+        assert_eq!(visible_after(&["4 <think>"]), ["4 <think>"]);
+    }
+}
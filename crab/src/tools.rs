@@ -1,25 +1,102 @@
-use std::process::Command;
+use crate::error::CrabError;
+use crate::llm::{ToolCall, ToolDefinition};
+use serde_json::json;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
 
-pub fn execute_command(cmd: &str) -> Result<String, String> {
+/// Marker prefix for each line a command writes to stdout, printed as it arrives so
+/// the orchestrator can show progress on long-running commands.
+pub const OUTPUT_MARKER: &str = "[OUTPUT]";
+
+/// Kills a command's whole process group when dropped before the command finished,
+/// so nothing `sh -c` started outlives a cancelled run.
+struct ProcessGroup {
+    id: Option<u32>,
+}
+
+impl ProcessGroup {
+    fn finished(&mut self) {
+        self.id = None;
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            // SAFETY: killpg has no memory-safety preconditions.
+            unsafe {
+                libc::killpg(id as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Runs `cmd` through `sh -c`, streaming its stdout line by line. The command and
+/// everything it started are killed if the returned future is dropped, e.g. when the
+/// run is cancelled.
+pub async fn execute_command(cmd: &str) -> Result<String, CrabError> {
     let parts: Vec<&str> = cmd.split_whitespace().collect();
 
     if parts.is_empty() {
-        return Err("Empty command".to_string());
+        return Err(CrabError::Spawn {
+            command: cmd.to_string(),
+            message: "Empty command".to_string(),
+        });
     }
 
-    let output = Command::new("sh")
+    let spawn_error = |e: std::io::Error| CrabError::Spawn {
+        command: cmd.to_string(),
+        message: e.to_string(),
+    };
+
+    let mut child = Command::new("sh")
         .arg("-c")
         .arg(cmd)
-        .output()
-        .map_err(|e| format!("Failed to execute: {}", e))?;
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(spawn_error)?;
+    let mut group = ProcessGroup { id: child.id() };
+
+    let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let mut output = Vec::new();
+    let mut errors = Vec::new();
+
+    // Both pipes are drained together so a chatty stderr cannot block the command.
+    let (streamed, _) = tokio::join!(
+        async {
+            loop {
+                let start = output.len();
+                if stdout.read_until(b'\n', &mut output).await? == 0 {
+                    return Ok::<_, std::io::Error>(());
+                }
+                let line = String::from_utf8_lossy(&output[start..]);
+                println!("{} {}", OUTPUT_MARKER, line.trim_end());
+            }
+        },
+        stderr.read_to_end(&mut errors),
+    );
+    streamed.map_err(spawn_error)?;
+    let status = child.wait().await.map_err(spawn_error)?;
+    group.finished();
 
-    if output.status.success() {
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    if status.success() {
+        Ok(String::from_utf8_lossy(&output).to_string())
     } else {
-        Err(String::from_utf8_lossy(&output.stderr).to_string())
+        Err(CrabError::CommandFailed {
+            command: cmd.to_string(),
+            code: status.code(),
+            stderr: String::from_utf8_lossy(&errors).to_string(),
+        })
     }
 }
 
+#[allow(dead_code)]
 pub fn can_execute_command(cmd: &str) -> bool {
     let allowed = [
         "curl", "jq", "cat", "ls", "echo", "grep", "awk", "sed", "python3", "node",
@@ -141,3 +218,170 @@ pub fn extract_delegate_action(response: &str) -> Option<(String, String)> {
         _ => None,
     }
 }
+
+/// Typed form of the tool calls the agent loop knows how to dispatch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AgentAction {
+    RunTerminal { command: String },
+    Delegate { agent_role: String, task: String },
+    SendFile { filename: String },
+    PanelAction { action: String },
+    ViewImage { path: String },
+}
+
+pub fn agent_tools() -> Vec<ToolDefinition> {
+    vec![
+        ToolDefinition {
+            name: "run_terminal",
+            description: "Execute a single shell command inside the cubicle and return its output.",
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "command": { "type": "string", "description": "Shell command to run with sh -c" }
+                },
+                "required": ["command"]
+            }),
+        },
+        ToolDefinition {
+            name: "delegate",
+            description: "Delegate a sub-task to another agent. Requires operator approval.",
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "agent_role": { "type": "string", "description": "Role needed, e.g. 'Python Expert'" },
+                    "task": { "type": "string", "description": "Detailed task description" }
+                },
+                "required": ["agent_role", "task"]
+            }),
+        },
+        ToolDefinition {
+            name: "send_file",
+            description: "Send a file from /app/workspace/out/ to the user via Telegram.",
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "filename": { "type": "string", "description": "File name inside /app/workspace/out/" }
+                },
+                "required": ["filename"]
+            }),
+        },
+        ToolDefinition {
+            name: "panel_action",
+            description: "Queue a dashboard panel action such as CALENDAR_CREATE:title|prompt|start_time|end_time|color|symbol.",
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "action": { "type": "string", "description": "Panel action string" }
+                },
+                "required": ["action"]
+            }),
+        },
+        ToolDefinition {
+            name: "view_image",
+            description: "Look at an image in the workspace, e.g. a photo the user uploaded to /app/workspace/in/.",
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Image path, absolute or relative to /app/workspace" }
+                },
+                "required": ["path"]
+            }),
+        },
+    ]
+}
+
+pub fn parse_tool_call(call: &ToolCall) -> Result<AgentAction, String> {
+    let arg = |key: &str| -> Result<String, String> {
+        call.arguments
+            .get(key)
+            .and_then(|v| v.as_str())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| format!("Missing '{}' argument for tool '{}'", key, call.name))
+    };
+
+    match call.name.as_str() {
+        "run_terminal" => Ok(AgentAction::RunTerminal {
+            command: arg("command")?,
+        }),
+        "delegate" => Ok(AgentAction::Delegate {
+            agent_role: arg("agent_role")?,
+            task: arg("task")?,
+        }),
+        "send_file" => Ok(AgentAction::SendFile {
+            filename: arg("filename")?,
+        }),
+        "panel_action" => Ok(AgentAction::PanelAction {
+            action: arg("action")?,
+        }),
+        "view_image" => Ok(AgentAction::ViewImage { path: arg("path")? }),
+        other => Err(format!("Unknown tool: {}", other)),
+    }
+}
+
+pub fn build_tool_prompt() -> String {
+    r#"
+TOOLS:
+You have native tools: run_terminal, delegate, send_file, panel_action and view_image.
+Images the user mentions by name are attached to their message; use view_image for any other image.
+Call them instead of putting commands, delegation markers or actions in your message text.
+When you are done, reply with the RESPONSE CONTRACT JSON and leave "terminal" empty.
+"#
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, arguments: serde_json::Value) -> ToolCall {
+        ToolCall {
+            id: "call_0".to_string(),
+            name: name.to_string(),
+            arguments,
+        }
+    }
+
+    #[test]
+    fn parses_known_tool_calls() {
+        assert_eq!(
+            parse_tool_call(&call("run_terminal", json!({ "command": " ls -la " }))),
+            Ok(AgentAction::RunTerminal {
+                command: "ls -la".to_string()
+            })
+        );
+        assert_eq!(
+            parse_tool_call(&call(
+                "delegate",
+                json!({ "agent_role": "Python Expert", "task": "Write a parser" })
+            )),
+            Ok(AgentAction::Delegate {
+                agent_role: "Python Expert".to_string(),
+                task: "Write a parser".to_string()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn captures_stdout_and_reports_failures_with_stderr() {
+        assert_eq!(
+            execute_command("printf 'one\\ntwo'").await.unwrap(),
+            "one\ntwo"
+        );
+        assert_eq!(
+            execute_command("echo oops >&2; exit 3").await,
+            Err(CrabError::CommandFailed {
+                command: "echo oops >&2; exit 3".to_string(),
+                code: Some(3),
+                stderr: "oops\n".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_missing_arguments_and_unknown_tools() {
+        assert!(parse_tool_call(&call("send_file", json!({}))).is_err());
+        assert!(parse_tool_call(&call("run_terminal", json!("not an object"))).is_err());
+        assert!(parse_tool_call(&call("format_disk", json!({}))).is_err());
+    }
+}
@@ -0,0 +1,316 @@
+use crate::error::CrabError;
+use crate::limits::Limits;
+use crate::llm::{Completion, Usage};
+use crate::locked_file::with_locked_state;
+use crate::pricing::price_for;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Marker prefix for the usage summary printed when crab exits.
+pub const USAGE_MARKER: &str = "[USAGE]";
+
+const DEFAULT_LEDGER: &str = "/app/workspace/data/usage.json";
+
+/// Tokens and cost spent today, persisted so `maxTokensPerDay` holds across runs.
+/// Runs of the same agent share the file and only change it under its lock.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DailyLedger {
+    /// Days since the Unix epoch (UTC).
+    day: u64,
+    tokens: u64,
+    cost_usd: f64,
+}
+
+#[derive(Debug)]
+struct ModelUsage {
+    provider: String,
+    model: String,
+    calls: u32,
+    usage: Usage,
+    cost_usd: f64,
+    priced: bool,
+}
+
+/// Accumulates usage and cost for this run and enforces `MAX_COST_USD` and
+/// `maxTokensPerDay` before each call.
+#[derive(Debug)]
+pub struct UsageTracker {
+    models: Vec<ModelUsage>,
+    max_cost_usd: Option<f64>,
+    max_tokens_per_day: Option<u64>,
+    ledger_path: Option<PathBuf>,
+    today: DailyLedger,
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86_400
+}
+
+/// Adds `tokens` and `cost_usd` to today's totals in the ledger at `path`, starting
+/// over on a new day, and returns the totals as stored.
+fn add_to_ledger(path: &Path, tokens: u64, cost_usd: f64) -> io::Result<DailyLedger> {
+    let day = today();
+    with_locked_state(path, |ledger: &mut DailyLedger| {
+        if ledger.day != day {
+            *ledger = DailyLedger {
+                day,
+                ..Default::default()
+            };
+        }
+        ledger.tokens += tokens;
+        ledger.cost_usd += cost_usd;
+        ledger.clone()
+    })
+}
+
+impl UsageTracker {
+    pub fn from_env(limits: &Limits) -> Self {
+        let max_cost_usd = env::var("MAX_COST_USD")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v: &f64| v > 0.0);
+        let ledger = env::var("USAGE_LEDGER").unwrap_or_else(|_| DEFAULT_LEDGER.to_string());
+
+        Self::new(
+            max_cost_usd,
+            limits.max_tokens_per_day,
+            Some(PathBuf::from(ledger)),
+        )
+    }
+
+    pub fn new(
+        max_cost_usd: Option<f64>,
+        max_tokens_per_day: Option<u64>,
+        ledger_path: Option<PathBuf>,
+    ) -> Self {
+        let today = ledger_path
+            .as_deref()
+            .and_then(|path| add_to_ledger(path, 0, 0.0).ok())
+            .unwrap_or(DailyLedger {
+                day: today(),
+                ..Default::default()
+            });
+
+        Self {
+            models: Vec::new(),
+            max_cost_usd,
+            max_tokens_per_day,
+            ledger_path,
+            today,
+        }
+    }
+
+    pub fn cost_usd(&self) -> f64 {
+        self.models.iter().fold(0.0, |sum, m| sum + m.cost_usd)
+    }
+
+    /// Adds a completion to the totals and the daily ledger. Returns its cost, or
+    /// `None` when the model has no known price.
+    pub fn record(&mut self, completion: &Completion) -> Option<f64> {
+        let price = price_for(&completion.provider, &completion.model);
+        let cost = price.map(|p| p.cost(&completion.usage));
+
+        let index = match self
+            .models
+            .iter()
+            .position(|m| m.provider == completion.provider && m.model == completion.model)
+        {
+            Some(index) => index,
+            None => {
+                self.models.push(ModelUsage {
+                    provider: completion.provider.clone(),
+                    model: completion.model.clone(),
+                    calls: 0,
+                    usage: Usage::default(),
+                    cost_usd: 0.0,
+                    priced: price.is_some(),
+                });
+                self.models.len() - 1
+            }
+        };
+        let entry = &mut self.models[index];
+        entry.calls += 1;
+        entry.usage.add(&completion.usage);
+        entry.cost_usd += cost.unwrap_or(0.0);
+
+        self.add_today(completion.usage.total() as u64, cost.unwrap_or(0.0));
+
+        cost
+    }
+
+    /// Adds a call to today's totals. With a ledger, the totals are re-read under its
+    /// lock first, so calls made by other runs since the last update are counted too.
+    fn add_today(&mut self, tokens: u64, cost_usd: f64) {
+        if let Some(path) = &self.ledger_path {
+            if let Some(dir) = path.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            match add_to_ledger(path, tokens, cost_usd) {
+                Ok(ledger) => {
+                    self.today = ledger;
+                    return;
+                }
+                Err(e) => eprintln!("Warning: Could not update usage ledger: {}", e),
+            }
+        }
+        self.today.tokens += tokens;
+        self.today.cost_usd += cost_usd;
+    }
+
+    /// Fails if a call with about `input_tokens` of prompt and up to `max_output`
+    /// tokens of reply could push this run or today over a limit. Today's total
+    /// includes what other runs have recorded since.
+    pub fn check(
+        &mut self,
+        provider: &str,
+        model: &str,
+        input_tokens: u32,
+        max_output: u32,
+    ) -> Result<(), CrabError> {
+        let projected = Usage {
+            input_tokens,
+            output_tokens: max_output,
+            cached_tokens: 0,
+            reasoning_tokens: 0,
+        };
+
+        if let Some(limit) = self.max_tokens_per_day {
+            self.add_today(0, 0.0);
+            let after = self.today.tokens + projected.total() as u64;
+            if after > limit {
+                return Err(CrabError::BudgetExceeded(format!(
+                    "Daily token limit of {} would be exceeded ({} used today, next call needs up to {})",
+                    limit,
+                    self.today.tokens,
+                    projected.total()
+                )));
+            }
+        }
+
+        if let (Some(limit), Some(price)) = (self.max_cost_usd, price_for(provider, model)) {
+            let spent = self.cost_usd();
+            let next = price.cost(&projected);
+            if spent + next > limit {
+                return Err(CrabError::BudgetExceeded(format!(
+                    "Cost limit of ${:.4} would be exceeded (${:.4} spent, next call up to ${:.4})",
+                    limit, spent, next
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The machine-readable summary printed at exit.
+    pub fn summary(&self) -> Value {
+        let mut total = Usage::default();
+        for entry in &self.models {
+            total.add(&entry.usage);
+        }
+
+        json!({
+            "calls": self.models.iter().map(|m| m.calls).sum::<u32>(),
+            "inputTokens": total.input_tokens,
+            "outputTokens": total.output_tokens,
+            "cachedTokens": total.cached_tokens,
+            "totalTokens": total.total(),
+            "costUsd": self.cost_usd(),
+            "costComplete": self.models.iter().all(|m| m.priced),
+            "models": self.models.iter().map(|m| json!({
+                "provider": m.provider,
+                "model": m.model,
+                "calls": m.calls,
+                "inputTokens": m.usage.input_tokens,
+                "outputTokens": m.usage.output_tokens,
+                "cachedTokens": m.usage.cached_tokens,
+                "costUsd": if m.priced { json!(m.cost_usd) } else { Value::Null },
+            })).collect::<Vec<_>>(),
+            "limits": {
+                "maxCostUsd": self.max_cost_usd,
+                "maxTokensPerDay": self.max_tokens_per_day,
+                "tokensToday": self.today.tokens,
+            },
+        })
+    }
+
+    pub fn print_summary(&self) {
+        println!("{} {}", USAGE_MARKER, self.summary());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completion(model: &str, input: u32, output: u32) -> Completion {
+        Completion {
+            provider: "openai".to_string(),
+            model: model.to_string(),
+            usage: Usage {
+                input_tokens: input,
+                output_tokens: output,
+                cached_tokens: 0,
+                reasoning_tokens: 0,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accumulates_cost_per_model_and_stops_before_cost_limit() {
+        let mut tracker = UsageTracker::new(Some(0.01), None, None);
+
+        let cost = tracker.record(&completion("gpt-4o", 1_000, 500)).unwrap();
+        assert!((cost - 0.0075).abs() < 1e-9);
+        tracker.record(&completion("gpt-4o", 1_000, 0));
+
+        let summary = tracker.summary();
+        assert_eq!(summary["calls"], 2);
+        assert_eq!(summary["inputTokens"], 2_000);
+        assert_eq!(summary["models"].as_array().unwrap().len(), 1);
+
+        // $0.01 spent so far; another call of any size would go over.
+        assert!(matches!(
+            tracker.check("openai", "gpt-4o", 100, 100),
+            Err(CrabError::BudgetExceeded(_))
+        ));
+        // Unknown prices cannot be enforced, so they are let through.
+        assert!(tracker.check("openrouter", "auto", 100, 100).is_ok());
+    }
+
+    #[test]
+    fn daily_token_limit_carries_over_between_runs() {
+        let path = env::temp_dir().join(format!("crab-usage-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut first = UsageTracker::new(None, Some(1_000), Some(path.clone()));
+        first.record(&completion("gpt-4o", 600, 100));
+
+        let mut second = UsageTracker::new(None, Some(1_000), Some(path.clone()));
+        assert!(second.check("openai", "gpt-4o", 100, 100).is_ok());
+        assert!(second.check("openai", "gpt-4o", 200, 200).is_err());
+        assert_eq!(second.summary()["limits"]["tokensToday"], 700);
+
+        // Runs going at the same time add to the ledger instead of overwriting it.
+        first.record(&completion("gpt-4o", 100, 0));
+        assert!(second.check("openai", "gpt-4o", 150, 100).is_err());
+        second.record(&completion("gpt-4o", 50, 0));
+        assert_eq!(second.summary()["limits"]["tokensToday"], 850);
+        assert_eq!(
+            UsageTracker::new(None, None, Some(path.clone())).summary()["limits"]["tokensToday"],
+            850
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+}
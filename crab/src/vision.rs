@@ -0,0 +1,207 @@
+//! Loads workspace images for vision models: finds the ones a message refers to,
+//! and shrinks anything over the size limits before it is base64-encoded.
+
+use crate::llm::ImagePart;
+use base64::Engine;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Where the orchestrator saves files users upload through Telegram.
+pub const INBOX: &str = "in";
+
+const EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp"];
+
+/// Anthropic caps an image at 5 MB of base64, which is about 3.75 MB of raw bytes,
+/// and gains nothing from more than 1568 px on the long side.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageLimits {
+    pub max_bytes: usize,
+    pub max_dimension: u32,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 3_750_000,
+            max_dimension: 1_568,
+        }
+    }
+}
+
+impl ImageLimits {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let number = |key: &str| env::var(key).ok().and_then(|v| v.parse::<u64>().ok());
+        Self {
+            max_bytes: number("IMAGE_MAX_BYTES")
+                .map(|n| n as usize)
+                .unwrap_or(defaults.max_bytes),
+            max_dimension: number("IMAGE_MAX_DIMENSION")
+                .map(|n| n as u32)
+                .unwrap_or(defaults.max_dimension),
+        }
+    }
+}
+
+fn media_type(format: ImageFormat) -> Option<&'static str> {
+    match format {
+        ImageFormat::Png => Some("image/png"),
+        ImageFormat::Jpeg => Some("image/jpeg"),
+        ImageFormat::Gif => Some("image/gif"),
+        ImageFormat::WebP => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Resolves `reference` to an image file inside `workspace`: an absolute path, a path
+/// relative to the workspace, or a bare file name in `in/`. Paths that escape the
+/// workspace are rejected.
+pub fn resolve_image(workspace: &Path, reference: &str) -> Option<PathBuf> {
+    let reference = Path::new(reference);
+    let candidates = if reference.is_absolute() {
+        vec![reference.to_path_buf()]
+    } else {
+        vec![
+            workspace.join(reference),
+            workspace.join(INBOX).join(reference),
+        ]
+    };
+
+    let root = workspace.canonicalize().ok()?;
+    candidates
+        .into_iter()
+        .filter_map(|path| path.canonicalize().ok())
+        .find(|path| path.starts_with(&root) && path.is_file())
+}
+
+/// Image files in `workspace` that `text` mentions by path or by name, in order.
+pub fn referenced_images(workspace: &Path, text: &str) -> Vec<PathBuf> {
+    let mut found: Vec<PathBuf> = Vec::new();
+    for word in text.split_whitespace() {
+        let word = word
+            .trim_start_matches(['"', '\'', '(', '[', '`'])
+            .trim_end_matches(['"', '\'', ')', ']', '`', ',', ';', ':', '!', '?', '.']);
+        let is_image = Path::new(word)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()));
+        if !is_image {
+            continue;
+        }
+        if let Some(path) = resolve_image(workspace, word) {
+            if !found.contains(&path) {
+                found.push(path);
+            }
+        }
+    }
+    found
+}
+
+/// Reads an image and returns it ready to send. Files within the limits are sent
+/// as they are; larger ones are scaled down and re-encoded as JPEG, or PNG when they
+/// have transparency.
+pub fn load_image(path: &Path, limits: &ImageLimits) -> Result<ImagePart, String> {
+    let bytes =
+        fs::read(path).map_err(|e| format!("Could not read image {}: {}", path.display(), e))?;
+    let format = image::guess_format(&bytes)
+        .map_err(|_| format!("{} is not a supported image", path.display()))?;
+
+    let reader = image::io::Reader::with_format(Cursor::new(&bytes), format);
+    let fits = bytes.len() <= limits.max_bytes
+        && reader
+            .into_dimensions()
+            .is_ok_and(|(w, h)| w.max(h) <= limits.max_dimension);
+
+    if let (true, Some(media_type)) = (fits, media_type(format)) {
+        return Ok(encode(media_type, &bytes));
+    }
+
+    let decoded = image::load_from_memory_with_format(&bytes, format)
+        .map_err(|e| format!("Could not decode image {}: {}", path.display(), e))?;
+    shrink(decoded, limits).map_err(|e| format!("Could not resize {}: {}", path.display(), e))
+}
+
+fn shrink(image: DynamicImage, limits: &ImageLimits) -> Result<ImagePart, String> {
+    let mut dimension = limits.max_dimension.max(1);
+    loop {
+        let resized = if image.width().max(image.height()) > dimension {
+            image.resize(dimension, dimension, FilterType::Triangle)
+        } else {
+            image.clone()
+        };
+
+        let mut out = Vec::new();
+        let media_type = if resized.color().has_alpha() {
+            resized
+                .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+                .map_err(|e| e.to_string())?;
+            "image/png"
+        } else {
+            JpegEncoder::new_with_quality(&mut out, 85)
+                .encode_image(&resized.to_rgb8())
+                .map_err(|e| e.to_string())?;
+            "image/jpeg"
+        };
+
+        if out.len() <= limits.max_bytes || dimension <= 64 {
+            return Ok(encode(media_type, &out));
+        }
+        dimension = dimension * 3 / 4;
+    }
+}
+
+fn encode(media_type: &str, bytes: &[u8]) -> ImagePart {
+    ImagePart {
+        media_type: media_type.to_string(),
+        data: base64::engine::general_purpose::STANDARD.encode(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn workspace() -> PathBuf {
+        let dir = env::temp_dir().join(format!("crab-vision-{}", std::process::id()));
+        fs::create_dir_all(dir.join(INBOX)).unwrap();
+        dir
+    }
+
+    #[test]
+    fn finds_referenced_inbox_images_and_downscales_large_ones() {
+        let workspace = workspace();
+        let photo = workspace.join(INBOX).join("photo_1.png");
+        RgbImage::from_pixel(400, 200, Rgb([200, 10, 10]))
+            .save(&photo)
+            .unwrap();
+
+        let found = referenced_images(
+            &workspace,
+            "What is in photo_1.png? Also see in/photo_1.png, missing.jpg and ../../etc/x.png.",
+        );
+        assert_eq!(found, vec![photo.canonicalize().unwrap()]);
+
+        let small = load_image(&photo, &ImageLimits::default()).unwrap();
+        assert_eq!(small.media_type, "image/png");
+
+        let limits = ImageLimits {
+            max_bytes: 1_000_000,
+            max_dimension: 100,
+        };
+        let shrunk = load_image(&photo, &limits).unwrap();
+        assert_eq!(shrunk.media_type, "image/jpeg");
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(&shrunk.data)
+            .unwrap();
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (100, 50));
+
+        let _ = fs::remove_dir_all(&workspace);
+    }
+}
@@ -0,0 +1,96 @@
+//! Runs the agent binary against the scripted `mock` provider and checks what the
+//! model was sent at each turn.
+
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+#[test]
+fn runs_commands_asks_for_approval_and_delegates_through_the_mock() {
+    let temp = TempDir::new().unwrap();
+    let dir = temp.path();
+    let workspace = dir.join("workspace");
+    let keep = dir.join("keep.txt");
+    fs::write(&keep, "precious").unwrap();
+
+    let reply = json!({ "userId": "", "message": "Tidied up.", "action": "", "terminal": "", "panelActions": [] });
+    let script = json!([
+        {
+            "match": "(?i)tidy up",
+            "toolCalls": [
+                { "name": "run_terminal", "arguments": { "command": "echo crab-was-here" } },
+                { "name": "run_terminal", "arguments": { "command": format!("rm -f {}", keep.display()) } },
+            ],
+        },
+        { "toolCalls": [{ "name": "delegate", "arguments": { "agent_role": "Reviewer", "task": "Check the cleanup" } }] },
+        { "content": reply.to_string() },
+    ]);
+    let script_path = dir.join("mock.json");
+    let record_path = dir.join("requests.jsonl");
+    fs::write(&script_path, script.to_string()).unwrap();
+    // The operator has already said no, so the dangerous command is denied at once.
+    let deny_file = dir.join("deny.lock");
+    fs::write(&deny_file, "").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_hermit-crab"))
+        .env_clear()
+        .env("PATH", env::var("PATH").unwrap_or_default())
+        .env("LLM_PROVIDER", "mock")
+        .env("LLM_MODEL", "scripted")
+        .env("LLM_MOCK_SCRIPT", &script_path)
+        .env("LLM_MOCK_RECORD", &record_path)
+        .env("USAGE_LEDGER", dir.join("usage.json"))
+        .env("LLM_SAMPLING_FILE", dir.join("sampling.json"))
+        .env("WORKSPACE_DIR", &workspace)
+        .env("STOP_FILE", dir.join("stop.lock"))
+        .env("HITL_ENABLED", "true")
+        .env("HITL_APPROVAL_FILE", dir.join("approval.lock"))
+        .env("HITL_DENY_FILE", &deny_file)
+        .env("USER_MSG", "Please tidy up the workspace")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}\n{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    assert!(stdout.contains("[HITL] APPROVAL_REQUIRED: rm -f"));
+    assert!(stdout.contains("[MEETING] TARGET_ROLE: Reviewer"));
+    assert!(stdout.contains("Tidied up."));
+    assert!(Path::new(&keep).exists());
+    assert!(workspace.is_dir());
+
+    let requests: Vec<Value> = fs::read_to_string(&record_path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(requests.len(), 3);
+    let last = |i: usize| requests[i]["messages"].as_array().unwrap().clone();
+
+    let first = last(0);
+    assert_eq!(first[0]["role"], "system");
+    assert_eq!(
+        first.last().unwrap()["content"],
+        "Please tidy up the workspace"
+    );
+
+    let second = last(1);
+    let results: Vec<&str> = second
+        .iter()
+        .filter(|m| m["role"] == "tool")
+        .map(|m| m["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(results.len(), 2);
+    assert!(results[0].contains("crab-was-here"));
+    assert_eq!(results[1], "ERROR: Command denied by user");
+
+    let third = last(2);
+    assert_eq!(third.last().unwrap()["name"], "delegate");
+}
@@ -19,6 +19,25 @@ export function generateSessionToken(userId: number): string {
     return Buffer.from(data).toString('base64');
 }
 
+// Without a configured secret a random one is used, so tokens only hold until the
+// orchestrator restarts; the next run of each agent is signed again.
+const CONTAINER_TOKEN_SECRET = process.env.CONTAINER_TOKEN_SECRET || process.env.SESSION_SECRET || (() => {
+    console.warn('[Auth] CONTAINER_TOKEN_SECRET is not set; using a random secret for this process');
+    return crypto.randomBytes(32).toString('hex');
+})();
+
+// Per-container credential for /api/internal/* calls. Derived from the agent id so the
+// orchestrator can verify it without storing anything.
+export function signContainerToken(agentId: number): string {
+    return crypto.createHmac('sha256', CONTAINER_TOKEN_SECRET).update(`container:${agentId}`).digest('hex');
+}
+
+export function verifyContainerToken(agentId: number, token: string): boolean {
+    const expected = Buffer.from(signContainerToken(agentId));
+    const actual = Buffer.from(token);
+    return expected.length === actual.length && crypto.timingSafeEqual(expected, actual);
+}
+
 export async function validateUser(userId: number): Promise<boolean> {
     return await isAllowed(userId);
 }
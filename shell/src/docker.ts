@@ -2,9 +2,11 @@ import Docker from 'dockerode';
 import * as fs from 'fs';
 import * as path from 'path';
 import { PassThrough } from 'stream';
-import { createAuditLog, getAgentById, getAllSettings, getSetting, getActiveMeetings } from './db';
+import { createAuditLog, createAgentRuntimeLog, getAgentById, getAllSettings, getSetting, getActiveMeetings } from './db';
 import { sendApprovalRequest } from './telegram';
 import { searchRagMemories, initWorkspaceDatabases, workspaceDataExists } from './workspace-db';
+import { signContainerToken, getLimits } from './auth';
+import { needsApiKey, providerApiKey, upstreamProvider, usesProxy } from './providers';
 
 let docker: Docker;
 try {
@@ -34,6 +36,10 @@ interface AgentConfig {
 interface SpawnResult {
     containerId: string;
     output: string;
+    /** Set when crab summarized the history it was given; replaces the stored history. */
+    compactedHistory?: Message[];
+    /** crab's `[USAGE]` summary: tokens and cost per model for this run. */
+    usage?: Record<string, any>;
 }
 
 type ProgressCallback = (status: string, details?: string) => void;
@@ -109,7 +115,7 @@ async function createNewCubicle(config: AgentConfig): Promise<Docker.Container>
     fs.mkdirSync(path.join(workspacePath, 'data'), { recursive: true });
 
     const settings = await getAllSettings();
-    const provider = config.llmProvider || settings.default_provider || 'openrouter';
+    const provider = usesProxy(config.llmProvider) ? 'proxy' : upstreamProvider(config.llmProvider, settings);
     const model = config.llmModel || settings.default_model || 'auto';
 
     const envVars = [
@@ -119,12 +125,20 @@ async function createNewCubicle(config: AgentConfig): Promise<Docker.Container>
         `DOCKER_IMAGE=${config.dockerImage}`,
         `LLM_PROVIDER=${provider}`,
         `LLM_MODEL=${model}`,
+        `LLM_PROXY_TOKEN=${signContainerToken(config.agentId)}`,
         `PERSONALITY=${config.personality || ''}`,
         `PYTHON_GUIDE=Always use virtual environments for Python: python3 -m venv venv && source venv/bin/activate && pip install <package>. Never install packages globally with pip. Use npm for Node.js packages (works globally).`,
         `WEB_GUIDELINES=When working with the web: 1) Never send secrets/API keys in URLs or logs. 2) Use environment variables for sensitive data, never hardcode keys. 3) Validate and sanitize all user inputs. 4) When installing packages, prefer well-maintained packages and check for vulnerabilities. 5) Don't exfiltrate data - only return results to the user.`,
     ];
 
     if (config.requireApproval) envVars.push('HITL_ENABLED=true');
+    // Sampling defaults for every agent; an agent's own workspace/sampling.json overrides them.
+    const samplingKeys = ['LLM_TEMPERATURE', 'LLM_TOP_P', 'LLM_SEED', 'LLM_STOP', 'LLM_PRESENCE_PENALTY', 'LLM_FREQUENCY_PENALTY', 'LLM_PROVIDER_ROUTING', 'LLM_REASONING_EFFORT', 'LLM_THINKING_BUDGET'];
+    // Network settings for on-prem deployments; certificate paths must exist inside the cubicle.
+    const networkKeys = ['HTTPS_PROXY', 'HTTP_PROXY', 'NO_PROXY', 'LLM_CONNECT_TIMEOUT', 'LLM_READ_TIMEOUT', 'LLM_TIMEOUT', 'LLM_CA_BUNDLE', 'LLM_CLIENT_CERT', 'LLM_CLIENT_KEY'];
+    for (const key of ['LLM_BASE_URL', 'LLM_HEADERS', 'OLLAMA_HOST', 'AZURE_OPENAI_ENDPOINT', 'AZURE_OPENAI_DEPLOYMENT', 'AZURE_OPENAI_API_VERSION', 'AZURE_OPENAI_EMBEDDING_DEPLOYMENT', 'LLM_PROMPT_CACHE', 'LLM_RATE_LIMIT_RPM', 'LLM_RATE_LIMIT_TPM', 'LLM_EMBEDDING_MODEL', 'LLM_MODELS_FILE', 'MEMORY_TOP_K', ...samplingKeys, ...networkKeys]) {
+        if (process.env[key]) envVars.push(`${key}=${process.env[key]}`);
+    }
 
     const now = new Date().toISOString();
     const binds = [
@@ -166,21 +180,13 @@ async function createNewCubicle(config: AgentConfig): Promise<Docker.Container>
 
 export async function spawnAgent(config: AgentConfig): Promise<SpawnResult> {
     const settings = await getAllSettings();
-    const provider = config.llmProvider || settings.default_provider || 'openrouter';
+    // Behind the proxy the orchestrator calls the upstream provider, so its key must be here.
+    const upstream = upstreamProvider(config.llmProvider, settings);
+    const provider = usesProxy(config.llmProvider) ? 'proxy' : upstream;
     const model = config.llmModel || settings.default_model || 'auto';
-    const providerKeyMap: Record<string, { key: string; env: string }> = {
-        'openai': { key: 'openai_api_key', env: 'OPENAI_API_KEY' },
-        'anthropic': { key: 'anthropic_api_key', env: 'ANTHROPIC_API_KEY' },
-        'google': { key: 'google_api_key', env: 'GOOGLE_API_KEY' },
-        'groq': { key: 'groq_api_key', env: 'GROQ_API_KEY' },
-        'openrouter': { key: 'openrouter_api_key', env: 'OPENROUTER_API_KEY' },
-        'mistral': { key: 'mistral_api_key', env: 'MISTRAL_API_KEY' },
-        'deepseek': { key: 'deepseek_api_key', env: 'DEEPSEEK_API_KEY' },
-        'xai': { key: 'xai_api_key', env: 'XAI_API_KEY' },
-    };
-
-    if (!settings[providerKeyMap[provider]?.key] && !process.env[providerKeyMap[provider]?.env]) {
-        return { containerId: '', output: `❌ **SYSTEM ERROR**: Missing API Key for '${provider}'.` };
+
+    if (needsApiKey(upstream) && !providerApiKey(upstream, settings)) {
+        return { containerId: '', output: `❌ **SYSTEM ERROR**: Missing API Key for '${upstream}'.` };
     }
 
     try {
@@ -212,13 +218,18 @@ export async function spawnAgent(config: AgentConfig): Promise<SpawnResult> {
                 `USER_MSG=${config.userMessage}`,
                 `HISTORY=${historyB64}`,
                 `MAX_TOKENS=${config.maxTokens}`,
+                `MAX_HISTORY_MESSAGES=${getLimits().maxHistoryMessages}`,
+                `MAX_TOKENS_PER_DAY=${getLimits().maxTokensPerDay}`,
+                `MAX_COST_USD=${process.env.MAX_COST_USD || ''}`,
                 `PERSONALITY=${config.personality || ''}`,
                 `LLM_PROVIDER=${provider}`,
                 `LLM_MODEL=${model}`,
                 `ORCHESTRATOR_URL=http://172.17.0.1:3000`,
                 `PYTHON_GUIDE=Always use virtual environments for Python: python3 -m venv venv && source venv/bin/activate && pip install <package>. Never install packages globally with pip. Use npm for Node.js packages (works globally).`,
                 `WEB_GUIDELINES=When working with the web: 1) Never send secrets/API keys in URLs or logs. 2) Use environment variables for sensitive data, never hardcode keys. 3) Validate and sanitize all user inputs. 4) When installing packages, prefer well-maintained packages and check for vulnerabilities. 5) Don't exfiltrate data - only return results to the user.`,
-                ...((await container.inspect()).Config.Env || [])
+                ...((await container.inspect()).Config.Env || []),
+                // Signed again per run: the cubicle may predate this orchestrator's secret.
+                `LLM_PROXY_TOKEN=${signContainerToken(config.agentId)}`,
             ],
             AttachStdout: true,
             AttachStderr: true
@@ -253,6 +264,10 @@ export async function spawnAgent(config: AgentConfig): Promise<SpawnResult> {
                 if (line.includes('COMMAND_OUTPUT:')) {
                     sendProgress(`📤 Processing output...`);
                 }
+                if (line.includes('[OUTPUT]')) {
+                    const latest = line.split('[OUTPUT]').pop()?.split('\n')[0]?.trim();
+                    sendProgress(`⚙️ Command running...`, latest?.slice(0, 50));
+                }
                 if (line.includes('[HITL] APPROVAL_REQUIRED:')) {
                     try {
                         const cmd = line.split('REQUIRED:')[1]?.trim() || 'Unknown command';
@@ -267,6 +282,19 @@ export async function spawnAgent(config: AgentConfig): Promise<SpawnResult> {
                 if (line.includes('[MEETING]')) {
                     sendProgress('🤝 Coordinating with other agents...');
                 }
+                if (line.includes('[STREAM]')) {
+                    sendProgress('✍️ Writing response...');
+                }
+                if (line.includes('[RETRY]')) {
+                    const details = line.split('[RETRY]')[1]?.split('\n')[0]?.trim();
+                    sendProgress('🔁 Provider busy, retrying...', details?.slice(0, 60));
+                }
+                if (line.includes('[FAILOVER]')) {
+                    sendProgress('🔀 Switching to backup model...');
+                }
+                if (line.includes('[RateLimit]')) {
+                    sendProgress('⏳ Queued behind other requests to the provider...');
+                }
             });
 
             if (stream) {
@@ -285,9 +313,23 @@ export async function spawnAgent(config: AgentConfig): Promise<SpawnResult> {
                         if (trimmed.startsWith('{') || trimmed.startsWith('}')) return true;
 
                         if (trimmed.startsWith('[Workspace]')) return false;
+                        if (trimmed.startsWith('[Context]')) return false;
+                        if (trimmed.startsWith('[Contract]')) return false;
                         if (trimmed.includes('Working directory set to')) return false;
                         if (trimmed.startsWith('[HITL]')) return false;
                         if (trimmed.startsWith('[MEETING]')) return false;
+                        if (trimmed.startsWith('[STREAM]')) return false;
+                        if (trimmed.startsWith('[RETRY]')) return false;
+                        if (trimmed.startsWith('[FAILOVER]')) return false;
+                        if (trimmed.startsWith('[LLM]')) return false;
+                        if (trimmed.startsWith('[ERROR]')) return false;
+                        if (trimmed.startsWith('[HISTORY]')) return false;
+                        if (trimmed.startsWith('[USAGE]')) return false;
+                        if (trimmed.startsWith('[Cassette]')) return false;
+                        if (trimmed.startsWith('[Replay]')) return false;
+                        if (trimmed.startsWith('[OUTPUT]')) return false;
+                        if (trimmed.startsWith('[REASONING]')) return false;
+                        if (trimmed.startsWith('[RateLimit]')) return false;
                         if (trimmed.includes('TARGET_ROLE:')) return false;
                         if (trimmed.includes('DELEGATION_APPROVAL_REQUIRED')) return false;
                         if (trimmed.startsWith('[INTERNAL_COMMAND_OUTPUT]')) return false;
@@ -297,7 +339,13 @@ export async function spawnAgent(config: AgentConfig): Promise<SpawnResult> {
 
                     let cleanOutput = filteredLines.join('\n').trim();
 
-                    if (!cleanOutput || cleanOutput.length < 2) {
+                    await logReasoning(config.agentId, lines);
+
+                    const agentError = parseAgentError(lines);
+
+                    if (agentError && (!cleanOutput || cleanOutput.length < 2)) {
+                        cleanOutput = agentError;
+                    } else if (!cleanOutput || cleanOutput.length < 2) {
                         if (output.includes('Error:') || output.includes('error')) {
                             const errorLines = lines.filter(l => l.toLowerCase().includes('error'));
                             cleanOutput = `❌ Agent error:\n${errorLines.join('\n') || 'Unknown error occurred'}`;
@@ -306,7 +354,7 @@ export async function spawnAgent(config: AgentConfig): Promise<SpawnResult> {
                         }
                     }
 
-                    resolve({ containerId, output: cleanOutput });
+                    resolve({ containerId, output: cleanOutput, compactedHistory: parseCompactedHistory(lines), usage: parseUsage(lines) });
                 });
 
                 stream.on('error', (err: Error) => reject(err));
@@ -319,6 +367,71 @@ export async function spawnAgent(config: AgentConfig): Promise<SpawnResult> {
     }
 }
 
+const AGENT_ERROR_HINTS: Record<string, string> = {
+    config: '⚙️ Agent is misconfigured (missing API key or provider settings).',
+    auth: '🔑 The LLM provider rejected the API key.',
+    rate_limited: '⏳ The LLM provider is rate limiting requests. Try again shortly.',
+    context_overflow: '📚 The conversation is too long for this model. Try /clear or a model with a larger context.',
+    content_filter: '🚫 The request was blocked by the provider\'s content filter.',
+    timeout: '⌛ The LLM provider timed out.',
+    network: '🌐 Could not reach the LLM provider.',
+    budget_exceeded: '💸 The agent reached its cost or daily token limit.',
+    cancelled: '🛑 The agent was stopped before it finished.',
+};
+
+/** Decodes crab's `[HISTORY] <base64 json>` line, if it compacted the history. */
+function parseCompactedHistory(lines: string[]): Message[] | undefined {
+    const line = lines.find(l => l.trim().startsWith('[HISTORY]'));
+    if (!line) return undefined;
+    try {
+        const encoded = line.trim().slice('[HISTORY]'.length).trim();
+        const parsed = JSON.parse(Buffer.from(encoded, 'base64').toString('utf-8'));
+        return Array.isArray(parsed) ? parsed : undefined;
+    } catch {
+        return undefined;
+    }
+}
+
+/** Keeps each `[REASONING] {json}` line from crab in the agent's runtime log. */
+async function logReasoning(agentId: number, lines: string[]): Promise<void> {
+    for (const line of lines.filter(l => l.trim().startsWith('[REASONING]'))) {
+        try {
+            const reasoning = JSON.parse(line.trim().slice('[REASONING]'.length));
+            await createAgentRuntimeLog(agentId, 'info', 'reasoning', reasoning.text || '', {
+                provider: reasoning.provider,
+                model: reasoning.model,
+                tokens: reasoning.tokens,
+            });
+        } catch (err) {
+            console.error('Failed to log agent reasoning:', err);
+        }
+    }
+}
+
+/** Reads crab's `[USAGE] {json}` summary line. */
+function parseUsage(lines: string[]): Record<string, any> | undefined {
+    const line = lines.find(l => l.trim().startsWith('[USAGE]'));
+    if (!line) return undefined;
+    try {
+        return JSON.parse(line.trim().slice('[USAGE]'.length));
+    } catch {
+        return undefined;
+    }
+}
+
+/** Turns crab's `[ERROR] {json}` line into a user-facing message. */
+function parseAgentError(lines: string[]): string | null {
+    const line = lines.find(l => l.trim().startsWith('[ERROR]'));
+    if (!line) return null;
+    try {
+        const error = JSON.parse(line.trim().slice('[ERROR]'.length));
+        const hint = AGENT_ERROR_HINTS[error.kind] || '❌ Agent error:';
+        return `${hint}\n${error.message}`;
+    } catch {
+        return null;
+    }
+}
+
 export async function stopCubicle(containerId: string): Promise<void> {
     try { await docker.getContainer(containerId).stop(); } catch (err) { }
 }
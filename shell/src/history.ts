@@ -44,6 +44,15 @@ export function saveHistory(scope: string, history: Message[]): void {
   fs.writeFileSync(historyPath, JSON.stringify(history, null, 2));
 }
 
+const SUMMARY_PREFIX = 'Summary of earlier conversation:';
+
+/** Last `count` messages, keeping the rolling summary crab writes at the front. */
+export function recentHistory(history: Message[], count: number): Message[] {
+  const summary = history.find(m => m.role === 'system' && m.content.startsWith(SUMMARY_PREFIX));
+  const rest = history.filter(m => m !== summary).slice(-count);
+  return summary ? [summary, ...rest] : rest;
+}
+
 export function clearHistory(scope: string): void {
   const historyPath = getHistoryPath(scope);
   if (fs.existsSync(historyPath)) {
@@ -0,0 +1,138 @@
+// Where each LLM provider's API key lives: a dashboard setting first, then the environment.
+export const PROVIDER_KEYS: Record<string, { key: string; env: string }> = {
+    'openai': { key: 'openai_api_key', env: 'OPENAI_API_KEY' },
+    'azure': { key: 'azure_openai_api_key', env: 'AZURE_OPENAI_API_KEY' },
+    'anthropic': { key: 'anthropic_api_key', env: 'ANTHROPIC_API_KEY' },
+    'google': { key: 'google_api_key', env: 'GOOGLE_API_KEY' },
+    'groq': { key: 'groq_api_key', env: 'GROQ_API_KEY' },
+    'openrouter': { key: 'openrouter_api_key', env: 'OPENROUTER_API_KEY' },
+    'mistral': { key: 'mistral_api_key', env: 'MISTRAL_API_KEY' },
+    'deepseek': { key: 'deepseek_api_key', env: 'DEEPSEEK_API_KEY' },
+    'xai': { key: 'xai_api_key', env: 'XAI_API_KEY' },
+};
+
+// Local servers and the scripted mock run without a key (crab sends one only if set).
+const KEYLESS_PROVIDERS = ['ollama', 'openai-compatible', 'mock'];
+
+export function needsApiKey(provider: string): boolean {
+    return !KEYLESS_PROVIDERS.includes(provider);
+}
+
+export function providerApiKey(provider: string, settings: Record<string, any>): string | undefined {
+    const entry = PROVIDER_KEYS[provider];
+    if (!entry) return undefined;
+    return settings[entry.key] || process.env[entry.env] || undefined;
+}
+
+// The provider that actually answers. An agent set to 'proxy' is served by the default
+// provider through /api/internal/llm.
+export function upstreamProvider(configured: string | undefined, settings: Record<string, any>): string {
+    const fallback = settings.default_provider && settings.default_provider !== 'proxy' ? settings.default_provider : 'openrouter';
+    if (!configured || configured === 'default' || configured === 'proxy') return fallback;
+    return configured;
+}
+
+// Crab routes completions through the orchestrator, which holds the keys, when the agent
+// is set to 'proxy' or LLM_PROXY_MODE=true applies it to every agent.
+export function usesProxy(configured: string | undefined): boolean {
+    return configured === 'proxy' || process.env.LLM_PROXY_MODE === 'true';
+}
+
+// Crab relays chat requests through /api/internal/llm in OpenAI's shape; proxyRequestBody
+// turns one into the upstream provider's. Fields a provider has no equivalent for are
+// refused with an error rather than dropped.
+interface RelayedImage { mediaType: string; data: string }
+
+const OPENAI_SAMPLING = ['temperature', 'top_p', 'seed', 'stop', 'presence_penalty', 'frequency_penalty'];
+// Providers that accept a strict JSON schema; the others only have plain JSON mode.
+const JSON_SCHEMA_PROVIDERS = ['openai', 'azure', 'openrouter', 'xai'];
+
+// The text and images of an OpenAI message, whose content is a string or a list of parts.
+export function splitContent(content: any): { text: string; images: RelayedImage[] } {
+    if (typeof content === 'string') return { text: content, images: [] };
+    const parts: any[] = Array.isArray(content) ? content : [];
+    const text = parts.filter(p => p.type === 'text').map(p => p.text).join('\n');
+    const images = parts.filter(p => p.type === 'image_url').map(p => {
+        const match = /^data:([^;,]+);base64,(.*)$/s.exec(p.image_url?.url || '');
+        if (!match) throw new Error('Only base64 data URL images can be relayed');
+        return { mediaType: match[1], data: match[2] };
+    });
+    return { text, images };
+}
+
+function refuse(provider: string, request: any, fields: string[]) {
+    const present = fields.filter(field => request[field] !== undefined && request[field] !== null);
+    if (present.length) throw new Error(`${provider} does not support ${present.join(', ')}`);
+}
+
+export function proxyRequestBody(provider: string, model: string, request: any): any {
+    // Tool calls are not relayed yet; crab disables them in proxy mode.
+    refuse(provider, request, ['tools', 'tool_choice']);
+    const messages: any[] = request.messages || [];
+    const maxTokens = Number(request.max_tokens) || 4000;
+    const system = messages.filter(m => m.role === 'system').map(m => splitContent(m.content).text).join('\n\n');
+    const turns = messages.filter(m => m.role !== 'system');
+
+    if (provider === 'google') {
+        const generationConfig: any = {
+            maxOutputTokens: maxTokens,
+            temperature: request.temperature,
+            topP: request.top_p,
+            seed: request.seed,
+            stopSequences: request.stop,
+            presencePenalty: request.presence_penalty,
+            frequencyPenalty: request.frequency_penalty,
+            responseMimeType: request.response_format ? 'application/json' : undefined,
+        };
+        return {
+            contents: turns.map(m => {
+                const { text, images } = splitContent(m.content);
+                return {
+                    role: m.role === 'assistant' ? 'model' : 'user',
+                    parts: [{ text }, ...images.map(i => ({ inlineData: { mimeType: i.mediaType, data: i.data } }))],
+                };
+            }),
+            ...(system ? { systemInstruction: { parts: [{ text: system }] } } : {}),
+            generationConfig: JSON.parse(JSON.stringify(generationConfig)),
+        };
+    }
+
+    if (provider === 'anthropic') {
+        // There is no JSON mode; crab validates the reply against its contract instead.
+        refuse('anthropic', request, ['seed', 'presence_penalty', 'frequency_penalty']);
+        return JSON.parse(JSON.stringify({
+            model,
+            max_tokens: maxTokens,
+            system: system || undefined,
+            messages: turns.map(m => {
+                const { text, images } = splitContent(m.content);
+                if (!images.length) return { role: m.role, content: text };
+                return {
+                    role: m.role,
+                    content: [
+                        { type: 'text', text },
+                        ...images.map(i => ({ type: 'image', source: { type: 'base64', media_type: i.mediaType, data: i.data } })),
+                    ],
+                };
+            }),
+            temperature: request.temperature,
+            top_p: request.top_p,
+            stop_sequences: request.stop,
+        }));
+    }
+
+    const body: any = { messages, max_tokens: maxTokens };
+    if (provider !== 'azure') body.model = model;
+    for (const field of OPENAI_SAMPLING) {
+        if (request[field] !== undefined && request[field] !== null) body[field] = request[field];
+    }
+    // Mistral names the seed differently.
+    if (provider === 'mistral' && body.seed !== undefined) {
+        body.random_seed = body.seed;
+        delete body.seed;
+    }
+    if (request.response_format) {
+        body.response_format = JSON_SCHEMA_PROVIDERS.includes(provider) ? request.response_format : { type: 'json_object' };
+    }
+    return body;
+}
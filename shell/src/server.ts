@@ -15,14 +15,15 @@ import {
     deleteRagMemory as wsDeleteRagMemory, clearRagMemories as wsClearRagMemories
 } from './workspace-db';
 import { checkDocker, listContainers, getContainerExec, docker, spawnAgent, restartAgentContainer } from './docker';
-import { hashPassword, verifyPassword, generateSessionToken } from './auth';
+import { hashPassword, verifyPassword, generateSessionToken, verifyContainerToken } from './auth';
+import { providerApiKey, proxyRequestBody, upstreamProvider } from './providers';
 import { startTunnel, syncWebhooks, getTunnelUrl } from './tunnel';
 import * as fs from 'fs';
 import * as path from 'path';
 import * as http from 'http';
 import { execFileSync } from 'child_process';
 import cookie from '@fastify/cookie';
-import { loadHistory, saveHistory, clearHistory } from './history';
+import { loadHistory, saveHistory, clearHistory, recentHistory } from './history';
 import { discoverSitesFromWorkspaces, deleteSiteWorkspace, deleteWebApp } from './sites';
 import { resolveDashboardStaticRoot } from './dashboard-static';
 
@@ -701,14 +702,14 @@ export async function startServer() {
         const historyKey = `dashboard_${agent.id}_${scopedUserId}`;
 
         try {
-            const history = loadHistory(historyKey);
+            let history = loadHistory(historyKey);
             const result = await spawnAgent({
                 agentId: agent.id,
                 agentName: agent.name,
                 agentRole: agent.role,
                 dockerImage: agent.docker_image,
                 userMessage: message,
-                history: history.slice(-20),
+                history: recentHistory(history, 20),
                 maxTokens: 1000,
                 requireApproval: agent.require_approval === 1,
                 userId: scopedUserId,
@@ -716,9 +717,10 @@ export async function startServer() {
                 llmModel: agent.llm_model && agent.llm_model !== 'default' ? agent.llm_model : undefined
             });
 
+            if (result.compactedHistory) history = result.compactedHistory;
             history.push({ role: 'user', content: message });
             history.push({ role: 'assistant', content: result.output });
-            saveHistory(historyKey, history.slice(-40));
+            saveHistory(historyKey, recentHistory(history, 40));
 
             const estimatedCost = result.output.length * 0.00001;
             await import('./db').then(m => m.updateSpend(agent.id, estimatedCost));
@@ -757,57 +759,49 @@ export async function startServer() {
             const { messages, agentId } = request.body;
             if (!messages || !agentId) return reply.code(400).send({ error: 'Missing messages or agentId' });
 
+            const authHeader = request.headers.authorization;
+            const token = typeof authHeader === 'string' && authHeader.startsWith('Bearer ') ? authHeader.slice(7).trim() : '';
+            if (!token || !verifyContainerToken(Number(agentId), token)) {
+                return reply.code(401).send({ error: 'Missing or invalid container token' });
+            }
+
             const agent = await getAgentById(Number(agentId));
             if (!agent) return reply.code(404).send({ error: 'Agent not found' });
 
             const settings = await getAllSettings();
-            const provider = agent.llm_provider && agent.llm_provider !== 'default' ? agent.llm_provider : (settings.default_provider || 'openrouter');
+            const provider = upstreamProvider(agent.llm_provider, settings);
             const model = agent.llm_model && agent.llm_model !== 'default' ? agent.llm_model : (settings.default_model || 'auto');
-
-            const providerKeyMap: Record<string, { key: string; env: string }> = {
-                'openai': { key: 'openai_api_key', env: 'OPENAI_API_KEY' },
-                'anthropic': { key: 'anthropic_api_key', env: 'ANTHROPIC_API_KEY' },
-                'google': { key: 'google_api_key', env: 'GOOGLE_API_KEY' },
-                'groq': { key: 'groq_api_key', env: 'GROQ_API_KEY' },
-                'openrouter': { key: 'openrouter_api_key', env: 'OPENROUTER_API_KEY' },
-                'mistral': { key: 'mistral_api_key', env: 'MISTRAL_API_KEY' },
-                'deepseek': { key: 'deepseek_api_key', env: 'DEEPSEEK_API_KEY' },
-                'xai': { key: 'xai_api_key', env: 'XAI_API_KEY' },
-            };
-
-            const apiKey = settings[providerKeyMap[provider]?.key] || process.env[providerKeyMap[provider]?.env];
+            const apiKey = providerApiKey(provider, settings);
 
             if (!apiKey) {
                 return { output: `❌ **SYSTEM ERROR**: Missing API Key for '${provider}'.` };
             }
 
+            let body: any;
+            try {
+                body = proxyRequestBody(provider, model, request.body);
+            } catch (e: any) {
+                return reply.code(400).send({ error: e.message });
+            }
+
             let url = '';
             let headers: any = { 'Content-Type': 'application/json' };
-            let body: any = { messages, max_tokens: 4000 };
 
             if (provider === 'google') {
                 url = `https://generativelanguage.googleapis.com/v1beta/models/${model}:generateContent?key=${apiKey}`;
-                const mappedMessages = messages.map((m: any) => ({
-                    role: m.role === 'assistant' ? 'model' : 'user',
-                    parts: [{ text: m.content }]
-                }));
-                body = { contents: mappedMessages };
-                const sysMsg = messages.find((m: any) => m.role === 'system');
-                if (sysMsg) {
-                    body.systemInstruction = { parts: [{ text: sysMsg.content }] };
-                    body.contents = body.contents.filter((m: any) => m.role !== 'system');
-                }
             } else if (provider === 'anthropic') {
                 url = 'https://api.anthropic.com/v1/messages';
                 headers['x-api-key'] = apiKey;
                 headers['anthropic-version'] = '2023-06-01';
-                body.model = model;
-                const sysMsg = messages.find((m: any) => m.role === 'system')?.content;
-                if (sysMsg) body.system = sysMsg;
-                body.messages = messages.filter((m: any) => m.role !== 'system');
+            } else if (provider === 'azure') {
+                const endpoint = (settings.azure_openai_endpoint || process.env.AZURE_OPENAI_ENDPOINT || '').replace(/\/+$/, '');
+                if (!endpoint) return { output: `❌ **SYSTEM ERROR**: Missing Azure OpenAI endpoint (AZURE_OPENAI_ENDPOINT).` };
+                const deployment = process.env.AZURE_OPENAI_DEPLOYMENT || model;
+                const apiVersion = process.env.AZURE_OPENAI_API_VERSION || '2024-10-21';
+                url = `${endpoint}/openai/deployments/${encodeURIComponent(deployment)}/chat/completions?api-version=${encodeURIComponent(apiVersion)}`;
+                headers['api-key'] = apiKey;
             } else {
                 headers['Authorization'] = `Bearer ${apiKey}`;
-                body.model = model;
                 if (provider === 'openrouter') { url = 'https://openrouter.ai/api/v1/chat/completions'; headers['HTTP-Referer'] = 'https://crabshell.local'; headers['X-Title'] = 'CrabShell'; }
                 else if (provider === 'openai') url = 'https://api.openai.com/v1/chat/completions';
                 else if (provider === 'groq') url = 'https://api.groq.com/openai/v1/chat/completions';
@@ -825,12 +819,27 @@ export async function startServer() {
                 return { output: `❌ **API ERROR**: ${JSON.stringify(data)}` };
             }
 
+            // Usage goes back in OpenAI's shape so crab can count it against the daily limit.
             let output = '';
-            if (provider === 'google') output = data.candidates?.[0]?.content?.parts?.[0]?.text || '';
-            else if (provider === 'anthropic') output = data.content?.[0]?.text || '';
-            else output = data.choices?.[0]?.message?.content || '';
+            let usage: any;
+            if (provider === 'google') {
+                output = data.candidates?.[0]?.content?.parts?.[0]?.text || '';
+                const meta = data.usageMetadata;
+                if (meta) usage = { prompt_tokens: meta.promptTokenCount || 0, completion_tokens: meta.candidatesTokenCount || 0 };
+            } else if (provider === 'anthropic') {
+                output = data.content?.[0]?.text || '';
+                const u = data.usage;
+                if (u) usage = {
+                    prompt_tokens: (u.input_tokens || 0) + (u.cache_creation_input_tokens || 0) + (u.cache_read_input_tokens || 0),
+                    completion_tokens: u.output_tokens || 0,
+                    prompt_tokens_details: { cached_tokens: u.cache_read_input_tokens || 0 },
+                };
+            } else {
+                output = data.choices?.[0]?.message?.content || '';
+                usage = data.usage;
+            }
 
-            return { output: output || 'Error extracting LLM response' };
+            return { output: output || 'Error extracting LLM response', usage };
         } catch (e: any) {
             console.error('Proxy LLM Error:', e);
             return reply.code(500).send({ error: e.message });
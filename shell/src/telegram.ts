@@ -5,7 +5,7 @@ import * as fs from 'fs';
 import * as path from 'path';
 import * as crypto from 'crypto';
 import * as chokidar from 'chokidar';
-import { loadHistory, saveHistory, clearHistory } from './history';
+import { loadHistory, saveHistory, clearHistory, recentHistory } from './history';
 import { setPreviewPassword } from './server';
 import { parseAgentResponse, parseFileAction } from './agent-response';
 
@@ -313,6 +313,10 @@ To get access, follow these steps:
         return await handleBudgetCommand(agent);
     }
 
+    if (text === '/stop') {
+        return await handleStopCommand(agent, userId);
+    }
+
     if (text?.startsWith('/containers') || text === '/containers') {
         const isOperator = (await getOperator())?.user_id === userId;
         if (!isOperator) {
@@ -376,7 +380,7 @@ export async function processAgentMessage(
     }
 
     const historyKey = `telegram_${agent.id}_${userId}`;
-    const history = loadHistory(historyKey);
+    let history = loadHistory(historyKey);
 
     const meetings = await getActiveMeetings(agent.id);
     const meetingContext = meetings.length > 0
@@ -394,7 +398,7 @@ export async function processAgentMessage(
             agentRole: agent.role,
             dockerImage: agent.docker_image,
             userMessage: text,
-            history: history.slice(-10),
+            history: recentHistory(history, 10),
             maxTokens: 1000,
             requireApproval: agent.require_approval === 1,
             userId: userId,
@@ -418,9 +422,10 @@ export async function processAgentMessage(
             result.output = `❌ *API Key Error (401 Unauthorized)*\n\nYour API key is either missing or invalid for this provider.\n\n*How to fix:*\n1. Open Dashboard -> Settings\n2. Enter a valid API key\n3. Click "Save All Settings"\n4. Send \`/reset\` here to delete this broken cubicle and apply your new keys!`;
         }
 
+        if (result.compactedHistory) history = result.compactedHistory;
         history.push({ role: 'user', content: text });
         history.push({ role: 'assistant', content: result.output });
-        saveHistory(historyKey, history.slice(-40));
+        saveHistory(historyKey, recentHistory(history, 40));
 
         const previewInfo = detectWebServer(result.output, agent.id);
         if (previewInfo) {
@@ -1071,6 +1076,7 @@ export async function setBotCommands(token: string): Promise<void> {
                     { command: 'logs', description: 'View container logs' },
                     { command: 'workspace', description: 'List workspace files' },
                     { command: 'budget', description: 'Check remaining budget' },
+                    { command: 'stop', description: 'Stop the current task' },
                     { command: 'reset', description: 'Reset the cubicle' },
                     { command: 'containers', description: 'List all containers (operator)' },
                     { command: 'agents', description: 'List all agents (operator)' }
@@ -1143,6 +1149,25 @@ async function handleResetCommand(agent: any, userId: number): Promise<string> {
     return `📊 No cubicle to reset.\n\nSend a message to create one.`;
 }
 
+/** Asks a running agent to wrap up; crab polls for the stop file and replies early. */
+async function handleStopCommand(agent: any, userId: number): Promise<string> {
+    const status = await getCubicleStatus(agent.id, userId);
+    if (status?.status !== 'running' || !status.containerId) {
+        return `📊 No running cubicle to stop.`;
+    }
+    try {
+        const exec = await docker.getContainer(status.containerId).exec({
+            Cmd: ['touch', '/tmp/hermit_stop.lock'],
+            AttachStdout: true,
+            AttachStderr: true
+        });
+        await exec.start({});
+        return `🛑 Stop requested. The agent will finish its current step and reply.`;
+    } catch (e: any) {
+        return `❌ Failed to stop: ${e.message}`;
+    }
+}
+
 async function handleBudgetCommand(agent: any): Promise<string> {
     const budget = await getBudget(agent.id);
     if (budget) {
@@ -1166,6 +1191,7 @@ async function handleHelpCommand(agent: any): Promise<string> {
 /logs - Recent container logs
 /workspace - Files in persistent workspace
 /budget - Daily budget remaining
+/stop - Stop the current task
 /reset - Kill and reset cubicle
 /clear - Clear conversation context
 
@@ -1218,7 +1244,9 @@ async function handleFileUpload(token: string, agent: any, userId: number, messa
         const savePath = path.join(inboundDir, fileName);
         fs.writeFileSync(savePath, buffer);
 
-        return `✅ *File uploaded successfully!*\n\n📄 \`${fileName}\`\nSaved to workspace. I can now access it.`;
+        const isImage = /\.(png|jpe?g|gif|webp)$/i.test(fileName);
+        const hint = isImage ? `\nMention \`${fileName}\` in a message and I will look at it.` : '';
+        return `✅ *File uploaded successfully!*\n\n📄 \`${fileName}\`\nSaved to workspace. I can now access it.${hint}`;
     } catch (e: any) {
         return `❌ Failed to upload file: ${e.message}`;
     }
@@ -0,0 +1,83 @@
+import { afterEach, describe, expect, it } from 'vitest';
+import { needsApiKey, providerApiKey, proxyRequestBody, splitContent, upstreamProvider, usesProxy } from '../src/providers';
+
+describe('providers', () => {
+  afterEach(() => {
+    delete process.env.LLM_PROXY_MODE;
+    delete process.env.GROQ_API_KEY;
+  });
+
+  it('serves proxy agents from the default provider', () => {
+    expect(upstreamProvider('proxy', { default_provider: 'anthropic' })).toBe('anthropic');
+    expect(upstreamProvider('default', { default_provider: 'proxy' })).toBe('openrouter');
+    expect(upstreamProvider('groq', { default_provider: 'anthropic' })).toBe('groq');
+  });
+
+  it('routes through the proxy when the agent or LLM_PROXY_MODE asks for it', () => {
+    expect(usesProxy('proxy')).toBe(true);
+    expect(usesProxy('openai')).toBe(false);
+    process.env.LLM_PROXY_MODE = 'true';
+    expect(usesProxy('openai')).toBe(true);
+  });
+
+  it('prefers the dashboard key over the environment', () => {
+    process.env.GROQ_API_KEY = 'from-env';
+    expect(providerApiKey('groq', {})).toBe('from-env');
+    expect(providerApiKey('groq', { groq_api_key: 'from-settings' })).toBe('from-settings');
+    expect(providerApiKey('proxy', {})).toBeUndefined();
+  });
+
+  it('lets local servers and the mock run without a key', () => {
+    expect(needsApiKey('ollama')).toBe(false);
+    expect(needsApiKey('openai-compatible')).toBe(false);
+    expect(needsApiKey('mock')).toBe(false);
+    expect(needsApiKey('openai')).toBe(true);
+  });
+
+  it('relays images as data, not links', () => {
+    const content = [
+      { type: 'text', text: 'What is this?' },
+      { type: 'image_url', image_url: { url: 'data:image/png;base64,AAAA' } },
+    ];
+    expect(splitContent(content)).toEqual({ text: 'What is this?', images: [{ mediaType: 'image/png', data: 'AAAA' }] });
+    expect(() => splitContent([{ type: 'image_url', image_url: { url: 'https://example.com/a.png' } }])).toThrow();
+  });
+
+  it('translates a relayed request for Gemini', () => {
+    const body = proxyRequestBody('google', 'gemini-2.0-flash', {
+      messages: [
+        { role: 'system', content: 'Be brief.' },
+        { role: 'user', content: [{ type: 'text', text: 'Hi' }, { type: 'image_url', image_url: { url: 'data:image/jpeg;base64,BBBB' } }] },
+      ],
+      max_tokens: 100,
+      temperature: 0.3,
+      stop: ['###'],
+      response_format: { type: 'json_schema' },
+    });
+    expect(body).toEqual({
+      contents: [{ role: 'user', parts: [{ text: 'Hi' }, { inlineData: { mimeType: 'image/jpeg', data: 'BBBB' } }] }],
+      systemInstruction: { parts: [{ text: 'Be brief.' }] },
+      generationConfig: { maxOutputTokens: 100, temperature: 0.3, stopSequences: ['###'], responseMimeType: 'application/json' },
+    });
+  });
+
+  it('refuses fields the upstream provider cannot honour', () => {
+    const messages = [{ role: 'user', content: 'Hi' }];
+    expect(() => proxyRequestBody('anthropic', 'claude-sonnet-4', { messages, seed: 1 })).toThrow('anthropic does not support seed');
+    expect(() => proxyRequestBody('openai', 'gpt-4o', { messages, tools: [] })).toThrow('openai does not support tools');
+    expect(proxyRequestBody('anthropic', 'claude-sonnet-4', { messages, temperature: 0.5, stop: ['###'] })).toEqual({
+      model: 'claude-sonnet-4', max_tokens: 4000, messages: [{ role: 'user', content: 'Hi' }], temperature: 0.5, stop_sequences: ['###'],
+    });
+  });
+
+  it('renames and downgrades OpenAI-style fields per provider', () => {
+    const messages = [{ role: 'user', content: 'Hi' }];
+    const schema = { type: 'json_schema', json_schema: { name: 'reply', schema: {} } };
+    expect(proxyRequestBody('mistral', 'mistral-large', { messages, seed: 7 })).toEqual({
+      messages, max_tokens: 4000, model: 'mistral-large', random_seed: 7,
+    });
+    expect(proxyRequestBody('groq', 'llama-3.3-70b', { messages, response_format: schema }).response_format).toEqual({ type: 'json_object' });
+    expect(proxyRequestBody('openai', 'gpt-4o', { messages, response_format: schema }).response_format).toEqual(schema);
+    expect(proxyRequestBody('azure', 'gpt-4o', { messages }).model).toBeUndefined();
+  });
+});